@@ -7,7 +7,8 @@ import { Label } from './ui/label';
 import { Dialog, DialogContent, DialogHeader, DialogTitle, DialogTrigger } from './ui/dialog';
 import { Card, CardContent, CardDescription, CardHeader, CardTitle } from './ui/card';
 import { Badge } from './ui/badge';
-import { Download, FileText, AlertCircle } from 'lucide-react';
+import { Switch } from './ui/switch';
+import { Download, FileText, AlertCircle, Link } from 'lucide-react';
 
 interface ExportDialogProps {
   children: React.ReactNode;
@@ -18,7 +19,9 @@ export function ExportDialog({ children }: ExportDialogProps) {
   const [isOpen, setIsOpen] = useState(false);
   const [filename, setFilename] = useState(CsvExporter.getDefaultFilename());
   const [isExporting, setIsExporting] = useState(false);
+  const [includeSources, setIncludeSources] = useState(true);
 
+  const rowsWithSources = CsvExporter.countRowsWithSources(results);
   const canExport = results.length > 0 || errors.length > 0;
 
   const handleExport = async () => {
@@ -27,7 +30,9 @@ export function ExportDialog({ children }: ExportDialogProps) {
     setIsExporting(true);
     
     try {
-      const { successCsv, errorCsv } = CsvExporter.exportResults(results, errors, filename);
+      const { successCsv, errorCsv } = CsvExporter.exportResults(results, errors, filename, {
+        includeSources: rowsWithSources > 0 && includeSources,
+      });
       
       // Download success results
       CsvExporter.downloadCsv(successCsv, `${filename}-success`);
@@ -87,6 +92,16 @@ export function ExportDialog({ children }: ExportDialogProps) {
                   <Badge variant="destructive">{errors.length}</Badge>
                 </div>
               )}
+
+              {rowsWithSources > 0 && (
+                <div className="flex items-center justify-between">
+                  <div className="flex items-center space-x-2">
+                    <Link className="h-4 w-4 text-blue-600" />
+                    <span className="text-sm">Rows with field sources</span>
+                  </div>
+                  <Badge variant="secondary">{rowsWithSources}</Badge>
+                </div>
+              )}
             </CardContent>
           </Card>
 
@@ -106,6 +121,26 @@ export function ExportDialog({ children }: ExportDialogProps) {
             </p>
           </div>
 
+          {/* Field sources */}
+          {rowsWithSources > 0 && (
+            <div className="flex items-center justify-between space-x-2">
+              <div className="space-y-0.5 flex-1">
+                <Label htmlFor="export-sources" className="text-sm font-medium cursor-pointer">
+                  Include field sources
+                </Label>
+                <p className="text-xs text-muted-foreground">
+                  Adds a &lt;field&gt;_sources column with the source URLs of each field
+                </p>
+              </div>
+              <Switch
+                id="export-sources"
+                checked={includeSources}
+                onCheckedChange={setIncludeSources}
+                disabled={isExporting}
+              />
+            </div>
+          )}
+
           {/* Notes */}
           <div className="p-3 bg-yellow-50 border border-yellow-200 rounded-md">
             <div className="flex items-start space-x-2">
@@ -119,7 +119,7 @@ export function useGeminiProcessor() {
           } else {
             merged = { ...base, result_value: data };
           }
-          addResult({ ...merged, _status: 'success', _rawResponse: payload.raw });
+          addResult({ ...merged, _status: 'success', _rawResponse: payload.raw, _sources: payload.sources ?? undefined });
         } else {
           logger.warn('Row error', { index: payload.index, error: payload.error });
           addError({ ...csvData[payload.index], _error: payload.error ?? 'Unknown error', _rowIndex: payload.index });
@@ -4,10 +4,17 @@ export interface Row {
   [key: string]: string | number | undefined;
 }
 
-export interface ProcessedRow extends Row {
+// フィールド名 → 出典 URL
+export type FieldSources = Record<string, string[]>;
+
+// 入力行（Row）の値に加えて、行ごとの付帯情報（_sources）を持つ
+export interface ProcessedRow {
+  [key: string]: string | number | undefined | FieldSources;
   _status: 'success' | 'error';
   _error?: string;
   _rawResponse?: string;
+  // フィールドごとの出典 URL（fieldSources を有効にし、検索・URL グラウンディングがあった行のみ）
+  _sources?: FieldSources;
 }
 
 export interface ErrorRow extends Row {
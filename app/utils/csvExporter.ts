@@ -1,11 +1,17 @@
 import Papa from 'papaparse';
 import type { ProcessedRow, ErrorRow } from '../types';
 
+export interface ExportOptions {
+  // フィールドごとの出典 URL を「<フィールド名>_sources」列として出力する（複数の URL は改行区切り）
+  includeSources?: boolean;
+}
+
 export class CsvExporter {
   static exportResults(
     results: ProcessedRow[],
     errors: ErrorRow[],
-    filename: string = 'gemini-results'
+    filename: string = 'gemini-results',
+    options: ExportOptions = {}
   ): { successCsv: string; errorCsv: string | null } {
     // ネストされたオブジェクトをフラット化する関数
     const flattenObject = (obj: Record<string, any>, prefix = ''): Record<string, any> => {
@@ -99,7 +105,7 @@ export class CsvExporter {
     
     const expandedData: Record<string, any>[] = [];
     results.forEach((result, idx) => {
-      const { _status, _error, _rawResponse, ...data } = result;
+      const { _status, _error, _rawResponse, _sources, ...data } = result;
       console.log(`[CSV Export] Row ${idx}: Original data:`, JSON.stringify(data, null, 2));
       
       // resultフィールドが生テキストの場合、それを除去
@@ -112,6 +118,13 @@ export class CsvExporter {
         }
       }
       
+      // 出典列は配列展開の対象にしない（1つのセルにまとめる）
+      if (options.includeSources && _sources) {
+        for (const [field, urls] of Object.entries(_sources)) {
+          cleanedData[`${field}_sources`] = urls.join('\n');
+        }
+      }
+
       const rows = expandArrays(cleanedData);
       console.log(`[CSV Export] Row ${idx}: Expanded to ${rows.length} rows:`, JSON.stringify(rows, null, 2));
       
@@ -156,6 +169,11 @@ export class CsvExporter {
     return { successCsv, errorCsv };
   }
 
+  // 出典 URL を持つ行の数（エクスポート画面の表示用）
+  static countRowsWithSources(results: ProcessedRow[]): number {
+    return results.filter((r) => r._sources && Object.keys(r._sources).length > 0).length;
+  }
+
   static downloadCsv(content: string, filename: string): void {
     const blob = new Blob([content], { type: 'text/csv;charset=utf-8;' });
     const link = document.createElement('a');
@@ -0,0 +1,190 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+// 行の列に書かれたローカルファイル（商品写真・スキャンPDFなど）を添付として読み込む
+// テンプレート内の {{file:列名}} がその列を添付扱いにする目印で、プロンプト上はファイル名に置き換わる
+
+pub const ATTACHMENT_MARKER_PREFIX: &str = "{{file:";
+
+// 既定の1ファイルあたり上限（inline data はリクエスト全体で 20MB まで。base64 で約 4/3 倍になる）
+pub const DEFAULT_MAX_ATTACHMENT_BYTES: u64 = 15 * 1024 * 1024;
+
+// 既定の1行（1リクエスト）あたりの合計上限。複数列の添付を足して inline data の上限を超えないようにする
+pub const DEFAULT_MAX_TOTAL_ATTACHMENT_BYTES: u64 = 15 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+  pub column: String,
+  pub path: String,
+  pub mime_type: String,
+  pub size_bytes: u64,
+  pub sha256: String,
+  pub data_base64: String,
+}
+
+impl Attachment {
+  pub fn is_image(&self) -> bool {
+    self.mime_type.starts_with("image/")
+  }
+
+  // OpenAI 互換 API などで使う data URL
+  pub fn data_url(&self) -> String {
+    format!("data:{};base64,{}", self.mime_type, self.data_base64)
+  }
+
+  // request ログ用（本体のバイト列は含めない）
+  pub fn log_json(&self) -> serde_json::Value {
+    serde_json::json!({
+      "column": self.column,
+      "path": self.path,
+      "mimeType": self.mime_type,
+      "sizeBytes": self.size_bytes,
+      "sha256": self.sha256,
+    })
+  }
+}
+
+// テンプレート中の {{file:列名}} から添付列を出現順に取り出す（重複は除く）
+pub fn attachment_columns(template: &str) -> Vec<String> {
+  let mut cols: Vec<String> = Vec::new();
+  let mut rest = template;
+  while let Some(start) = rest.find(ATTACHMENT_MARKER_PREFIX) {
+    let after = &rest[start + ATTACHMENT_MARKER_PREFIX.len()..];
+    let Some(end) = after.find("}}") else {
+      break;
+    };
+    let col = after[..end].trim().to_string();
+    if !col.is_empty() && !cols.contains(&col) {
+      cols.push(col);
+    }
+    rest = &after[end + 2..];
+  }
+  cols
+}
+
+// {{file:列名}} をファイル名（パスの末尾）に置き換える。空欄の列は空文字になる
+pub fn replace_markers(text: &str, columns: &[String], row: &serde_json::Map<String, serde_json::Value>) -> String {
+  let mut out = text.to_string();
+  for col in columns {
+    let name = cell_path(row, col)
+      .map(|p| Path::new(&p).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or(p))
+      .unwrap_or_default();
+    out = out.replace(&format!("{}{}}}}}", ATTACHMENT_MARKER_PREFIX, col), &name);
+  }
+  out
+}
+
+// 添付列のファイルを読み込む。空欄の列は添付なしとして飛ばし、
+// ファイルが無い・上限超過（1件・合計）・非対応の形式はその行のエラーとして返す
+pub async fn load(
+  columns: &[String],
+  row: &serde_json::Map<String, serde_json::Value>,
+  max_bytes: u64,
+  max_total_bytes: u64,
+) -> Result<Vec<Attachment>> {
+  let mut out = Vec::new();
+  let mut total: u64 = 0;
+  for col in columns {
+    let Some(path) = cell_path(row, col) else {
+      continue;
+    };
+    let mime_type = mime_type_for(&path)
+      .ok_or_else(|| anyhow!("attachment column '{}': unsupported file type: {}", col, path))?;
+    let meta = tokio::fs::metadata(&path)
+      .await
+      .map_err(|e| anyhow!("attachment column '{}': cannot read {}: {}", col, path, e))?;
+    if !meta.is_file() {
+      return Err(anyhow!("attachment column '{}': not a file: {}", col, path));
+    }
+    if meta.len() > max_bytes {
+      return Err(anyhow!(
+        "attachment column '{}': {} is {} bytes, which exceeds the limit of {} bytes",
+        col,
+        path,
+        meta.len(),
+        max_bytes
+      ));
+    }
+    total += meta.len();
+    if total > max_total_bytes {
+      return Err(anyhow!(
+        "attachments total {} bytes (up to column '{}'), which exceeds the per-request limit of {} bytes",
+        total,
+        col,
+        max_total_bytes
+      ));
+    }
+    let bytes = tokio::fs::read(&path)
+      .await
+      .map_err(|e| anyhow!("attachment column '{}': cannot read {}: {}", col, path, e))?;
+    let sha256 = Sha256::digest(&bytes).iter().map(|b| format!("{:02x}", b)).collect();
+    out.push(Attachment {
+      column: col.clone(),
+      path,
+      mime_type: mime_type.to_string(),
+      size_bytes: bytes.len() as u64,
+      sha256,
+      data_base64: base64::engine::general_purpose::STANDARD.encode(&bytes),
+    });
+  }
+  Ok(out)
+}
+
+fn cell_path(row: &serde_json::Map<String, serde_json::Value>, col: &str) -> Option<String> {
+  let v = row.get(col)?.as_str()?.trim();
+  if v.is_empty() {
+    None
+  } else {
+    Some(v.to_string())
+  }
+}
+
+// Gemini の inline data が受け付ける形式のみ
+fn mime_type_for(path: &str) -> Option<&'static str> {
+  let ext = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+  match ext.as_str() {
+    "png" => Some("image/png"),
+    "jpg" | "jpeg" => Some("image/jpeg"),
+    "webp" => Some("image/webp"),
+    "heic" => Some("image/heic"),
+    "heif" => Some("image/heif"),
+    "pdf" => Some("application/pdf"),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn write_temp(name: &str, len: usize) -> String {
+    let dir = std::env::temp_dir().join(format!("attachments-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, vec![0u8; len]).unwrap();
+    path.to_string_lossy().to_string()
+  }
+
+  #[tokio::test]
+  async fn load_enforces_per_file_and_total_limits() {
+    let row: serde_json::Map<String, serde_json::Value> = serde_json::from_value(serde_json::json!({
+      "front": write_temp("front.png", 600),
+      "back": write_temp("back.jpg", 600),
+      "empty": "",
+    }))
+    .unwrap();
+    let cols = vec!["front".to_string(), "back".to_string(), "empty".to_string()];
+
+    let loaded = load(&cols, &row, 1000, 1200).await.unwrap();
+    assert_eq!(loaded.iter().map(|a| a.column.as_str()).collect::<Vec<_>>(), ["front", "back"]);
+    assert_eq!(loaded[1].mime_type, "image/jpeg");
+
+    let err = load(&cols, &row, 500, 10_000).await.unwrap_err().to_string();
+    assert!(err.contains("column 'front'") && err.contains("limit of 500 bytes"), "{}", err);
+
+    let err = load(&cols, &row, 1000, 1000).await.unwrap_err().to_string();
+    assert!(err.contains("total 1200 bytes") && err.contains("column 'back'"), "{}", err);
+  }
+}
@@ -0,0 +1,362 @@
+use crate::gemini::{check_finish, generation_config_json, parts_text, GenerateResponse, DEFAULT_GEMINI_API_BASE};
+use crate::prompts::SystemPrompts;
+use crate::provider::{GenerationParams, SafetySetting, Stage};
+use crate::usage::{StageUsage, TokenUsage};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+// Gemini Batch API（REST）クライアント
+// 入力 JSONL をアップロード → バッチ作成 → 状態ポーリング → 結果 JSONL をダウンロードして行番号へ戻す
+// base_url を差し替えればローカルのモックサーバーに対して動作確認できる
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BatchOptions {
+  // API のベースURL（未指定は https://generativelanguage.googleapis.com）
+  #[serde(default)]
+  pub base_url: Option<String>,
+  // 状態ポーリング間隔（秒）
+  #[serde(default)]
+  pub poll_interval_secs: Option<u64>,
+}
+
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 30;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct BatchStatus {
+  pub name: String,
+  pub state: String,
+  pub responses_file: Option<String>,
+  pub error: Option<String>,
+}
+
+impl BatchStatus {
+  // BATCH_STATE_* / JOB_STATE_* の接尾辞で判定する
+  pub fn is_succeeded(&self) -> bool {
+    self.state.ends_with("SUCCEEDED")
+  }
+
+  pub fn is_terminal(&self) -> bool {
+    ["SUCCEEDED", "FAILED", "CANCELLED", "EXPIRED"].iter().any(|s| self.state.ends_with(s))
+  }
+}
+
+pub struct BatchClient {
+  http: reqwest::Client,
+  base_url: String,
+  api_key: String,
+}
+
+impl BatchClient {
+  // http: 実行内で共有するクライアント
+  pub fn new(http: reqwest::Client, api_key: String, base_url: Option<String>) -> Self {
+    let base_url = base_url
+      .filter(|u| !u.trim().is_empty())
+      .unwrap_or_else(|| DEFAULT_GEMINI_API_BASE.to_string());
+    Self {
+      http,
+      base_url: base_url.trim_end_matches('/').to_string(),
+      api_key,
+    }
+  }
+
+  // Files API（resumable upload）で入力 JSONL をアップロードし、files/... 名を返す
+  pub async fn upload_jsonl(&self, display_name: &str, bytes: Vec<u8>) -> Result<String> {
+    let start = self
+      .http
+      .post(format!("{}/upload/v1beta/files", self.base_url))
+      .header("x-goog-api-key", &self.api_key)
+      .header("X-Goog-Upload-Protocol", "resumable")
+      .header("X-Goog-Upload-Command", "start")
+      .header("X-Goog-Upload-Header-Content-Length", bytes.len().to_string())
+      .header("X-Goog-Upload-Header-Content-Type", "application/jsonl")
+      .json(&json!({ "file": { "display_name": display_name } }))
+      .send()
+      .await?;
+    let start = check_status(start).await?;
+    let upload_url = start
+      .headers()
+      .get("x-goog-upload-url")
+      .and_then(|v| v.to_str().ok())
+      .map(|s| s.to_string())
+      .ok_or_else(|| anyhow!("x-goog-upload-url header not found in upload response"))?;
+
+    let resp = self
+      .http
+      .post(upload_url)
+      .header("x-goog-api-key", &self.api_key)
+      .header("X-Goog-Upload-Offset", "0")
+      .header("X-Goog-Upload-Command", "upload, finalize")
+      .body(bytes)
+      .send()
+      .await?;
+    let value: serde_json::Value = check_status(resp).await?.json().await?;
+    value
+      .pointer("/file/name")
+      .and_then(|v| v.as_str())
+      .map(|s| s.to_string())
+      .ok_or_else(|| anyhow!("file.name not found in upload response"))
+  }
+
+  pub async fn create(&self, model: &str, display_name: &str, file_name: &str) -> Result<BatchStatus> {
+    let model = model.trim_start_matches("models/");
+    let resp = self
+      .http
+      .post(format!("{}/v1beta/models/{}:batchGenerateContent", self.base_url, model))
+      .header("x-goog-api-key", &self.api_key)
+      .json(&json!({
+        "batch": {
+          "display_name": display_name,
+          "input_config": { "file_name": file_name },
+        }
+      }))
+      .send()
+      .await?;
+    let value: serde_json::Value = check_status(resp).await?.json().await?;
+    parse_status(&value)
+  }
+
+  pub async fn get(&self, name: &str) -> Result<BatchStatus> {
+    let resp = self
+      .http
+      .get(format!("{}/v1beta/{}", self.base_url, name))
+      .header("x-goog-api-key", &self.api_key)
+      .send()
+      .await?;
+    let value: serde_json::Value = check_status(resp).await?.json().await?;
+    parse_status(&value)
+  }
+
+  pub async fn cancel(&self, name: &str) -> Result<()> {
+    let resp = self
+      .http
+      .post(format!("{}/v1beta/{}:cancel", self.base_url, name))
+      .header("x-goog-api-key", &self.api_key)
+      .send()
+      .await?;
+    check_status(resp).await?;
+    Ok(())
+  }
+
+  pub async fn download(&self, file_name: &str) -> Result<String> {
+    let resp = self
+      .http
+      .get(format!("{}/download/v1beta/{}:download?alt=media", self.base_url, file_name))
+      .header("x-goog-api-key", &self.api_key)
+      .send()
+      .await?;
+    Ok(check_status(resp).await?.text().await?)
+  }
+}
+
+async fn check_status(resp: reqwest::Response) -> Result<reqwest::Response> {
+  let status = resp.status();
+  if status.is_success() {
+    return Ok(resp);
+  }
+  let body = resp.text().await.unwrap_or_default();
+  Err(anyhow!("bad response from server; code {}; description: {}", status.as_u16(), body))
+}
+
+// バッチ作成・取得のレスポンス（Operation 形式 / Batch 形式のどちらでも可）から状態を読む
+fn parse_status(value: &serde_json::Value) -> Result<BatchStatus> {
+  let meta = value.get("metadata").unwrap_or(value);
+  let name = value
+    .get("name")
+    .or_else(|| meta.get("name"))
+    .and_then(|v| v.as_str())
+    .ok_or_else(|| anyhow!("batch name not found in response"))?
+    .to_string();
+  let state = meta
+    .get("state")
+    .and_then(|v| v.as_str())
+    .unwrap_or("BATCH_STATE_PENDING")
+    .to_string();
+  let responses_file = value
+    .pointer("/response/responsesFile")
+    .or_else(|| meta.pointer("/output/responsesFile"))
+    .and_then(|v| v.as_str())
+    .map(|s| s.to_string());
+  let error = value
+    .get("error")
+    .map(|e| e.get("message").and_then(|m| m.as_str()).map(|s| s.to_string()).unwrap_or_else(|| e.to_string()));
+  Ok(BatchStatus { name, state, responses_file, error })
+}
+
+pub fn row_key(idx: usize) -> String {
+  format!("row-{}", idx)
+}
+
+fn parse_row_key(key: &str) -> Option<usize> {
+  key.strip_prefix("row-")?.parse().ok()
+}
+
+// 入力 JSONL の1行（単発構造化のリクエスト）
+pub fn build_request_line(
+  idx: usize,
+  prompt: &str,
+  prompts: &SystemPrompts,
+  response_schema: Option<&serde_json::Value>,
+  params: &GenerationParams,
+  safety_settings: &[SafetySetting],
+) -> serde_json::Value {
+  let mut generation_config = generation_config_json(params);
+  generation_config.insert("responseMimeType".into(), json!("application/json"));
+  if let Some(schema) = response_schema {
+    generation_config.insert("responseSchema".into(), schema.clone());
+  }
+  let mut request = json!({
+    "systemInstruction": { "parts": [{ "text": prompts.single_system }] },
+    "contents": [{ "role": "user", "parts": [{ "text": prompt }] }],
+    "generationConfig": generation_config,
+  });
+  if !safety_settings.is_empty() {
+    request["safetySettings"] = safety_settings.iter().map(|s| s.to_api_json()).collect();
+  }
+  json!({ "key": row_key(idx), "request": request })
+}
+
+// 結果 JSONL を行番号ごとの GenerateResponse へ変換する（キーが解釈できない行は無視）
+pub fn parse_results(jsonl: &str) -> Vec<(usize, Result<GenerateResponse>)> {
+  jsonl
+    .lines()
+    .filter(|l| !l.trim().is_empty())
+    .filter_map(|line| {
+      let value: serde_json::Value = serde_json::from_str(line).ok()?;
+      let idx = parse_row_key(value.get("key")?.as_str()?)?;
+      if let Some(err) = value.get("error") {
+        let msg = err.get("message").and_then(|m| m.as_str()).map(|s| s.to_string()).unwrap_or_else(|| err.to_string());
+        return Some((idx, Err(anyhow!("batch request failed: {}", msg))));
+      }
+      let Some(resp) = value.get("response") else {
+        return Some((idx, Err(anyhow!("response not found in batch output"))));
+      };
+      // 安全性フィルタ等で止まった行は終了理由付きのエラーにする
+      Some((idx, check_finish(Stage::Structure, resp).map(|_| response_from_json(resp))))
+    })
+    .collect()
+}
+
+// GenerateContentResponse（REST JSON）から本文（思考パートを除く）・使用量・モデルを取り出す
+fn response_from_json(resp: &serde_json::Value) -> GenerateResponse {
+  let text = resp
+    .pointer("/candidates/0/content/parts")
+    .and_then(|p| p.as_array())
+    .map(|parts| parts_text(parts))
+    .unwrap_or_default();
+  GenerateResponse {
+    text,
+    usage: Some(StageUsage { search: None, structure: resp.get("usageMetadata").and_then(TokenUsage::from_gemini), tools: None, verify: None }),
+    model: resp.get("modelVersion").and_then(|m| m.as_str()).map(|s| s.to_string()),
+    ..Default::default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_server::{serve, Reply};
+
+  fn result_line(idx: usize, body: serde_json::Value) -> String {
+    let mut line = body;
+    line["key"] = json!(row_key(idx));
+    line.to_string()
+  }
+
+  fn ok_response(text: &str) -> serde_json::Value {
+    json!({
+      "candidates": [{ "content": { "parts": [{ "text": "thinking", "thought": true }, { "text": text }] }, "finishReason": "STOP" }],
+      "usageMetadata": { "promptTokenCount": 10, "candidatesTokenCount": 5, "totalTokenCount": 15 },
+      "modelVersion": "gemini-test",
+    })
+  }
+
+  #[tokio::test]
+  async fn runs_a_batch_against_a_local_server() {
+    let polls = std::sync::atomic::AtomicUsize::new(0);
+    // 行 0・2・3 は成功、行 1 はエラー、行 4 は安全性フィルタで停止、行 5 は response なし（順不同で返す）
+    let results = [
+      result_line(3, json!({ "response": ok_response("{\"v\":3}") })),
+      result_line(1, json!({ "error": { "code": 500, "message": "internal" } })),
+      result_line(0, json!({ "response": ok_response("{\"v\":0}") })),
+      result_line(4, json!({ "response": { "candidates": [{ "finishReason": "SAFETY" }] } })),
+      json!({ "key": "not-a-row", "response": ok_response("ignored") }).to_string(),
+      result_line(5, json!({})),
+      result_line(2, json!({ "response": ok_response("{\"v\":2}") })),
+    ]
+    .join("\n");
+    let server = serve(move |req, base| match (req.method.as_str(), req.path.as_str()) {
+      ("POST", "/upload/v1beta/files") if req.header("X-Goog-Upload-Command") == Some("start") => {
+        Reply::json(json!({})).header("x-goog-upload-url", &format!("{}/upload/session-1", base))
+      }
+      ("POST", "/upload/session-1") => Reply::json(json!({ "file": { "name": "files/input-1" } })),
+      ("POST", "/v1beta/models/gemini-test:batchGenerateContent") => Reply::json(json!({
+        "name": "batches/b1",
+        "metadata": { "name": "batches/b1", "state": "BATCH_STATE_PENDING" },
+      })),
+      ("GET", "/v1beta/batches/b1") => {
+        if polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+          Reply::json(json!({ "name": "batches/b1", "metadata": { "state": "BATCH_STATE_RUNNING" } }))
+        } else {
+          Reply::json(json!({
+            "name": "batches/b1",
+            "metadata": { "state": "BATCH_STATE_SUCCEEDED" },
+            "done": true,
+            "response": { "responsesFile": "files/output-1" },
+          }))
+        }
+      }
+      ("GET", "/download/v1beta/files/output-1:download?alt=media") => {
+        Reply::with_type(200, "application/jsonl", results.clone())
+      }
+      _ => Reply::with_type(404, "text/plain", "not found"),
+    })
+    .await;
+
+    let client = BatchClient::new(reqwest::Client::new(), "test-key".into(), Some(format!("{}/", server.base_url)));
+    let prompts = SystemPrompts::resolve(&Default::default());
+    let lines: Vec<String> = (0..6)
+      .map(|i| build_request_line(i, &format!("prompt {}", i), &prompts, None, &GenerationParams::default(), &[]).to_string())
+      .collect();
+    let file = client.upload_jsonl("run", lines.join("\n").into_bytes()).await.unwrap();
+    assert_eq!(file, "files/input-1");
+
+    let created = client.create("models/gemini-test", "run", &file).await.unwrap();
+    assert_eq!(created.name, "batches/b1");
+    assert!(!created.is_terminal());
+
+    let running = client.get(&created.name).await.unwrap();
+    assert_eq!(running.state, "BATCH_STATE_RUNNING");
+    assert!(!running.is_terminal());
+    let done = client.get(&created.name).await.unwrap();
+    assert!(done.is_succeeded());
+    assert_eq!(done.responses_file.as_deref(), Some("files/output-1"));
+
+    let mut parsed = parse_results(&client.download("files/output-1").await.unwrap());
+    parsed.sort_by_key(|(idx, _)| *idx);
+    assert_eq!(parsed.iter().map(|(idx, _)| *idx).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5]);
+    for (idx, res) in &parsed {
+      match idx {
+        0 | 2 | 3 => {
+          let resp = res.as_ref().unwrap();
+          assert_eq!(resp.text, format!("{{\"v\":{}}}", idx));
+          assert_eq!(resp.model.as_deref(), Some("gemini-test"));
+          assert_eq!(resp.usage.as_ref().and_then(|u| u.structure.as_ref()).map(|u| u.total_tokens), Some(15));
+        }
+        1 => assert!(res.as_ref().unwrap_err().to_string().contains("internal")),
+        4 => assert!(res.as_ref().unwrap_err().to_string().contains("SAFETY")),
+        _ => assert!(res.as_ref().unwrap_err().to_string().contains("response not found")),
+      }
+    }
+
+    let requests = server.requests();
+    assert!(requests.iter().all(|r| r.header("x-goog-api-key") == Some("test-key")));
+    let upload = requests.iter().find(|r| r.path == "/upload/session-1").unwrap();
+    let uploaded = String::from_utf8(upload.body.clone()).unwrap();
+    let first: serde_json::Value = serde_json::from_str(uploaded.lines().next().unwrap()).unwrap();
+    assert_eq!(first["key"], "row-0");
+    assert_eq!(first["request"]["contents"][0]["parts"][0]["text"], "prompt 0");
+    let create = requests.iter().find(|r| r.path.ends_with(":batchGenerateContent")).unwrap();
+    assert_eq!(create.json()["batch"]["input_config"]["file_name"], "files/input-1");
+  }
+}
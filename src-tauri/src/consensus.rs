@@ -0,0 +1,198 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// self-consistency: 同じ行の複数サンプル（構造化 JSON）をフィールドごとにまとめる
+// 数値は中央値、それ以外（列挙・真偽値・文字列・配列など）は最も多い値を採用し、
+// 採用した値と一致したサンプルの割合をそのフィールドの一致率とする
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Consistency {
+  // 生成したサンプル数
+  pub samples: u32,
+  // JSON として解釈できたサンプル数（一致率の分母）
+  pub valid_samples: u32,
+  // フィールド名 → 一致率（0.0〜1.0）。トップレベルがオブジェクトでない場合は "$"
+  pub agreement: BTreeMap<String, f64>,
+}
+
+// 解釈できたサンプルをまとめ、まとめた値と一致率を返す（サンプルが無ければ None）
+pub fn merge(samples: &[serde_json::Value]) -> Option<(serde_json::Value, BTreeMap<String, f64>)> {
+  if samples.is_empty() {
+    return None;
+  }
+  let mut agreement = BTreeMap::new();
+  if !samples.iter().all(|s| s.is_object()) {
+    let values: Vec<Option<&serde_json::Value>> = samples.iter().map(Some).collect();
+    let (value, ratio) = vote(&values)?;
+    agreement.insert("$".to_string(), ratio);
+    return Some((value, agreement));
+  }
+
+  // フィールドの順序は最初に現れたサンプルの順
+  let mut keys: Vec<&String> = Vec::new();
+  for s in samples {
+    for k in s.as_object().into_iter().flat_map(|o| o.keys()) {
+      if !keys.contains(&k) {
+        keys.push(k);
+      }
+    }
+  }
+  let mut merged = serde_json::Map::new();
+  for key in keys {
+    let values: Vec<Option<&serde_json::Value>> = samples.iter().map(|s| s.get(key)).collect();
+    if let Some((value, ratio)) = vote(&values) {
+      merged.insert(key.clone(), value);
+      agreement.insert(key.clone(), ratio);
+    }
+  }
+  Some((serde_json::Value::Object(merged), agreement))
+}
+
+// 1フィールド分の値を選ぶ（欠けているサンプルは不一致として数える）
+fn vote(values: &[Option<&serde_json::Value>]) -> Option<(serde_json::Value, f64)> {
+  let present: Vec<&serde_json::Value> = values.iter().flatten().copied().collect();
+  if present.is_empty() {
+    return None;
+  }
+  let non_null: Vec<&serde_json::Value> = present.iter().copied().filter(|v| !v.is_null()).collect();
+  let chosen = if !non_null.is_empty() && non_null.iter().all(|v| v.is_number()) && non_null.len() * 2 > present.len() {
+    median(&non_null)
+  } else {
+    most_common(&present)
+  };
+  let agreeing = present.iter().filter(|v| same_value(v, &chosen)).count();
+  Some((chosen, agreeing as f64 / values.len() as f64))
+}
+
+// 偶数個の場合は小さい側の中央（どれかのサンプルの値をそのまま使う）
+fn median(values: &[&serde_json::Value]) -> serde_json::Value {
+  let mut sorted: Vec<&serde_json::Value> = values.to_vec();
+  sorted.sort_by(|a, b| {
+    let (a, b) = (a.as_f64().unwrap_or_default(), b.as_f64().unwrap_or_default());
+    a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+  });
+  sorted[(sorted.len() - 1) / 2].clone()
+}
+
+// 同数の場合は先に現れた値
+fn most_common(values: &[&serde_json::Value]) -> serde_json::Value {
+  let mut best: Option<(&serde_json::Value, usize)> = None;
+  for v in values {
+    let count = values.iter().filter(|o| same_value(o, v)).count();
+    if !matches!(best, Some((_, c)) if c >= count) {
+      best = Some((v, count));
+    }
+  }
+  best.map(|(v, _)| v.clone()).unwrap_or_default()
+}
+
+// 数値は 1 と 1.0 を同じ値として扱う
+pub(crate) fn same_value(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+  match (a.as_f64(), b.as_f64()) {
+    (Some(x), Some(y)) => x == y,
+    _ => a == b,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  // (説明, サンプル, まとめた値, フィールド → 一致率)
+  type Case = (&'static str, Vec<serde_json::Value>, serde_json::Value, Vec<(&'static str, f64)>);
+
+  #[test]
+  fn merge_picks_values_per_field() {
+    let cases: Vec<Case> = vec![
+      (
+        "numbers use the median",
+        vec![json!({ "price": 10 }), json!({ "price": 30 }), json!({ "price": 20 })],
+        json!({ "price": 20 }),
+        vec![("price", 1.0 / 3.0)],
+      ),
+      (
+        "1 and 1.0 agree",
+        vec![json!({ "n": 1 }), json!({ "n": 1.0 }), json!({ "n": 2 })],
+        json!({ "n": 1.0 }),
+        vec![("n", 2.0 / 3.0)],
+      ),
+      (
+        "even count takes the lower middle",
+        vec![json!({ "n": 4 }), json!({ "n": 2 }), json!({ "n": 8 }), json!({ "n": 6 })],
+        json!({ "n": 4 }),
+        vec![("n", 0.25)],
+      ),
+      (
+        "enums take the majority",
+        vec![json!({ "color": "red" }), json!({ "color": "blue" }), json!({ "color": "red" })],
+        json!({ "color": "red" }),
+        vec![("color", 2.0 / 3.0)],
+      ),
+      (
+        "booleans take the majority",
+        vec![json!({ "ok": true }), json!({ "ok": false }), json!({ "ok": true }), json!({ "ok": true })],
+        json!({ "ok": true }),
+        vec![("ok", 0.75)],
+      ),
+      (
+        "strings must match exactly",
+        vec![json!({ "maker": "Acme Inc." }), json!({ "maker": "ACME" }), json!({ "maker": "Acme Inc." })],
+        json!({ "maker": "Acme Inc." }),
+        vec![("maker", 2.0 / 3.0)],
+      ),
+      (
+        "nested objects are compared as a whole",
+        vec![
+          json!({ "address": { "city": "Tokyo", "zip": "100" } }),
+          json!({ "address": { "city": "Osaka", "zip": "530" } }),
+          json!({ "address": { "city": "Tokyo", "zip": "100" } }),
+        ],
+        json!({ "address": { "city": "Tokyo", "zip": "100" } }),
+        vec![("address", 2.0 / 3.0)],
+      ),
+      (
+        "ties keep the first value",
+        vec![json!({ "label": "a", "score": 20 }), json!({ "label": "b", "score": 10 })],
+        json!({ "label": "a", "score": 10 }),
+        vec![("label", 0.5), ("score", 0.5)],
+      ),
+      (
+        "missing and null values count as disagreement",
+        vec![json!({ "price": 10, "label": "a" }), json!({ "label": "a" }), json!({ "price": null, "label": "b" })],
+        json!({ "price": 10, "label": "a" }),
+        vec![("price", 1.0 / 3.0), ("label", 2.0 / 3.0)],
+      ),
+      (
+        "mostly null numbers stay null",
+        vec![json!({ "p": null }), json!({ "p": 5 }), json!({ "p": null })],
+        json!({ "p": null }),
+        vec![("p", 2.0 / 3.0)],
+      ),
+      (
+        "fields from any sample are kept",
+        vec![json!({ "a": 1 }), json!({ "a": 1, "extra": "x" })],
+        json!({ "a": 1, "extra": "x" }),
+        vec![("a", 1.0), ("extra", 0.5)],
+      ),
+      (
+        "non-object samples vote on the whole value",
+        vec![json!(["x", "y"]), json!({ "a": 1 }), json!(["x", "y"])],
+        json!(["x", "y"]),
+        vec![("$", 2.0 / 3.0)],
+      ),
+    ];
+    for (name, samples, want, want_agreement) in cases {
+      let (merged, agreement) = merge(&samples).unwrap_or_else(|| panic!("{}: no result", name));
+      assert_eq!(merged, want, "{}", name);
+      let want_agreement: BTreeMap<String, f64> = want_agreement.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+      assert_eq!(agreement, want_agreement, "{}", name);
+    }
+  }
+
+  #[test]
+  fn merge_without_samples_is_none() {
+    // 失敗したサンプル（JSON として読めなかったものを含む）は渡されないため、全滅なら None
+    assert!(merge(&[]).is_none());
+  }
+}
@@ -0,0 +1,188 @@
+use crate::provider::BoxFuture;
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::time::Duration;
+
+// URL グラウンディング用のページ取得・本文抽出
+// 取得処理はトレイトで差し替えられる（ローカルの HTTP サーバーや固定のページ本文で動作確認できる）
+
+#[derive(Debug, Clone)]
+pub struct FetchedPage {
+  pub url: String,
+  pub title: Option<String>,
+  pub text: String,
+  // max_chars（または受信バイト数の上限）で切り詰めたか
+  pub truncated: bool,
+}
+
+pub trait PageFetcher: Send + Sync {
+  fn fetch<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<FetchedPage>>;
+}
+
+// 既定のページ本文の上限（文字数）
+pub const DEFAULT_MAX_PAGE_CHARS: usize = 20_000;
+// 受信するレスポンス本文の上限（バイト）。超えた分は読まずに切り詰める
+pub const MAX_PAGE_BYTES: usize = 5 * 1024 * 1024;
+
+// reqwest で GET し、HTML からタグを除いた本文を取り出す（http は実行内で共有するクライアント）
+pub struct HttpPageFetcher {
+  http: reqwest::Client,
+  max_chars: usize,
+  max_bytes: usize,
+  timeout_secs: u64,
+}
+
+impl HttpPageFetcher {
+  pub fn new(http: reqwest::Client, max_chars: usize, timeout_secs: u64) -> Self {
+    Self { http, max_chars, max_bytes: MAX_PAGE_BYTES, timeout_secs }
+  }
+
+  pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+    self.max_bytes = max_bytes;
+    self
+  }
+}
+
+impl PageFetcher for HttpPageFetcher {
+  fn fetch<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<FetchedPage>> {
+    Box::pin(async move {
+      let mut req = self.http.get(url);
+      if self.timeout_secs > 0 {
+        req = req.timeout(Duration::from_secs(self.timeout_secs));
+      }
+      let mut resp = req.send().await?;
+      let status = resp.status();
+      if !status.is_success() {
+        return Err(anyhow!("HTTP {}", status.as_u16()));
+      }
+      let is_html = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|ct| ct.contains("html"))
+        .unwrap_or(true);
+      // Content-Length を信用せず、受信しながら上限で打ち切る
+      let mut bytes = Vec::new();
+      let mut cut = false;
+      while let Some(chunk) = resp.chunk().await? {
+        let room = self.max_bytes.saturating_sub(bytes.len());
+        bytes.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        if chunk.len() > room {
+          cut = true;
+          break;
+        }
+      }
+      let body = String::from_utf8_lossy(&bytes);
+      let (title, text) = if is_html { extract_html_text(&body) } else { (None, collapse_whitespace(&body)) };
+      let (text, truncated) = truncate_chars(text, self.max_chars);
+      Ok(FetchedPage { url: url.to_string(), title, text, truncated: truncated || cut })
+    })
+  }
+}
+
+static TITLE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+static NON_CONTENT_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"(?is)<(script|style|noscript|svg|head)[^>]*>.*?</(script|style|noscript|svg|head)>|<!--.*?-->").unwrap());
+static BLOCK_TAG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)</?(p|div|br|li|tr|h[1-6]|section|article|table)[^>]*>").unwrap());
+static TAG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<[^>]*>").unwrap());
+
+// タイトルと本文（スクリプト・スタイル・タグを除去）を取り出す
+pub fn extract_html_text(html: &str) -> (Option<String>, String) {
+  let title = TITLE_RE
+    .captures(html)
+    .map(|c| collapse_whitespace(&decode_entities(&c[1])))
+    .filter(|t| !t.is_empty());
+  let body = NON_CONTENT_RE.replace_all(html, " ");
+  let body = BLOCK_TAG_RE.replace_all(&body, "\n");
+  let body = TAG_RE.replace_all(&body, " ");
+  (title, collapse_whitespace(&decode_entities(&body)))
+}
+
+fn decode_entities(s: &str) -> String {
+  s.replace("&nbsp;", " ")
+    .replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&quot;", "\"")
+    .replace("&#39;", "'")
+    .replace("&amp;", "&")
+}
+
+// 行内の連続空白を1つにまとめ、空行を除く
+fn collapse_whitespace(s: &str) -> String {
+  s.lines()
+    .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+    .filter(|l| !l.is_empty())
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+fn truncate_chars(text: String, max_chars: usize) -> (String, bool) {
+  if max_chars == 0 || text.chars().count() <= max_chars {
+    return (text, false);
+  }
+  (text.chars().take(max_chars).collect(), true)
+}
+
+// セルの値から http(s) の URL を取り出す（空白・カンマ・改行区切りで複数可）
+pub fn extract_urls(value: &str) -> Vec<String> {
+  let mut urls: Vec<String> = Vec::new();
+  for token in value.split(|c: char| c.is_whitespace() || c == ',' || c == ';') {
+    let token = token.trim_matches(|c: char| c == '"' || c == '\'' || c == '<' || c == '>');
+    if (token.starts_with("http://") || token.starts_with("https://")) && !urls.iter().any(|u| u == token) {
+      urls.push(token.to_string());
+    }
+  }
+  urls
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_server::{serve, Reply};
+
+  const PAGE: &str = r#"<html><head><title> Acme &amp; Co </title><style>body { color: red }</style></head>
+<body><script>var x = "<p>hidden</p>";</script><!-- note -->
+<h1>About</h1><p>Founded in   1999.</p><div>Tokyo&nbsp;office</div></body></html>"#;
+
+  #[test]
+  fn extracts_title_and_visible_text() {
+    let (title, text) = extract_html_text(PAGE);
+    assert_eq!(title.as_deref(), Some("Acme & Co"));
+    assert_eq!(text, "About\nFounded in 1999.\nTokyo office");
+  }
+
+  #[tokio::test]
+  async fn fetches_pages_from_a_local_server() {
+    let server = serve(|req, _| match req.path.as_str() {
+      "/page" => Reply::with_type(200, "text/html; charset=utf-8", PAGE),
+      "/plain" => Reply::with_type(200, "text/plain", "line one\n\n  line   two "),
+      // Content-Length なしで上限を超える本文を返す
+      "/large" => Reply { content_length: false, ..Reply::with_type(200, "text/plain", "a".repeat(10_000)) },
+      _ => Reply::with_type(404, "text/plain", "not found"),
+    })
+    .await;
+    let fetcher = HttpPageFetcher::new(reqwest::Client::new(), 10, 5);
+
+    let page = fetcher.fetch(&format!("{}/page", server.base_url)).await.unwrap();
+    assert_eq!(page.title.as_deref(), Some("Acme & Co"));
+    assert_eq!(page.text, "About\nFoun");
+    assert!(page.truncated);
+
+    let plain = HttpPageFetcher::new(reqwest::Client::new(), 0, 5).fetch(&format!("{}/plain", server.base_url)).await.unwrap();
+    assert_eq!(plain.title, None);
+    assert_eq!(plain.text, "line one\nline two");
+    assert!(!plain.truncated);
+
+    let large = HttpPageFetcher::new(reqwest::Client::new(), 0, 5)
+      .with_max_bytes(1000)
+      .fetch(&format!("{}/large", server.base_url))
+      .await
+      .unwrap();
+    assert_eq!(large.text.len(), 1000);
+    assert!(large.truncated);
+
+    let missing = fetcher.fetch(&format!("{}/missing", server.base_url)).await.unwrap_err();
+    assert_eq!(missing.to_string(), "HTTP 404");
+  }
+}
@@ -1,6 +1,19 @@
+use crate::attachments::Attachment;
+use crate::fetch::FetchedPage;
+use crate::http_pool::SharedHttp;
+use crate::notes_cache::{self, CachedNotes};
+use crate::tools::{LocalToolRun, ToolCallRecord};
+use crate::usage::{StageUsage, TokenUsage};
+use crate::sources;
+use crate::verify::{self, FieldVerdict};
+use crate::provider::{
+  json_vector, with_timeout, BoxFuture, EmbedRequest, EmbedResponse, FinishReason, GenerateError, GenerateRequest, GenerationParams, LlmProvider,
+  PartialSink, PrefixCacheRequest, SafetySetting, Stage, TextRequest, UrlGrounding,
+};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use gemini_rust::{Gemini, Tool};
+use gemini_rust::{Content, GenerateContentRequest, GenerationConfig, Role, Tool};
+use std::sync::{Arc, Mutex};
 
 // ↑ 旧REST実装は削除（gemini-rustへ移行）
 
@@ -10,41 +23,497 @@ use gemini_rust::{Gemini, Tool};
 
 // （旧REST向けの構造体類は削除）
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct GenerateResponse {
   pub text: String,
   pub grounding_metadata: Option<serde_json::Value>,
   pub intermediate_notes: Option<String>,
   pub stage2_input: Option<String>,
+  // 段階別のトークン使用量
+  pub usage: Option<StageUsage>,
+  // 応答したモデル（レスポンスの modelVersion など）
+  pub model: Option<String>,
+  // 共有メモ（notes cache）を使った場合、既存のメモを再利用したか
+  #[serde(default)]
+  pub notes_cache_hit: Option<bool>,
+  // 検証段を行った場合のフィールドごとの判定
+  #[serde(default)]
+  pub verification: Option<Vec<FieldVerdict>>,
+  // 検証段が失敗した場合の理由（構造化結果はそのまま使い、verification は None）
+  #[serde(default)]
+  pub verification_error: Option<String>,
 }
 
 // （旧REST用スキーマ関数は不要）
 
+// REST で直接呼び出す API（Batch など）のベースURL
+pub const DEFAULT_GEMINI_API_BASE: &str = "https://generativelanguage.googleapis.com";
+
+// モデル未指定時に使用するモデル（request ログに実際の値を残すため明示する）
+pub const DEFAULT_GEMINI_MODEL: &str = "gemini-2.5-flash";
+
+// 埋め込みでモデル未指定時に使用するモデル
+pub const DEFAULT_GEMINI_EMBEDDING_MODEL: &str = "gemini-embedding-001";
+
+// gemini-rust のリクエスト・応答の型を使い、実行内で共有するクライアントで REST を呼ぶ LlmProvider 実装
+pub struct GeminiProvider {
+  http: SharedHttp,
+  api_key: String,
+  model: String,
+  // create_prefix_cache で登録したプロンプト接頭辞のキャッシュ名（cachedContents/...。実行中の全行で共有）
+  prefix_cache: Mutex<Option<Arc<String>>>,
+}
+
+impl GeminiProvider {
+  pub fn new(api_key: String, model: Option<String>, http: SharedHttp) -> Self {
+    Self {
+      http,
+      api_key,
+      model: model.unwrap_or_else(|| DEFAULT_GEMINI_MODEL.to_string()),
+      prefix_cache: Mutex::new(None),
+    }
+  }
+
+  fn api<'a>(&'a self, model: &'a str) -> ApiTarget<'a> {
+    ApiTarget { http: &self.http, api_key: &self.api_key, model }
+  }
+}
+
+impl LlmProvider for GeminiProvider {
+  fn name(&self) -> &'static str {
+    "gemini"
+  }
+
+  fn model(&self) -> &str {
+    &self.model
+  }
+
+  fn supports_web_search(&self) -> bool {
+    true
+  }
+
+  fn supports_streaming(&self) -> bool {
+    true
+  }
+
+  fn supports_local_tools(&self) -> bool {
+    true
+  }
+
+  fn generate_structured(&self, req: GenerateRequest) -> BoxFuture<'_, Result<GenerateResponse>> {
+    let cache = if req.prefix_cached { self.prefix_cache.lock().unwrap().clone() } else { None };
+    Box::pin(async move {
+      if req.prefix_cached && cache.is_none() {
+        return Err(anyhow!("prompt prefix cache is not available"));
+      }
+      // キャッシュは作成時のモデル専用
+      let model = req.model.clone().unwrap_or_else(|| self.model.clone());
+      if req.prefix_cached && model != self.model {
+        return Err(anyhow!("prompt prefix cache cannot be used with model {}", model));
+      }
+      generate_events_with_search_once(&self.api(&model), req, cache).await
+    })
+  }
+
+  fn generate_text(&self, req: TextRequest) -> BoxFuture<'_, Result<GenerateResponse>> {
+    Box::pin(async move {
+      generate_prompt_text_once(&self.api(&self.model), req).await
+    })
+  }
+
+  // embedContent は REST で直接呼ぶ（使用量は返らない）
+  fn embed(&self, req: EmbedRequest) -> BoxFuture<'_, Result<EmbedResponse>> {
+    Box::pin(async move {
+      let url = self.api(&self.model).url("embedContent");
+      let mut body = serde_json::json!({ "content": { "parts": [{ "text": req.text }] } });
+      if let Some(task_type) = req.task_type.as_ref() {
+        body["taskType"] = serde_json::json!(task_type);
+      }
+      if let Some(dims) = req.dimensions {
+        body["outputDimensionality"] = serde_json::json!(dims);
+      }
+      let resp = with_timeout(Stage::Embed, req.timeout_secs, post_generate_content(&self.http, &url, &self.api_key, &body)).await?;
+      let vector = resp
+        .pointer("/embedding/values")
+        .and_then(json_vector)
+        .ok_or_else(|| anyhow!("embedding.values not found in Gemini response"))?;
+      Ok(EmbedResponse { vector, usage: None, model: Some(self.model.clone()) })
+    })
+  }
+
+  // countTokens も REST で直接呼ぶ（texts はそれぞれ1つのユーザーターンとして、1回のリクエストで合計を数える）
+  fn count_tokens(&self, texts: Vec<String>, timeout_secs: u64) -> BoxFuture<'_, Result<u64>> {
+    Box::pin(async move {
+      let url = self.api(&self.model).url("countTokens");
+      let contents: Vec<serde_json::Value> =
+        texts.iter().map(|t| serde_json::json!({ "role": "user", "parts": [{ "text": t }] })).collect();
+      let body = serde_json::json!({ "contents": contents });
+      let resp = with_timeout(Stage::CountTokens, timeout_secs, post_generate_content(&self.http, &url, &self.api_key, &body)).await?;
+      resp
+        .get("totalTokens")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| anyhow!("totalTokens not found in Gemini response"))
+    })
+  }
+
+  // cachedContents も REST で直接作る
+  fn create_prefix_cache(&self, req: PrefixCacheRequest) -> BoxFuture<'_, Result<Option<String>>> {
+    Box::pin(async move {
+      // キャッシュを参照するリクエストは system_instruction / tools を持てないため、使う段の分をここで含める
+      let mut body = serde_json::json!({
+        "model": format!("models/{}", self.model.trim_start_matches("models/")),
+        "contents": [Content::text(req.prefix).with_role(Role::User)],
+        "ttl": format!("{}s", req.ttl_secs.max(60)),
+      });
+      if req.enable_web_search {
+        body["systemInstruction"] = serde_json::json!(Content::text(req.prompts.search_system.as_str()));
+        body["tools"] = serde_json::json!([Tool::google_search()]);
+      } else {
+        body["systemInstruction"] = serde_json::json!(Content::text(req.prompts.single_system.as_str()));
+      }
+      let url = format!("{}/v1beta/cachedContents", DEFAULT_GEMINI_API_BASE);
+      let resp = post_generate_content(&self.http, &url, &self.api_key, &body).await?;
+      let name = resp
+        .get("name")
+        .and_then(|n| n.as_str())
+        .ok_or_else(|| anyhow!("name not found in cachedContents response"))?
+        .to_string();
+      println!("[gemini.rs] create_prefix_cache: name={}", name);
+      *self.prefix_cache.lock().unwrap() = Some(Arc::new(name.clone()));
+      Ok(Some(name))
+    })
+  }
+
+  fn release_prefix_cache(&self) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+      let Some(name) = self.prefix_cache.lock().unwrap().take() else {
+        return Ok(());
+      };
+      // まだ参照中のリクエストがあれば削除せず TTL で失効させる
+      let Ok(name) = Arc::try_unwrap(name) else {
+        return Ok(());
+      };
+      let url = format!("{}/v1beta/{}", DEFAULT_GEMINI_API_BASE, name);
+      let resp = self.http.send(self.http.client.delete(url).header("x-goog-api-key", &self.api_key)).await?;
+      let status = resp.status();
+      if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(anyhow!("bad response from server; code {}; description: {}", status.as_u16(), body));
+      }
+      Ok(())
+    })
+  }
+}
+
 // ==== gemini-rust を使った新実装（Structured Response & Google Search）====
 
+// generateContent の送り先
+// リクエストは gemini-rust の GenerateContentRequest で組み立て、実行内で共有するクライアントで REST で送る
+// （gemini-rust の ContentBuilder は自前のクライアントを持ち、safetySettings も指定できないため）
+struct ApiTarget<'a> {
+  http: &'a SharedHttp,
+  api_key: &'a str,
+  model: &'a str,
+}
+
+impl ApiTarget<'_> {
+  fn url(&self, method: &str) -> String {
+    format!("{}/v1beta/models/{}:{}", DEFAULT_GEMINI_API_BASE, self.model.trim_start_matches("models/"), method)
+  }
+}
+
+// 生成パラメータを REST の generationConfig（camelCase JSON）へ変換
+pub(crate) fn generation_config_json(params: &GenerationParams) -> serde_json::Map<String, serde_json::Value> {
+  let mut cfg = serde_json::Map::new();
+  if let Some(v) = params.temperature {
+    cfg.insert("temperature".into(), serde_json::json!(v));
+  }
+  if let Some(v) = params.top_p {
+    cfg.insert("topP".into(), serde_json::json!(v));
+  }
+  if let Some(v) = params.top_k {
+    cfg.insert("topK".into(), serde_json::json!(v));
+  }
+  if let Some(v) = params.max_output_tokens {
+    cfg.insert("maxOutputTokens".into(), serde_json::json!(v));
+  }
+  if let Some(v) = params.seed {
+    cfg.insert("seed".into(), serde_json::json!(v));
+  }
+  if let Some(v) = params.thinking_budget {
+    cfg.insert("thinkingConfig".into(), serde_json::json!({ "thinkingBudget": v }));
+  }
+  cfg
+}
+
+// リクエスト本文を作り、生成パラメータを generationConfig へ直接載せる
+// （gemini_rust の GenerationConfig には seed などが無く、型を経由すると黙って落ちるため）
+fn request_body(request: &GenerateContentRequest, params: &GenerationParams) -> Result<serde_json::Value> {
+  let mut body = serde_json::to_value(request)?;
+  let params = generation_config_json(params);
+  if !params.is_empty() {
+    let obj = body.as_object_mut().ok_or_else(|| anyhow!("request body is not a JSON object"))?;
+    match obj.entry("generationConfig").or_insert_with(|| serde_json::json!({})) {
+      serde_json::Value::Object(cfg) => cfg.extend(params),
+      other => *other = serde_json::Value::Object(params),
+    }
+  }
+  Ok(body)
+}
+
+fn extract_usage(resp: &serde_json::Value) -> Option<TokenUsage> {
+  TokenUsage::from_gemini(resp.get("usageMetadata")?)
+}
+
+fn extract_model_version(resp: &serde_json::Value) -> Option<String> {
+  resp.get("modelVersion")?.as_str().map(|s| s.to_string())
+}
+
+// 検索フェーズのレスポンスから出典情報（検索クエリ・参照URL/タイトル・根拠区間）を取り出す
+// フロントエンドの GeminiResponse.groundingMetadata と同じ形に揃える
+fn extract_grounding_metadata(resp: &serde_json::Value) -> Option<serde_json::Value> {
+  let gm = resp.pointer("/candidates/0/groundingMetadata")?.as_object()?;
+  let chunks: Vec<serde_json::Value> = gm
+    .get("groundingChunks")
+    .and_then(|c| c.as_array())
+    .map(|arr| {
+      arr
+        .iter()
+        .filter_map(|c| c.get("web"))
+        .map(|web| serde_json::json!({
+          "web": {
+            "uri": web.get("uri").cloned().unwrap_or(serde_json::Value::Null),
+            "title": web.get("title").cloned().unwrap_or(serde_json::Value::Null),
+          }
+        }))
+        .collect()
+    })
+    .unwrap_or_default();
+  Some(serde_json::json!({
+    "webSearchQueries": gm.get("webSearchQueries").cloned().unwrap_or_else(|| serde_json::json!([])),
+    "groundingChunks": chunks,
+    "groundingSupports": gm.get("groundingSupports").cloned().unwrap_or_else(|| serde_json::json!([])),
+  }))
+}
+
+// 1段分のリクエスト（システムプロンプト・ユーザーメッセージ、添付ファイルは inline data としてその後ろに付ける。生成パラメータは run_stage で載せる）
+fn stage_request(system: Option<&str>, user: String, attachments: &[Attachment]) -> GenerateContentRequest {
+  let mut contents = vec![Content::text(user).with_role(Role::User)];
+  contents.extend(attachments.iter().map(|a| Content::inline_data(a.mime_type.as_str(), a.data_base64.as_str()).with_role(Role::User)));
+  GenerateContentRequest {
+    contents,
+    generation_config: None,
+    safety_settings: None,
+    tools: None,
+    tool_config: None,
+    system_instruction: system.map(Content::text),
+    cached_content: None,
+  }
+}
+
+// 応答を JSON（スキーマ指定時はその形）にする
+fn json_output(mut request: GenerateContentRequest, schema: Option<serde_json::Value>) -> GenerateContentRequest {
+  let cfg = request.generation_config.get_or_insert_with(GenerationConfig::default);
+  cfg.response_mime_type = Some("application/json".to_string());
+  cfg.response_schema = schema;
+  request
+}
+
+// 1回の generateContent 呼び出しの結果（一括・ストリーミング共通）
+#[derive(Default)]
+struct StageOutput {
+  text: String,
+  usage: Option<TokenUsage>,
+  grounding_metadata: Option<serde_json::Value>,
+  model: Option<String>,
+}
+
+impl StageOutput {
+  fn from_response(resp: &serde_json::Value) -> Self {
+    let parts = resp.pointer("/candidates/0/content/parts").and_then(|p| p.as_array()).map(|p| p.as_slice()).unwrap_or_default();
+    Self {
+      text: parts_text(parts),
+      usage: extract_usage(resp),
+      grounding_metadata: extract_grounding_metadata(resp),
+      model: extract_model_version(resp),
+    }
+  }
+}
+
+// partial 指定時はストリーミング（SSE）で受信し、届いたテキスト片を逐次渡す（タイムアウトは受信完了までに掛かる）
+async fn run_stage(
+  api: &ApiTarget<'_>,
+  mut request: GenerateContentRequest,
+  params: &GenerationParams,
+  safety: &[gemini_rust::SafetySetting],
+  stage: Stage,
+  secs: u64,
+  partial: Option<&PartialSink>,
+) -> Result<StageOutput> {
+  if !safety.is_empty() {
+    request.safety_settings = Some(safety.to_vec());
+  }
+  let body = request_body(&request, params)?;
+  match partial {
+    None => {
+      let resp = with_timeout(stage, secs, post_generate_content(api.http, &api.url("generateContent"), api.api_key, &body)).await?;
+      check_finish(stage, &resp)?;
+      Ok(StageOutput::from_response(&resp))
+    }
+    Some(sink) => with_timeout(stage, secs, stream_stage(api, &body, stage, sink)).await,
+  }
+}
+
+async fn stream_stage(api: &ApiTarget<'_>, body: &serde_json::Value, stage: Stage, sink: &PartialSink) -> Result<StageOutput> {
+  let url = format!("{}?alt=sse", api.url("streamGenerateContent"));
+  let mut resp = api.http.send(api.http.client.post(url).header("x-goog-api-key", api.api_key).json(body)).await?;
+  let status = resp.status();
+  if !status.is_success() {
+    let body = resp.text().await.unwrap_or_default();
+    return Err(anyhow!("bad response from server; code {}; description: {}", status.as_u16(), body));
+  }
+  let mut out = StageOutput::default();
+  let mut buf: Vec<u8> = Vec::new();
+  let mut done = false;
+  while !done {
+    match resp.chunk().await? {
+      Some(bytes) => buf.extend(bytes.iter().filter(|b| **b != b'\r')),
+      None => {
+        // 末尾に空行の無いイベントも読む
+        buf.extend_from_slice(b"\n\n");
+        done = true;
+      }
+    }
+    for data in drain_sse_events(&mut buf) {
+      let chunk: serde_json::Value = serde_json::from_str(&data).map_err(|e| anyhow!("invalid stream chunk: {}", e))?;
+      // 終了理由は最後のチャンクに付く（途中まで流した本文はそのまま残る）
+      check_finish(stage, &chunk)?;
+      let chunk = StageOutput::from_response(&chunk);
+      if !chunk.text.is_empty() {
+        sink.emit(stage, &chunk.text);
+        out.text.push_str(&chunk.text);
+      }
+      // 使用量・出典・モデルは最後のチャンクに付くため、届いた最新の値を採用する
+      out.usage = chunk.usage.or(out.usage);
+      out.grounding_metadata = chunk.grounding_metadata.or(out.grounding_metadata);
+      out.model = chunk.model.or(out.model);
+    }
+  }
+  Ok(out)
+}
+
+// SSE の受信バッファから、空行で終わったイベントの data を取り出す（未完のイベントはバッファに残す）
+fn drain_sse_events(buf: &mut Vec<u8>) -> Vec<String> {
+  let mut events = Vec::new();
+  while let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+    let event: Vec<u8> = buf.drain(..end + 2).collect();
+    let data: Vec<String> = String::from_utf8_lossy(&event)
+      .lines()
+      .filter_map(|l| l.strip_prefix("data:"))
+      .map(|d| d.strip_prefix(' ').unwrap_or(d).to_string())
+      .collect();
+    if !data.is_empty() {
+      events.push(data.join("\n"));
+    }
+  }
+  events
+}
+
+// promptFeedback.blockReason / candidates[0].finishReason が正常終了以外なら GenerateError::Blocked にする
+// （安全性フィルタ等で止まった応答を JSON 解析エラーではなく理由付きで報告する）
+pub(crate) fn check_finish(stage: Stage, resp: &serde_json::Value) -> Result<()> {
+  if let Some(block) = resp.pointer("/promptFeedback/blockReason").and_then(|v| v.as_str()) {
+    let reason = FinishReason::from_gemini(block).unwrap_or(FinishReason::Other);
+    return Err(GenerateError::Blocked { stage, reason, detail: format!("prompt blocked ({})", block) }.into());
+  }
+  if let Some(finish) = resp.pointer("/candidates/0/finishReason").and_then(|v| v.as_str()) {
+    if let Some(reason) = FinishReason::from_gemini(finish) {
+      return Err(GenerateError::Blocked { stage, reason, detail: format!("finish reason {}", finish) }.into());
+    }
+  }
+  Ok(())
+}
+
+// 安全性フィルタのしきい値を gemini-rust の型へ変換する（未知のカテゴリ・しきい値はエラー）
+// 実行前の検証にも同じ変換を使う
+pub(crate) fn api_safety_settings(settings: &[SafetySetting]) -> Result<Vec<gemini_rust::SafetySetting>> {
+  settings
+    .iter()
+    .map(|s| {
+      serde_json::from_value::<gemini_rust::SafetySetting>(s.to_api_json())
+        .map_err(|e| anyhow!("invalid safety setting {}/{}: {}", s.category, s.threshold, e))
+    })
+    .collect()
+}
+
+// URL グラウンディングの対象ページを並行して取得する（失敗したページはエラー文字列のまま残す）
+async fn fetch_pages(grounding: &UrlGrounding) -> Vec<(String, std::result::Result<FetchedPage, String>)> {
+  let fetches = grounding.urls.iter().map(|url| async move {
+    let page = grounding.fetcher.fetch(url).await.map_err(|e| e.to_string());
+    (url.clone(), page)
+  });
+  futures::future::join_all(fetches).await
+}
+
+// 取得できたページを検索時と同じ groundingMetadata の形で出典として返す
+fn pages_grounding_metadata(pages: &[(String, std::result::Result<FetchedPage, String>)]) -> serde_json::Value {
+  let chunks: Vec<serde_json::Value> = pages
+    .iter()
+    .filter_map(|(_, p)| p.as_ref().ok())
+    .map(|p| serde_json::json!({ "web": { "uri": p.url, "title": p.title } }))
+    .collect();
+  serde_json::json!({
+    "webSearchQueries": [],
+    "groundingChunks": chunks,
+    "groundingSupports": [],
+  })
+}
+
 // プロンプト生成（テキストのみ、ツールなし）
-pub async fn generate_prompt_text_once(api_key: String, prompt: String, _timeout_secs: u64) -> Result<GenerateResponse> {
-  let client = Gemini::new(api_key).map_err(|e| anyhow!(e.to_string()))?;
-  println!("[gemini.rs] generate_prompt_text_once: prompt=\n{}", prompt);
-  let resp = client
-    .generate_content()
-    .with_user_message(prompt)
-    .execute()
-    .await
-    .map_err(|e| anyhow!(e.to_string()))?;
-  let text = resp.text().to_string();
-  println!("[gemini.rs] generate_prompt_text_once: response_text(raw)=\n{}", text);
-  Ok(GenerateResponse { text, grounding_metadata: None, intermediate_notes: None, stage2_input: None })
-}
-
-pub async fn generate_events_with_search_once(
-  api_key: String,
-  prompt: String,
-  _timeout_secs: u64,
-  enable_web_search: bool,
-  response_schema: Option<serde_json::Value>,
+async fn generate_prompt_text_once(api: &ApiTarget<'_>, req: TextRequest) -> Result<GenerateResponse> {
+  println!("[gemini.rs] generate_prompt_text_once: prompt=\n{}", req.prompt);
+  let request = stage_request(None, req.prompt, &[]);
+  let resp = run_stage(api, request, &req.params, &[], Stage::Text, req.timeout_secs, None).await?;
+  println!("[gemini.rs] generate_prompt_text_once: response_text(raw)=\n{}", resp.text);
+  Ok(GenerateResponse {
+    text: resp.text,
+    usage: Some(StageUsage { search: None, structure: resp.usage, tools: None, verify: None }),
+    model: resp.model,
+    ..Default::default()
+  })
+}
+
+// prefix_cache がある場合、prompt は接頭辞を除いた残り（システムプロンプト・ツールはキャッシュ側に含まれる）
+async fn generate_events_with_search_once(
+  api: &ApiTarget<'_>,
+  req: GenerateRequest,
+  prefix_cache: Option<Arc<String>>,
 ) -> Result<GenerateResponse> {
-  let client = Gemini::new(api_key).map_err(|e| anyhow!(e.to_string()))?;
+  let GenerateRequest {
+    prompt,
+    timeouts,
+    enable_web_search,
+    response_schema,
+    params,
+    prompts,
+    partial,
+    attachments,
+    url_grounding,
+    local_tools,
+    safety_settings,
+    shared_notes,
+    verify: verify_fields,
+    field_sources,
+    spent,
+    ..
+  } = req;
+  let partial = partial.as_ref();
+  let record = |usage: StageUsage| {
+    if let Some(sink) = spent.as_ref() {
+      sink.add(&usage);
+    }
+  };
+  let safety = api_safety_settings(&safety_settings)?;
 
   println!("[gemini.rs] generate_events_with_search_once: enable_web_search={}", enable_web_search);
   println!("[gemini.rs] generate_events_with_search_once: prompt=\n{}", prompt);
@@ -58,78 +527,358 @@ pub async fn generate_events_with_search_once(
     None => println!("[gemini.rs] response_schema=None"),
   }
 
-  if enable_web_search {
-    // 1) 検索・収集フェーズ（ツール使用／MIME・スキーマ未指定）
-    let search_prompt = format!(
-      "{}\n\n上の指示に従い、信頼できる情報源を優先して事実を収集してください。結果は箇条書きのメモとして簡潔に出力してください。",
-      prompt
-    );
-    let notes_resp = client
-      .generate_content()
-      .with_system_prompt("与えられたタスクの要件に従い、Google検索ツールで根拠を収集し、確認できた事実のみを箇条書きで要約してください。URLや出典名を含めても構いません。")
-      .with_user_message(search_prompt)
-      .with_tool(Tool::google_search())
-      .execute()
-      .await
-      .map_err(|e| anyhow!(e.to_string()))?;
-    let notes_text = notes_resp.text().to_string();
-    println!("[gemini.rs] stage1(search/collect) notes(raw)=\n{}", notes_text);
+  // 1) 検索・収集フェーズ（ツール使用／MIME・スキーマ未指定）
+  // URL 指定がある場合は検索の代わりに、取得したページ本文だけからメモを作る
+  let mut notes: Option<StageOutput> = None;
+  let mut grounding_metadata = None;
+  let mut notes_cache_hit = None;
+  if enable_web_search || url_grounding.is_some() {
+    let run_stage1 = async {
+      let (search_request, page_metadata) = match url_grounding.as_ref() {
+        Some(grounding) => {
+          let pages = fetch_pages(grounding).await;
+          println!("[gemini.rs] stage1(url) fetched {} page(s)", pages.len());
+          if pages.iter().all(|(_, p)| p.is_err()) {
+            let errors: Vec<String> = pages.iter().filter_map(|(u, p)| p.as_ref().err().map(|e| format!("{}: {}", u, e))).collect();
+            return Err(anyhow!("none of the URLs could be fetched ({})", errors.join("; ")));
+          }
+          let url_prompt = prompts.render_url_input(&prompt, &pages);
+          let request = stage_request(Some(prompts.url_system.as_str()), url_prompt, &attachments);
+          (request, Some(pages_grounding_metadata(&pages)))
+        }
+        None => {
+          let search_prompt = prompts.render_search_input(&prompt);
+          let request = match prefix_cache.as_deref() {
+            Some(cache) => GenerateContentRequest {
+              cached_content: Some(cache.clone()),
+              ..stage_request(None, search_prompt, &attachments)
+            },
+            None => GenerateContentRequest {
+              tools: Some(vec![Tool::google_search()]),
+              ..stage_request(Some(prompts.search_system.as_str()), search_prompt, &attachments)
+            },
+          };
+          (request, None)
+        }
+      };
+      let mut stage1 = run_stage(api, search_request, &params.search, &safety, Stage::Search, timeouts.search_secs, partial).await?;
+      stage1.grounding_metadata = page_metadata.or(stage1.grounding_metadata.take());
+      println!("[gemini.rs] stage1(search/collect) notes(raw)=\n{}", stage1.text);
+      Ok::<_, anyhow::Error>(stage1)
+    };
+    // 共有メモがあれば同じキーの行の検索は最初の1回だけ（URL グラウンディングは行ごとのページなので共有しない）
+    let stage1 = match shared_notes.as_ref().filter(|_| url_grounding.is_none()) {
+      Some(shared) => {
+        let mut searched = false;
+        let cached = shared
+          .cell
+          .get_or_try_init(|| {
+            searched = true;
+            async {
+              let s = run_stage1.await?;
+              Ok::<_, anyhow::Error>(CachedNotes {
+                notes: s.text,
+                grounding_metadata: s.grounding_metadata,
+                usage: s.usage,
+                created_at_ms: notes_cache::now_ms(),
+              })
+            }
+          })
+          .await?;
+        println!("[gemini.rs] stage1 notes cache {}: {}", if searched { "miss" } else { "hit" }, shared.key);
+        notes_cache_hit = Some(!searched);
+        StageOutput {
+          text: cached.notes.clone(),
+          // 再利用した行には検索の使用量を計上しない
+          usage: if searched { cached.usage } else { None },
+          grounding_metadata: cached.grounding_metadata.clone(),
+          model: None,
+        }
+      }
+      None => run_stage1.await?,
+    };
+    record(StageUsage { search: stage1.usage, ..Default::default() });
+    grounding_metadata = stage1.grounding_metadata.clone();
+    notes = Some(stage1);
+  }
+
+  // 1.5) ローカルツールフェーズ（function calling。検索メモがあれば渡し、得られたメモを追記する）
+  let mut tools_usage = None;
+  if let Some(run) = local_tools.as_ref() {
+    let tool_input = prompts.render_tool_input(&prompt, notes.as_ref().map(|n| n.text.as_str()));
+    // 検索段が無い場合は添付をこの段に付ける
+    let tool_attachments: &[Attachment] = if notes.is_none() { &attachments } else { &[] };
+    let tool_stage = ToolStage {
+      api,
+      system: &prompts.tool_system,
+      run,
+      params: &params.search,
+      safety: &safety,
+    };
+    let out = with_timeout(Stage::Tools, timeouts.search_secs, run_tool_stage(tool_stage, tool_input, tool_attachments)).await?;
+    println!("[gemini.rs] stage1.5(tools) notes(raw)=\n{}", out.text);
+    record(StageUsage { tools: out.usage, ..Default::default() });
+    tools_usage = out.usage;
+    notes = Some(match notes {
+      Some(mut n) => {
+        n.text = format!("{}\n\n{}", n.text, out.text);
+        n
+      }
+      None => StageOutput { text: out.text, ..Default::default() },
+    });
+  }
+
+  if let Some(notes) = notes {
+    let notes_text = notes.text;
 
     // 2) 構造化フェーズ（ツール未使用／MIME・スキーマ指定）
-    let mut struct_builder = client
-      .generate_content()
-      .with_system_prompt("与えられたメモの内容だけに基づき、指定されたスキーマに厳密に従うJSONを返してください。未知や不確実な値はnullを使用してください。")
-      .with_user_message({
-        let s = format!(
-          "以下のメモを、指定のスキーマに従ってJSONへ構造化してください。\n\n--- メモ ---\n{}\n----------------\n",
-          notes_text
-        );
-        s
-      })
-      .with_response_mime_type("application/json");
-    if let Some(schema) = response_schema {
-      struct_builder = struct_builder.with_response_schema(schema);
-    }
-    let struct_resp = struct_builder
-      .execute()
-      .await
-      .map_err(|e| anyhow!(e.to_string()))?;
-    let text = struct_resp.text().to_string();
+    let mut stage2_input = prompts.render_structure_input(&notes_text);
+    if field_sources {
+      stage2_input.push_str(&prompts.render_sources_input(&sources::grounding_urls(grounding_metadata.as_ref())));
+    }
+    let struct_request = json_output(
+      stage_request(Some(prompts.structure_system.as_str()), stage2_input.clone(), &[]),
+      response_schema,
+    );
+    let structured = run_stage(api, struct_request, &params.structure, &safety, Stage::Structure, timeouts.structure_secs, partial).await?;
+    record(StageUsage { structure: structured.usage, ..Default::default() });
+    let text = structured.text;
     println!("[gemini.rs] stage2(structure) response_text(raw)=\n{}", text);
     if let Ok(json) = serde_json::from_str::<serde_json::Value>(&text) {
       if let Ok(pretty) = serde_json::to_string_pretty(&json) {
         println!("[gemini.rs] stage2(structure) response_text(JSON pretty)=\n{}", pretty);
       }
     }
+
+    // 3) 検証フェーズ（構造化結果の各フィールドをメモと照合。JSON として読めない応答は検証せず、行の解析エラーに任せる）
+    let mut verification = None;
+    let mut verification_error = None;
+    let mut verify_usage = None;
+    if let (true, Ok(mut output)) = (verify_fields, serde_json::from_str::<serde_json::Value>(&text)) {
+      // 出典の対応は検証の対象にしない
+      if let Some(obj) = output.as_object_mut() {
+        obj.remove(sources::SOURCES_FIELD);
+      }
+      let verify_input = prompts.render_verify_input(&notes_text, &output.to_string());
+      let verify_request =
+        json_output(stage_request(Some(prompts.verify_system.as_str()), verify_input, &[]), Some(verify::response_schema()));
+      // 判定の JSON は途中経過として流さない（タイムアウトは構造化と同じ）
+      // 検証段の失敗（タイムアウト・判定の解析エラーなど）で行は失敗にせず、理由を添えて検証なしで返す
+      match run_stage(api, verify_request, &params.structure, &safety, Stage::Verify, timeouts.structure_secs, None).await {
+        Ok(checked) => {
+          println!("[gemini.rs] stage3(verify) response_text(raw)=\n{}", checked.text);
+          record(StageUsage { verify: checked.usage, ..Default::default() });
+          verify_usage = checked.usage;
+          match verify::parse_verdicts(&checked.text, &output) {
+            Ok(verdicts) => verification = Some(verdicts),
+            Err(e) => verification_error = Some(e.to_string()),
+          }
+        }
+        Err(e) => verification_error = Some(e.to_string()),
+      }
+    }
     Ok(GenerateResponse {
       text,
-      grounding_metadata: None,
+      grounding_metadata,
       intermediate_notes: Some(notes_text),
-      stage2_input: Some("以下のメモを、指定のスキーマに従ってJSONへ構造化してください。--- メモ --- ...".to_string()),
+      stage2_input: Some(stage2_input),
+      usage: Some(StageUsage { search: notes.usage, structure: structured.usage, tools: tools_usage, verify: verify_usage }),
+      model: structured.model,
+      notes_cache_hit,
+      verification,
+      verification_error,
     })
   } else {
     // 検索なし：単発で構造化出力（ツール未使用／MIME・スキーマ指定）
-    let mut builder = client
-      .generate_content()
-      .with_system_prompt("指定されたスキーマに厳密に従い、JSONのみを返してください。未知や不確実な値はnullを使用してください。")
-      .with_user_message(prompt)
-      .with_response_mime_type("application/json");
-    if let Some(schema) = response_schema {
-      builder = builder.with_response_schema(schema);
-    }
-    let resp = builder
-      .execute()
-      .await
-      .map_err(|e| anyhow!(e.to_string()))?;
-    let text = resp.text().to_string();
+    let system = if prefix_cache.is_some() { None } else { Some(prompts.single_system.as_str()) };
+    let request = GenerateContentRequest {
+      cached_content: prefix_cache.as_deref().cloned(),
+      ..json_output(stage_request(system, prompt, &attachments), response_schema)
+    };
+    let resp = run_stage(api, request, &params.structure, &safety, Stage::Structure, timeouts.structure_secs, partial).await?;
+    record(StageUsage { structure: resp.usage, ..Default::default() });
+    let text = resp.text;
     println!("[gemini.rs] single(structure) response_text(raw)=\n{}", text);
     if let Ok(json) = serde_json::from_str::<serde_json::Value>(&text) {
       if let Ok(pretty) = serde_json::to_string_pretty(&json) {
         println!("[gemini.rs] single(structure) response_text(JSON pretty)=\n{}", pretty);
       }
     }
-    Ok(GenerateResponse { text, grounding_metadata: None, intermediate_notes: None, stage2_input: None })
+    Ok(GenerateResponse {
+      text,
+      usage: Some(StageUsage { search: None, structure: resp.usage, tools: None, verify: None }),
+      model: resp.model,
+      ..Default::default()
+    })
+  }
+}
+
+// ローカルツール段の呼び出しに必要な情報
+struct ToolStage<'a> {
+  api: &'a ApiTarget<'a>,
+  system: &'a str,
+  run: &'a LocalToolRun,
+  params: &'a GenerationParams,
+  safety: &'a [gemini_rust::SafetySetting],
+}
+
+// functionCall → ローカル実行 → functionResponse を、関数呼び出しが無くなるまで繰り返す
+// 複数ターンの関数呼び出し履歴（thought signature を含む）をそのまま送り返すため REST を直接使う
+async fn run_tool_stage(stage: ToolStage<'_>, input: String, attachments: &[Attachment]) -> Result<StageOutput> {
+  let url = stage.api.url("generateContent");
+  let mut user_parts = vec![serde_json::json!({ "text": input })];
+  for a in attachments {
+    user_parts.push(serde_json::json!({ "inlineData": { "mimeType": a.mime_type, "data": a.data_base64 } }));
+  }
+  let mut contents = vec![serde_json::json!({ "role": "user", "parts": user_parts })];
+  let mut out = StageOutput::default();
+  let mut round: u32 = 0;
+  loop {
+    let mut body = serde_json::json!({
+      "systemInstruction": { "parts": [{ "text": stage.system }] },
+      "contents": contents,
+      "tools": [{ "functionDeclarations": stage.run.tools.declarations() }],
+      "generationConfig": generation_config_json(stage.params),
+    });
+    // 上限に達したら関数呼び出しを禁止し、ここまでの結果でメモを書かせる
+    if round >= stage.run.max_rounds {
+      body["toolConfig"] = serde_json::json!({ "functionCallingConfig": { "mode": "NONE" } });
+    }
+    if !stage.safety.is_empty() {
+      body["safetySettings"] = serde_json::to_value(stage.safety)?;
+    }
+    let resp = post_generate_content(stage.api.http, &url, stage.api.api_key, &body).await?;
+    check_finish(Stage::Tools, &resp)?;
+    if let Some(u) = resp.get("usageMetadata").and_then(TokenUsage::from_gemini) {
+      out.usage.get_or_insert_with(TokenUsage::default).add(&u);
+    }
+    if let Some(m) = resp.get("modelVersion").and_then(|m| m.as_str()) {
+      out.model = Some(m.to_string());
+    }
+    let content = resp
+      .pointer("/candidates/0/content")
+      .cloned()
+      .ok_or_else(|| anyhow!("candidates[0].content not found in tool stage response"))?;
+    let parts = content.get("parts").and_then(|p| p.as_array()).cloned().unwrap_or_default();
+    let calls: Vec<(String, serde_json::Value)> = parts
+      .iter()
+      .filter_map(|p| p.get("functionCall"))
+      .map(|fc| {
+        let name = fc.get("name").and_then(|n| n.as_str()).unwrap_or_default().to_string();
+        (name, fc.get("args").cloned().unwrap_or_else(|| serde_json::json!({})))
+      })
+      .collect();
+    if calls.is_empty() {
+      out.text = parts_text(&parts);
+      return Ok(out);
+    }
+
+    round += 1;
+    contents.push(content);
+    let mut response_parts = Vec::new();
+    for (name, args) in calls {
+      let started = std::time::Instant::now();
+      let result = stage.run.tools.call(&name, &args);
+      stage.run.log.lock().unwrap_or_else(std::sync::PoisonError::into_inner).push(ToolCallRecord {
+        round,
+        name: name.clone(),
+        args,
+        result: result.as_ref().ok().cloned(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+        duration_ms: started.elapsed().as_millis() as u64,
+      });
+      let response = result.unwrap_or_else(|e| serde_json::json!({ "error": e.to_string() }));
+      response_parts.push(serde_json::json!({ "functionResponse": { "name": name, "response": response } }));
+    }
+    contents.push(serde_json::json!({ "role": "user", "parts": response_parts }));
   }
 }
 
+async fn post_generate_content(http: &SharedHttp, url: &str, api_key: &str, body: &serde_json::Value) -> Result<serde_json::Value> {
+  let resp = http.send(http.client.post(url).header("x-goog-api-key", api_key).json(body)).await?;
+  let status = resp.status();
+  if !status.is_success() {
+    let body = resp.text().await.unwrap_or_default();
+    return Err(anyhow!("bad response from server; code {}; description: {}", status.as_u16(), body));
+  }
+  Ok(resp.json().await?)
+}
+
+// REST レスポンスの content.parts から本文を取り出す（思考パートは除く）
+pub(crate) fn parts_text(parts: &[serde_json::Value]) -> String {
+  parts
+    .iter()
+    .filter(|p| !p.get("thought").and_then(|t| t.as_bool()).unwrap_or(false))
+    .filter_map(|p| p.get("text").and_then(|t| t.as_str()))
+    .collect::<Vec<_>>()
+    .join("")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
 
+  #[test]
+  fn every_generation_param_reaches_the_request_body() {
+    let params = GenerationParams {
+      temperature: Some(0.5),
+      top_p: Some(0.25),
+      top_k: Some(40),
+      max_output_tokens: Some(1024),
+      seed: Some(7),
+      thinking_budget: Some(0),
+    };
+    let request = json_output(stage_request(Some("system"), "user".into(), &[]), Some(serde_json::json!({ "type": "OBJECT" })));
+    let body = request_body(&request, &params).unwrap();
+    assert_eq!(
+      body["generationConfig"],
+      serde_json::json!({
+        "temperature": 0.5,
+        "topP": 0.25,
+        "topK": 40,
+        "maxOutputTokens": 1024,
+        "seed": 7,
+        "thinkingConfig": { "thinkingBudget": 0 },
+        "responseMimeType": "application/json",
+        "responseSchema": { "type": "OBJECT" },
+      })
+    );
+
+    // パラメータ未指定なら generationConfig も付けない
+    let body = request_body(&stage_request(None, "user".into(), &[]), &GenerationParams::default()).unwrap();
+    assert!(body.get("generationConfig").is_none());
+    let body = request_body(&stage_request(None, "user".into(), &[]), &GenerationParams { seed: Some(1), ..Default::default() }).unwrap();
+    assert_eq!(body["generationConfig"], serde_json::json!({ "seed": 1 }));
+  }
+
+  #[test]
+  fn safety_settings_convert_to_api_types() {
+    let settings = vec![
+      SafetySetting { category: "harassment".into(), threshold: "block_none".into() },
+      SafetySetting { category: "HARM_CATEGORY_DANGEROUS_CONTENT".into(), threshold: "OFF".into() },
+    ];
+    let converted = api_safety_settings(&settings).unwrap();
+    assert_eq!(
+      serde_json::to_value(&converted).unwrap(),
+      serde_json::json!([
+        { "category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE" },
+        { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "threshold": "OFF" },
+      ])
+    );
+    let unknown = [SafetySetting { category: "violence_in_cartoons".into(), threshold: "BLOCK_NONE".into() }];
+    assert!(api_safety_settings(&unknown).is_err());
+    let bad_threshold = [SafetySetting { category: "HARASSMENT".into(), threshold: "SOMETIMES".into() }];
+    assert!(api_safety_settings(&bad_threshold).is_err());
+  }
+
+  #[test]
+  fn sse_events_are_split_on_blank_lines() {
+    let mut buf = b"data: {\"a\":1}\n\ndata: {\"b\":2}\n\ndata: {\"c\"".to_vec();
+    assert_eq!(drain_sse_events(&mut buf), vec![r#"{"a":1}"#, r#"{"b":2}"#]);
+    // 未完のイベントは次のチャンクを待つ
+    assert_eq!(buf, b"data: {\"c\"".to_vec());
+    buf.extend_from_slice(b":3}\n\n");
+    assert_eq!(drain_sse_events(&mut buf), vec![r#"{"c":3}"#]);
+    assert!(buf.is_empty());
+  }
+}
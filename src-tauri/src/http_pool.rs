@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// 実行中に共有する HTTP クライアントの接続プール設定と、リクエスト・接続ごとの所要時間の計測
+// クライアントは実行ごとに1つだけ作り、全キーのバックエンド・Batch API・URL 取得で同じ接続プールを使う
+// 接続数の上限は設定できない（reqwest が制限するのはアイドル接続の数だけ）。同時に開く接続は並列数までに収まる
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct HttpPoolConfig {
+  // ホストごとに保持するアイドル接続の上限（未指定は並列数）。これを超えた接続は使い終わると閉じる
+  #[serde(default)]
+  pub max_idle_per_host: Option<usize>,
+  // アイドル接続を閉じるまでの秒数（未指定は 90）
+  #[serde(default)]
+  pub idle_timeout_secs: Option<u64>,
+  // HTTP/2 の keep-alive PING の間隔（秒、未指定は 30。0 で無効）
+  #[serde(default)]
+  pub http2_keep_alive_secs: Option<u64>,
+}
+
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 90;
+const DEFAULT_HTTP2_KEEP_ALIVE_SECS: u64 = 30;
+
+impl HttpPoolConfig {
+  // 並列数を既定のアイドル接続上限として補う
+  pub fn with_default_idle(mut self, concurrency: usize) -> Self {
+    if self.max_idle_per_host.is_none() {
+      self.max_idle_per_host = Some(concurrency.max(1));
+    }
+    self
+  }
+
+  // 新しい接続の確立（TCP・TLS）にかかった時間を stats に記録するクライアント
+  pub fn build_client(&self, stats: Arc<HttpStats>) -> Result<reqwest::Client> {
+    self
+      .client_builder()
+      .connector_layer(ConnectTiming { stats })
+      .build()
+      .map_err(|e| anyhow!("cannot build the HTTP client: {}", e))
+  }
+
+  fn client_builder(&self) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder()
+      .pool_idle_timeout(Duration::from_secs(self.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS)));
+    if let Some(n) = self.max_idle_per_host {
+      builder = builder.pool_max_idle_per_host(n);
+    }
+    let keep_alive = self.http2_keep_alive_secs.unwrap_or(DEFAULT_HTTP2_KEEP_ALIVE_SECS);
+    if keep_alive > 0 {
+      builder = builder
+        .http2_keep_alive_interval(Duration::from_secs(keep_alive))
+        .http2_keep_alive_timeout(Duration::from_secs(10))
+        .http2_keep_alive_while_idle(true);
+    }
+    builder
+  }
+}
+
+// リクエスト（送信からレスポンスヘッダー受信まで）と、新しく開いた接続（TCP・TLS の確立）の集計
+#[derive(Debug, Default)]
+pub struct HttpStats {
+  requests: AtomicU64,
+  ttfb_micros: AtomicU64,
+  connections: AtomicU64,
+  connect_micros: AtomicU64,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct HttpSummary {
+  pub requests: u64,
+  // 1リクエストあたりのレスポンスヘッダーまでの平均（ミリ秒）。ストリーミングでない生成ではモデルの処理時間がほとんどを占める
+  pub avg_ttfb_ms: f64,
+  // 新しく開いた接続の数（残りのリクエストはプールの接続を再利用した）
+  pub connections: u64,
+  // 接続1つあたりの確立（TCP・TLS）の平均（ミリ秒）。リクエストごとの接続のオーバーヘッドはこちら
+  pub avg_connect_ms: f64,
+}
+
+impl HttpStats {
+  pub fn record_request(&self, ttfb: Duration) {
+    self.requests.fetch_add(1, Ordering::Relaxed);
+    self.ttfb_micros.fetch_add(ttfb.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  pub fn record_connection(&self, elapsed: Duration) {
+    self.connections.fetch_add(1, Ordering::Relaxed);
+    self.connect_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> HttpSummary {
+    let average_ms = |total: &AtomicU64, count: u64| {
+      if count == 0 {
+        0.0
+      } else {
+        total.load(Ordering::Relaxed) as f64 / count as f64 / 1000.0
+      }
+    };
+    let requests = self.requests.load(Ordering::Relaxed);
+    let connections = self.connections.load(Ordering::Relaxed);
+    HttpSummary {
+      requests,
+      avg_ttfb_ms: average_ms(&self.ttfb_micros, requests),
+      connections,
+      avg_connect_ms: average_ms(&self.connect_micros, connections),
+    }
+  }
+}
+
+// コネクター（新しい接続を開くときだけ呼ばれる）を包み、確立にかかった時間を記録する
+#[derive(Clone)]
+struct ConnectTiming {
+  stats: Arc<HttpStats>,
+}
+
+impl<S> tower::Layer<S> for ConnectTiming {
+  type Service = TimedConnector<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    TimedConnector { inner, stats: self.stats.clone() }
+  }
+}
+
+#[derive(Clone)]
+struct TimedConnector<S> {
+  inner: S,
+  stats: Arc<HttpStats>,
+}
+
+impl<S, R> tower::Service<R> for TimedConnector<S>
+where
+  S: tower::Service<R>,
+  S::Future: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = Pin<Box<dyn Future<Output = std::result::Result<S::Response, S::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), S::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: R) -> Self::Future {
+    let started = Instant::now();
+    let connecting = self.inner.call(req);
+    let stats = self.stats.clone();
+    Box::pin(async move {
+      let conn = connecting.await;
+      if conn.is_ok() {
+        stats.record_connection(started.elapsed());
+      }
+      conn
+    })
+  }
+}
+
+// 実行内で共有するクライアントと計測先（clone しても同じ接続プール・集計を指す）
+#[derive(Debug, Clone, Default)]
+pub struct SharedHttp {
+  pub client: reqwest::Client,
+  pub stats: Arc<HttpStats>,
+}
+
+impl SharedHttp {
+  pub fn new(config: &HttpPoolConfig) -> Result<Self> {
+    let stats = Arc::new(HttpStats::default());
+    Ok(Self { client: config.build_client(stats.clone())?, stats })
+  }
+
+  // 送信し、レスポンスヘッダーが届くまでの時間を記録する（失敗したリクエストは数えない）
+  pub async fn send(&self, req: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let started = Instant::now();
+    let resp = req.send().await?;
+    self.stats.record_request(started.elapsed());
+    Ok(resp)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_server::{self, Reply};
+
+  #[tokio::test]
+  async fn counts_requests_and_new_connections() {
+    let server = test_server::serve(|_, _| Reply::with_type(200, "text/plain", "ok")).await;
+    let http = SharedHttp::new(&HttpPoolConfig::default().with_default_idle(2)).unwrap();
+    // テストサーバーは応答ごとに接続を閉じるため、毎回新しい接続になる
+    for _ in 0..2 {
+      let resp = http.send(http.client.get(format!("{}/ping", server.base_url))).await.unwrap();
+      assert_eq!(resp.text().await.unwrap(), "ok");
+    }
+    let summary = http.stats.snapshot();
+    assert_eq!((summary.requests, summary.connections), (2, 2));
+    assert!(summary.avg_connect_ms >= 0.0 && summary.avg_ttfb_ms > 0.0);
+
+    // 接続できなかったリクエストは数えない
+    let closed = format!("http://127.0.0.1:{}", std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port());
+    assert!(http.send(http.client.get(closed)).await.is_err());
+    assert_eq!(http.stats.snapshot().connections, 2);
+  }
+}
@@ -0,0 +1,195 @@
+use crate::provider::{classify_error, ErrorClass, LlmProvider};
+use anyhow::{anyhow, Result};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use serde::Deserialize;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// 複数の API キーを行ごとに振り分けるプール
+// キーごとにレートリミッタとバックエンドを持ち、quota / 認証エラーを返したキーは一時的に退避させる
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeyEntry {
+  // ログ・通知に出す別名（キー本体は記録しない）
+  pub alias: String,
+  pub key: String,
+  // このキーの RPM（未指定は ProcessConfig.rate_limit_rpm）
+  #[serde(default)]
+  pub rate_limit_rpm: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DispatchStrategy {
+  #[default]
+  RoundRobin,
+  // 処理中（レート待ちを含む）の行が最も少ないキー
+  LeastLoaded,
+}
+
+// quota エラー時の既定の退避時間
+pub const DEFAULT_RETIRE_SECS: u64 = 60;
+
+enum Retirement {
+  Active,
+  Until(Instant),
+  // 認証エラー: この実行の間は使わない
+  ForRun,
+}
+
+struct KeySlot {
+  alias: String,
+  provider: Arc<dyn LlmProvider>,
+  limiter: DefaultDirectRateLimiter,
+  in_flight: AtomicU32,
+  retired: Mutex<(Retirement, Option<String>)>,
+}
+
+pub struct KeyPool {
+  slots: Vec<KeySlot>,
+  strategy: DispatchStrategy,
+  next: AtomicUsize,
+  retire_for: Duration,
+}
+
+// 1行分のキーの割り当て（drop で処理中カウントを戻す）
+pub struct KeyLease {
+  pub alias: String,
+  pub provider: Arc<dyn LlmProvider>,
+  index: usize,
+  pool: Arc<KeyPool>,
+}
+
+impl Drop for KeyLease {
+  fn drop(&mut self) {
+    self.pool.slots[self.index].in_flight.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+enum Pick {
+  Slot(usize),
+  WaitUntil(Instant),
+  Exhausted,
+}
+
+impl KeyPool {
+  // build: キーからバックエンドを作る（プロバイダ設定は呼び出し側が持つ）
+  pub fn new(
+    keys: &[ApiKeyEntry],
+    default_rpm: u32,
+    strategy: DispatchStrategy,
+    retire_secs: u64,
+    build: impl Fn(&str) -> Result<Arc<dyn LlmProvider>>,
+  ) -> Result<Self> {
+    if keys.is_empty() {
+      return Err(anyhow!("at least one API key is required"));
+    }
+    let mut slots = Vec::new();
+    for entry in keys {
+      if slots.iter().any(|s: &KeySlot| s.alias == entry.alias) {
+        return Err(anyhow!("duplicate API key alias: {}", entry.alias));
+      }
+      let rpm = entry.rate_limit_rpm.unwrap_or(default_rpm).max(1);
+      slots.push(KeySlot {
+        alias: entry.alias.clone(),
+        provider: build(&entry.key)?,
+        limiter: RateLimiter::direct(Quota::per_minute(NonZeroU32::new(rpm).unwrap())),
+        in_flight: AtomicU32::new(0),
+        retired: Mutex::new((Retirement::Active, None)),
+      });
+    }
+    Ok(Self { slots, strategy, next: AtomicUsize::new(0), retire_for: Duration::from_secs(retire_secs) })
+  }
+
+  pub fn len(&self) -> usize {
+    self.slots.len()
+  }
+
+  // 先頭キーのバックエンド（モデル名・対応機能の判定やコンテキストキャッシュに使う）
+  pub fn primary(&self) -> Arc<dyn LlmProvider> {
+    self.slots[0].provider.clone()
+  }
+
+  pub fn primary_alias(&self) -> &str {
+    &self.slots[0].alias
+  }
+
+  // 使えるキーを選び、そのキーのレートリミッタの許可が出るまで待つ
+  // すべて退避中なら最も早く戻るキーを待ち、実行中ずっと使えない場合はエラー
+  pub async fn acquire(self: &Arc<Self>) -> Result<KeyLease> {
+    loop {
+      match self.pick() {
+        Pick::Slot(index) => {
+          let slot = &self.slots[index];
+          slot.in_flight.fetch_add(1, Ordering::Relaxed);
+          let lease = KeyLease { alias: slot.alias.clone(), provider: slot.provider.clone(), index, pool: self.clone() };
+          slot.limiter.until_ready().await;
+          return Ok(lease);
+        }
+        Pick::WaitUntil(at) => tokio::time::sleep_until(at.into()).await,
+        Pick::Exhausted => {
+          let reasons: Vec<String> = self
+            .slots
+            .iter()
+            .map(|s| format!("{}: {}", s.alias, s.retired.lock().unwrap().1.clone().unwrap_or_default()))
+            .collect();
+          return Err(anyhow!("all API keys are retired ({})", reasons.join("; ")));
+        }
+      }
+    }
+  }
+
+  fn pick(&self) -> Pick {
+    let now = Instant::now();
+    let mut earliest: Option<Instant> = None;
+    let mut available = Vec::new();
+    for (i, slot) in self.slots.iter().enumerate() {
+      let mut retired = slot.retired.lock().unwrap();
+      match retired.0 {
+        Retirement::Active => available.push(i),
+        Retirement::Until(t) if t <= now => {
+          *retired = (Retirement::Active, None);
+          available.push(i);
+        }
+        Retirement::Until(t) => earliest = Some(earliest.map_or(t, |e| e.min(t))),
+        Retirement::ForRun => {}
+      }
+    }
+    if available.is_empty() {
+      return earliest.map(Pick::WaitUntil).unwrap_or(Pick::Exhausted);
+    }
+    let start = self.next.fetch_add(1, Ordering::Relaxed);
+    let index = match self.strategy {
+      DispatchStrategy::RoundRobin => available[start % available.len()],
+      DispatchStrategy::LeastLoaded => {
+        // 同数なら巡回順で先のものを選ぶ
+        let n = available.len();
+        (0..n)
+          .map(|k| available[(start + k) % n])
+          .min_by_key(|&i| self.slots[i].in_flight.load(Ordering::Relaxed))
+          .unwrap_or(available[0])
+      }
+    };
+    Pick::Slot(index)
+  }
+
+  // quota / 認証エラーならそのキーを退避させ、通知用のメッセージを返す
+  pub fn report_failure(&self, lease: &KeyLease, err: &anyhow::Error) -> Option<String> {
+    let class = classify_error(err);
+    let slot = &self.slots[lease.index];
+    let mut retired = slot.retired.lock().unwrap();
+    match class {
+      ErrorClass::Quota => {
+        *retired = (Retirement::Until(Instant::now() + self.retire_for), Some("quota exceeded".into()));
+        Some(format!("API key '{}' hit its quota and was retired for {}s", slot.alias, self.retire_for.as_secs()))
+      }
+      ErrorClass::Auth => {
+        *retired = (Retirement::ForRun, Some("authentication failed".into()));
+        Some(format!("API key '{}' was rejected and retired for the rest of this run", slot.alias))
+      }
+      _ => None,
+    }
+  }
+}
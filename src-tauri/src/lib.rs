@@ -15,42 +15,156 @@ pub fn run() {
     .plugin(tauri_plugin_updater::Builder::new().build())
     .invoke_handler(tauri::generate_handler![
       crate::processor::process_rows,
+      crate::processor::embed_rows,
       crate::processor::abort_processing,
+      crate::preview::preview_run,
       gemini_generate_with_search,
       gemini_generate_prompt_text,
+      suggest_prompt_template,
       recreate_windows_shortcut
     ])
     .setup(|app| {
       app.manage(crate::processor::CancelHolder::default());
+      app.manage(crate::processor::PageFetcherHolder::default());
       Ok(())
     })
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 }
 
+mod attachments;
+mod batch;
+mod consensus;
+mod fetch;
 mod gemini;
+mod http_pool;
+mod key_pool;
+mod notes_cache;
+mod npy;
+mod ollama;
+mod openai;
+mod preview;
 mod processor;
+mod prompts;
+mod provider;
+mod sources;
+mod suggest;
+#[cfg(test)]
+mod test_server;
+mod tools;
+mod usage;
+mod verify;
 
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 async fn gemini_generate_with_search(
   api_key: String,
   prompt: String,
   enable_web_search: Option<bool>,
   response_schema: Option<serde_json::Value>,
+  provider: Option<crate::provider::ProviderKind>,
+  base_url: Option<String>,
+  model: Option<String>,
+  generation: Option<crate::provider::StageParams>,
+  system_prompts: Option<crate::prompts::SystemPromptConfig>,
+  safety_settings: Option<Vec<crate::provider::SafetySetting>>,
 ) -> Result<crate::gemini::GenerateResponse, String> {
+  let llm = crate::provider::build_provider(&crate::provider::ProviderSettings {
+    kind: provider.unwrap_or_default(),
+    api_key,
+    base_url,
+    model,
+    ..Default::default()
+  })
+  .map_err(|e| e.to_string())?;
+  // 検索を黙って外すと根拠のない応答を検索結果と取り違えるため、非対応のバックエンドではエラーにする
   let enable_web_search = enable_web_search.unwrap_or(true);
-  crate::gemini::generate_events_with_search_once(api_key, prompt, 60, enable_web_search, response_schema)
+  if enable_web_search && !llm.supports_web_search() {
+    return Err(format!(
+      "web search is not supported by provider '{}'; set enableWebSearch to false to generate without it",
+      llm.name()
+    ));
+  }
+  llm
+    .generate_structured(crate::provider::GenerateRequest {
+      enable_web_search,
+      response_schema,
+      params: generation.unwrap_or_default(),
+      safety_settings: safety_settings.unwrap_or_default(),
+      ..crate::provider::GenerateRequest::new(
+        prompt,
+        crate::provider::StageTimeouts::uniform(60),
+        std::sync::Arc::new(crate::prompts::SystemPrompts::resolve(&system_prompts.unwrap_or_default())),
+      )
+    })
     .await
     .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-async fn gemini_generate_prompt_text(api_key: String, prompt: String) -> Result<crate::gemini::GenerateResponse, String> {
-  crate::gemini::generate_prompt_text_once(api_key, prompt, 60)
+async fn gemini_generate_prompt_text(
+  api_key: String,
+  prompt: String,
+  provider: Option<crate::provider::ProviderKind>,
+  base_url: Option<String>,
+  model: Option<String>,
+  generation: Option<crate::provider::GenerationParams>,
+) -> Result<crate::gemini::GenerateResponse, String> {
+  let llm = crate::provider::build_provider(&crate::provider::ProviderSettings {
+    kind: provider.unwrap_or_default(),
+    api_key,
+    base_url,
+    model,
+    ..Default::default()
+  })
+  .map_err(|e| e.to_string())?;
+  llm
+    .generate_text(crate::provider::TextRequest {
+      prompt,
+      timeout_secs: 60,
+      params: generation.unwrap_or_default(),
+    })
     .await
     .map_err(|e| e.to_string())
 }
 
+// CSV の列名・サンプル行・目的から、プロンプトテンプレートと出力カラム定義を提案する（{{列名}} はヘッダーとの照合済み）
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn suggest_prompt_template(
+  api_key: String,
+  headers: Vec<String>,
+  sample_rows: Vec<serde_json::Map<String, serde_json::Value>>,
+  goal: String,
+  language: Option<String>,
+  provider: Option<crate::provider::ProviderKind>,
+  base_url: Option<String>,
+  model: Option<String>,
+  generation: Option<crate::provider::GenerationParams>,
+) -> Result<crate::suggest::PromptSuggestion, String> {
+  let llm = crate::provider::build_provider(&crate::provider::ProviderSettings {
+    kind: provider.unwrap_or_default(),
+    api_key,
+    base_url,
+    model,
+    ..Default::default()
+  })
+  .map_err(|e| e.to_string())?;
+  crate::suggest::suggest(
+    llm.as_ref(),
+    crate::suggest::SuggestRequest {
+      headers,
+      sample_rows,
+      goal,
+      language,
+      params: generation.unwrap_or_default(),
+      timeout_secs: 60,
+    },
+  )
+  .await
+  .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn recreate_windows_shortcut(name: Option<String>, description: Option<String>) -> Result<(), String> {
   #[cfg(target_os = "windows")]
@@ -0,0 +1,238 @@
+use crate::usage::TokenUsage;
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+// stage1（検索メモ収集）の結果をエンティティのキーごとに共有するキャッシュ
+// 同じキーの行が並行して来ても検索は最初の1回だけで、残りの行はそのメモで stage2 を行う
+// TTL 指定時はファイルへ保存し、次回以降の実行でも期限内なら再利用する
+// ファイルは全実行で共有するため、保存のたびにファイルの内容と合わせてから一時ファイル経由で置き換える
+
+// 実行中に途中保存する間隔（中断しても、それまでに集めたメモは残る）
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+// 同じプロセス内の実行どうしで、読み込み → 合成 → 書き込みが入れ違わないようにする
+static SAVE_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedNotes {
+  pub notes: String,
+  pub grounding_metadata: Option<serde_json::Value>,
+  // メモを作ったときの stage1 の使用量（再利用した行には計上しない）
+  pub usage: Option<TokenUsage>,
+  pub created_at_ms: u64,
+}
+
+// 1キー分の共有メモ（GenerateRequest に載せて stage1 の代わりに使う）
+#[derive(Debug, Clone)]
+pub struct SharedNotes {
+  pub key: String,
+  pub cell: Arc<OnceCell<CachedNotes>>,
+}
+
+pub struct NotesCache {
+  entries: Mutex<HashMap<String, Arc<OnceCell<CachedNotes>>>>,
+  // 保存先と保持期間（None なら実行中のみ）
+  persist: Option<(PathBuf, u64)>,
+  last_saved: Mutex<Instant>,
+}
+
+impl NotesCache {
+  pub fn in_memory() -> Self {
+    Self { entries: Mutex::new(HashMap::new()), persist: None, last_saved: Mutex::new(Instant::now()) }
+  }
+
+  // 保存済みのキャッシュを読み込む（期限切れの項目は捨てる。ファイルが無ければ空で始める）
+  pub async fn load(path: PathBuf, ttl_secs: u64) -> Result<Self> {
+    let entries = read_saved(&path, ttl_secs)
+      .await?
+      .into_iter()
+      .map(|(key, notes)| (key, Arc::new(OnceCell::new_with(Some(notes)))))
+      .collect();
+    Ok(Self { entries: Mutex::new(entries), persist: Some((path, ttl_secs)), last_saved: Mutex::new(Instant::now()) })
+  }
+
+  // scope（モデル・テンプレートのキー式など）と行ごとのキーの組で共有する
+  pub fn shared(&self, scope: &str, key: &str) -> SharedNotes {
+    let key = format!("{}\u{1f}{}", scope, key);
+    let cell = self.entries.lock().unwrap().entry(key.clone()).or_default().clone();
+    SharedNotes { key, cell }
+  }
+
+  // 前回の保存から一定時間たっていれば保存する（行ごとに呼ぶ）
+  pub async fn checkpoint(&self) -> Result<()> {
+    if self.persist.is_none() || self.last_saved.lock().unwrap().elapsed() < CHECKPOINT_INTERVAL {
+      return Ok(());
+    }
+    self.save().await
+  }
+
+  // 期限内の項目を、ファイルにある（他の実行が保存した）期限内の項目と合わせて書き出す
+  // 同じキーは作成の新しい方を残す。保存先が無ければ何もしない
+  pub async fn save(&self) -> Result<()> {
+    let Some((path, ttl_secs)) = self.persist.as_ref() else {
+      return Ok(());
+    };
+    *self.last_saved.lock().unwrap() = Instant::now();
+    let _guard = SAVE_LOCK.lock().await;
+    // 読めないファイルは、この実行の項目で置き換える
+    let mut saved = read_saved(path, *ttl_secs).await.unwrap_or_default();
+    let now = now_ms();
+    let mine: Vec<(String, CachedNotes)> = self
+      .entries
+      .lock()
+      .unwrap()
+      .iter()
+      .filter_map(|(k, cell)| cell.get().map(|n| (k.clone(), n.clone())))
+      .filter(|(_, n)| now.saturating_sub(n.created_at_ms) < ttl_secs.saturating_mul(1000))
+      .collect();
+    for (key, notes) in mine {
+      match saved.get(&key) {
+        Some(existing) if existing.created_at_ms > notes.created_at_ms => {}
+        _ => {
+          saved.insert(key, notes);
+        }
+      }
+    }
+    write_json(path, &saved).await
+  }
+}
+
+// 保存済みの期限内の項目（ファイルが無ければ空）
+async fn read_saved(path: &Path, ttl_secs: u64) -> Result<HashMap<String, CachedNotes>> {
+  let text = match tokio::fs::read_to_string(path).await {
+    Ok(text) => text,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+    Err(e) => return Err(anyhow!("cannot read notes cache {}: {}", path.display(), e)),
+  };
+  let saved: HashMap<String, CachedNotes> =
+    serde_json::from_str(&text).map_err(|e| anyhow!("invalid notes cache {}: {}", path.display(), e))?;
+  let now = now_ms();
+  Ok(saved.into_iter().filter(|(_, n)| now.saturating_sub(n.created_at_ms) < ttl_secs.saturating_mul(1000)).collect())
+}
+
+// 一時ファイルに書いてから置き換える（書き込み途中で止まっても元のファイルは壊れない）
+async fn write_json(path: &Path, value: &HashMap<String, CachedNotes>) -> Result<()> {
+  if let Some(dir) = path.parent() {
+    tokio::fs::create_dir_all(dir).await?;
+  }
+  let text = serde_json::to_string(value)?;
+  let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+  tokio::fs::write(&tmp, text)
+    .await
+    .map_err(|e| anyhow!("cannot write notes cache {}: {}", tmp.display(), e))?;
+  tokio::fs::rename(&tmp, path)
+    .await
+    .map_err(|e| anyhow!("cannot write notes cache {}: {}", path.display(), e))
+}
+
+pub fn now_ms() -> u64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("notes-cache-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = std::fs::remove_file(&path);
+    path
+  }
+
+  fn notes(text: &str, created_at_ms: u64) -> CachedNotes {
+    CachedNotes { notes: text.into(), grounding_metadata: None, usage: None, created_at_ms }
+  }
+
+  fn cached(cache: &NotesCache, key: &str) -> Option<String> {
+    cache.shared("scope", key).cell.get().map(|n| n.notes.clone())
+  }
+
+  #[tokio::test]
+  async fn saves_and_loads_notes_within_the_ttl() {
+    let path = temp_path("round_trip.json");
+    let cache = NotesCache::load(path.clone(), 3600).await.unwrap();
+    cache.shared("scope", "acme").cell.set(notes("Acme makes widgets", now_ms())).unwrap();
+    // まだメモの無いキーは保存しない
+    let _ = cache.shared("scope", "pending");
+    cache.save().await.unwrap();
+
+    let loaded = NotesCache::load(path.clone(), 3600).await.unwrap();
+    assert_eq!(cached(&loaded, "acme").as_deref(), Some("Acme makes widgets"));
+    assert_eq!(cached(&loaded, "pending"), None);
+    // 一時ファイルは残らない
+    let leftovers: Vec<_> = std::fs::read_dir(path.parent().unwrap())
+      .unwrap()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_name().to_string_lossy().starts_with("round_trip.json."))
+      .collect();
+    assert!(leftovers.is_empty());
+  }
+
+  #[tokio::test]
+  async fn load_drops_expired_notes() {
+    let path = temp_path("expiry.json");
+    let now = now_ms();
+    let saved: HashMap<String, CachedNotes> = [
+      ("scope\u{1f}fresh".to_string(), notes("fresh", now - 10_000)),
+      ("scope\u{1f}stale".to_string(), notes("stale", now - 7_200_000)),
+    ]
+    .into_iter()
+    .collect();
+    std::fs::write(&path, serde_json::to_string(&saved).unwrap()).unwrap();
+
+    let loaded = NotesCache::load(path.clone(), 3600).await.unwrap();
+    assert_eq!(cached(&loaded, "fresh").as_deref(), Some("fresh"));
+    assert_eq!(cached(&loaded, "stale"), None);
+
+    // 保存し直すと期限切れの項目はファイルからも消える
+    loaded.save().await.unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert!(text.contains("fresh") && !text.contains("stale"), "{}", text);
+  }
+
+  #[tokio::test]
+  async fn corrupt_file_fails_to_load_and_is_replaced_on_save() {
+    let path = temp_path("corrupt.json");
+    std::fs::write(&path, "{not json").unwrap();
+    let err = NotesCache::load(path.clone(), 3600).await.err().unwrap().to_string();
+    assert!(err.contains("invalid notes cache"), "{}", err);
+
+    let cache = NotesCache::load(temp_path("corrupt-other.json"), 3600).await.unwrap();
+    let cache = NotesCache { persist: Some((path.clone(), 3600)), ..cache };
+    cache.shared("scope", "acme").cell.set(notes("Acme", now_ms())).unwrap();
+    cache.save().await.unwrap();
+    assert_eq!(cached(&NotesCache::load(path, 3600).await.unwrap(), "acme").as_deref(), Some("Acme"));
+  }
+
+  #[tokio::test]
+  async fn concurrent_runs_keep_each_others_notes() {
+    let path = temp_path("merge.json");
+    let first = NotesCache::load(path.clone(), 3600).await.unwrap();
+    let second = NotesCache::load(path.clone(), 3600).await.unwrap();
+    let now = now_ms();
+    first.shared("scope", "acme").cell.set(notes("Acme (old)", now - 1_000)).unwrap();
+    first.shared("scope", "globex").cell.set(notes("Globex", now)).unwrap();
+    second.shared("scope", "acme").cell.set(notes("Acme (new)", now)).unwrap();
+    second.shared("scope", "initech").cell.set(notes("Initech", now)).unwrap();
+    second.save().await.unwrap();
+    first.save().await.unwrap();
+
+    let loaded = NotesCache::load(path, 3600).await.unwrap();
+    assert_eq!(cached(&loaded, "globex").as_deref(), Some("Globex"));
+    assert_eq!(cached(&loaded, "initech").as_deref(), Some("Initech"));
+    // 同じキーは新しく作られた方を残す
+    assert_eq!(cached(&loaded, "acme").as_deref(), Some("Acme (new)"));
+  }
+}
@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+// 埋め込みベクトルを NumPy の .npy（float32, 行数 × 次元数）として書き出す
+// 失敗した行も行番号がずれないよう NaN で埋める
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+pub async fn write_f32_matrix(path: &Path, rows: &[Option<Vec<f32>>], dims: usize) -> Result<()> {
+  let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}", rows.len(), dims);
+  // マジック(6) + バージョン(2) + ヘッダー長(2) + ヘッダー（改行込み）を 64 バイト境界に揃える
+  let unpadded = MAGIC.len() + 2 + 2 + header.len() + 1;
+  header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+  header.push('\n');
+  let header_len = u16::try_from(header.len()).map_err(|_| anyhow!("npy header is too long"))?;
+
+  let mut buf = Vec::with_capacity(MAGIC.len() + 4 + header.len() + rows.len() * dims * 4);
+  buf.extend_from_slice(MAGIC);
+  buf.extend_from_slice(&[1, 0]);
+  buf.extend_from_slice(&header_len.to_le_bytes());
+  buf.extend_from_slice(header.as_bytes());
+  for row in rows {
+    match row {
+      Some(v) if v.len() == dims => v.iter().for_each(|x| buf.extend_from_slice(&x.to_le_bytes())),
+      _ => (0..dims).for_each(|_| buf.extend_from_slice(&f32::NAN.to_le_bytes())),
+    }
+  }
+  tokio::fs::write(path, buf)
+    .await
+    .map_err(|e| anyhow!("cannot write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn writes_a_padded_header_and_nan_rows() {
+    let path = std::env::temp_dir().join(format!("npy-test-{}.npy", std::process::id()));
+    let rows = vec![Some(vec![1.0, -2.5]), None, Some(vec![3.0]), Some(vec![0.5, 4.0])];
+    write_f32_matrix(&path, &rows, 2).await.unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(&bytes[..6], MAGIC);
+    assert_eq!(&bytes[6..8], &[1, 0]);
+    let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+    let data_start = 10 + header_len;
+    assert_eq!(data_start % 64, 0);
+    let header = std::str::from_utf8(&bytes[10..data_start]).unwrap();
+    assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (4, 2), }"), "{}", header);
+    assert!(header.ends_with('\n'));
+
+    let values: Vec<f32> = bytes[data_start..].chunks(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+    assert_eq!(values.len(), 8);
+    assert_eq!(&values[..2], &[1.0, -2.5]);
+    // 失敗した行と次元数の合わない行は NaN
+    assert!(values[2..6].iter().all(|v| v.is_nan()));
+    assert_eq!(&values[6..], &[0.5, 4.0]);
+  }
+}
@@ -0,0 +1,255 @@
+use crate::gemini::GenerateResponse;
+use crate::http_pool::SharedHttp;
+use crate::usage::TokenUsage;
+use crate::provider::{
+  json_vector, to_json_schema, with_timeout, BoxFuture, ChatOutput, EmbedRequest, EmbedResponse, FinishReason, GenerateError, GenerateRequest, GenerationParams, LlmProvider, Stage,
+  TextRequest,
+};
+use anyhow::{anyhow, Result};
+use serde_json::json;
+
+pub const DEFAULT_OLLAMA_BASE_URL: &str = "http://localhost:11434";
+
+// ローカル Ollama `/api/chat` バックエンド（オフライン処理用）
+// データを外部へ送らないため、Web検索は常に無効
+pub struct OllamaProvider {
+  http: SharedHttp,
+  base_url: String,
+  model: String,
+}
+
+impl OllamaProvider {
+  pub fn new(base_url: String, model: String, http: SharedHttp) -> Self {
+    Self {
+      http,
+      base_url: base_url.trim_end_matches('/').to_string(),
+      model,
+    }
+  }
+
+  // 本文テキストと使用量・応答モデル名を返す
+  async fn chat(&self, body: serde_json::Value, stage: Stage, timeout_secs: u64) -> Result<ChatOutput> {
+    let value = with_timeout(stage, timeout_secs, self.post(body)).await?;
+    // 出力上限で打ち切られた応答は不完全な JSON になるため、理由付きのエラーにする
+    if value.get("done_reason").and_then(|r| r.as_str()) == Some("length") {
+      return Err(GenerateError::Blocked { stage, reason: FinishReason::MaxTokens, detail: "done reason length".into() }.into());
+    }
+    let text = value
+      .pointer("/message/content")
+      .and_then(|c| c.as_str())
+      .map(|s| s.to_string())
+      .ok_or_else(|| anyhow!("message.content not found in Ollama response"))?;
+    Ok(ChatOutput {
+      text,
+      usage: TokenUsage::from_ollama(&value),
+      model: value.get("model").and_then(|m| m.as_str()).map(|s| s.to_string()).or_else(|| Some(self.model.clone())),
+    })
+  }
+
+
+  async fn post(&self, body: serde_json::Value) -> Result<serde_json::Value> {
+    self.post_to("api/chat", body).await
+  }
+
+  async fn post_to(&self, path: &str, body: serde_json::Value) -> Result<serde_json::Value> {
+    let url = format!("{}/{}", self.base_url, path);
+    let resp = self.http.send(self.http.client.post(&url).json(&body)).await?;
+    let status = resp.status();
+    if !status.is_success() {
+      let body = resp.text().await.unwrap_or_default();
+      return Err(anyhow!("bad response from server; code {}; description: {}", status.as_u16(), body));
+    }
+    Ok(resp.json().await?)
+  }
+}
+
+impl LlmProvider for OllamaProvider {
+  fn name(&self) -> &'static str {
+    "ollama"
+  }
+
+  fn model(&self) -> &str {
+    &self.model
+  }
+
+  fn generate_structured(&self, req: GenerateRequest) -> BoxFuture<'_, Result<GenerateResponse>> {
+    Box::pin(async move {
+      // format にはスキーマ（なければ "json"）を渡す
+      let format = match &req.response_schema {
+        Some(schema) => to_json_schema(schema),
+        None => json!("json"),
+      };
+      // Ollama は画像のみ受け付ける（message.images に base64 を並べる）
+      if let Some(a) = req.attachments.iter().find(|a| !a.is_image()) {
+        return Err(anyhow!("ollama provider does not accept non-image attachments ({}: {})", a.column, a.mime_type));
+      }
+      let mut user = json!({ "role": "user", "content": req.prompt });
+      if !req.attachments.is_empty() {
+        user["images"] = json!(req.attachments.iter().map(|a| a.data_base64.as_str()).collect::<Vec<_>>());
+      }
+      let body = json!({
+        "model": req.model.as_deref().unwrap_or(&self.model),
+        "stream": false,
+        "messages": [
+          { "role": "system", "content": req.prompts.single_system },
+          user,
+        ],
+        "format": format,
+        "options": options(&req.params.structure),
+      });
+      let out = self.chat(body, Stage::Structure, req.timeouts.structure_secs).await?;
+      Ok(out.into_response())
+    })
+  }
+
+  // `/api/embed`（task_type は非対応のため無視）
+  fn embed(&self, req: EmbedRequest) -> BoxFuture<'_, Result<EmbedResponse>> {
+    Box::pin(async move {
+      let mut body = json!({ "model": self.model, "input": req.text });
+      if let Some(dims) = req.dimensions {
+        body["dimensions"] = json!(dims);
+      }
+      let value = with_timeout(Stage::Embed, req.timeout_secs, self.post_to("api/embed", body)).await?;
+      let vector = value
+        .pointer("/embeddings/0")
+        .and_then(json_vector)
+        .ok_or_else(|| anyhow!("embeddings[0] not found in Ollama response"))?;
+      Ok(EmbedResponse {
+        vector,
+        usage: TokenUsage::from_ollama(&value),
+        model: value.get("model").and_then(|m| m.as_str()).map(|s| s.to_string()).or_else(|| Some(self.model.clone())),
+      })
+    })
+  }
+
+  fn generate_text(&self, req: TextRequest) -> BoxFuture<'_, Result<GenerateResponse>> {
+    Box::pin(async move {
+      let body = json!({
+        "model": self.model,
+        "stream": false,
+        "messages": [{ "role": "user", "content": req.prompt }],
+        "options": options(&req.params),
+      });
+      let out = self.chat(body, Stage::Text, req.timeout_secs).await?;
+      Ok(out.into_response())
+    })
+  }
+}
+
+// 生成パラメータを Ollama の options へ変換（thinking_budget は非対応）
+fn options(params: &GenerationParams) -> serde_json::Value {
+  let mut o = serde_json::Map::new();
+  if let Some(v) = params.temperature {
+    o.insert("temperature".into(), json!(v));
+  }
+  if let Some(v) = params.top_p {
+    o.insert("top_p".into(), json!(v));
+  }
+  if let Some(v) = params.top_k {
+    o.insert("top_k".into(), json!(v));
+  }
+  if let Some(v) = params.max_output_tokens {
+    o.insert("num_predict".into(), json!(v));
+  }
+  if let Some(v) = params.seed {
+    o.insert("seed".into(), json!(v));
+  }
+  serde_json::Value::Object(o)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::attachments::Attachment;
+  use crate::prompts::SystemPrompts;
+  use crate::provider::{StageParams, StageTimeouts};
+  use crate::test_server::{serve, Reply};
+  use std::sync::Arc;
+
+  fn request(prompt: &str) -> GenerateRequest {
+    GenerateRequest::new(prompt.into(), StageTimeouts::uniform(10), Arc::new(SystemPrompts::defaults("en")))
+  }
+
+  fn attachment(mime_type: &str, data_base64: &str) -> Attachment {
+    Attachment {
+      column: "photo".into(),
+      path: "/data/photo".into(),
+      mime_type: mime_type.into(),
+      size_bytes: 3,
+      sha256: String::new(),
+      data_base64: data_base64.into(),
+    }
+  }
+
+  // プロンプトが "too long" なら出力上限で打ち切られた応答を返す
+  async fn server() -> crate::test_server::TestServer {
+    serve(|req, _| {
+      let done_reason = if req.json().pointer("/messages/1/content") == Some(&json!("too long")) { "length" } else { "stop" };
+      Reply::json(json!({
+        "model": "llama3:8b",
+        "message": { "role": "assistant", "content": "{\"name\":\"Widget\"}" },
+        "done": true,
+        "done_reason": done_reason,
+        "prompt_eval_count": 20,
+        "eval_count": 6,
+      }))
+    })
+    .await
+  }
+
+  #[tokio::test]
+  async fn maps_format_options_and_images() {
+    let server = server().await;
+    let llm = OllamaProvider::new(format!("{}/", server.base_url), "llama3".into(), SharedHttp::default());
+    let schema = json!({ "type": "OBJECT", "properties": { "name": { "type": "STRING" } }, "required": ["name"] });
+    let params = GenerationParams { temperature: Some(0.5), top_p: Some(0.25), top_k: Some(40), max_output_tokens: Some(256), seed: Some(7), thinking_budget: Some(100) };
+    let resp = llm
+      .generate_structured(GenerateRequest {
+        response_schema: Some(schema.clone()),
+        params: StageParams { search: GenerationParams::default(), structure: params },
+        attachments: vec![attachment("image/png", "iVBO")],
+        ..request("Describe the product")
+      })
+      .await
+      .unwrap();
+    assert_eq!(resp.text, "{\"name\":\"Widget\"}");
+    assert_eq!(resp.model.as_deref(), Some("llama3:8b"));
+    assert_eq!(resp.usage.and_then(|u| u.structure).map(|u| (u.prompt_tokens, u.candidates_tokens, u.total_tokens)), Some((20, 6, 26)));
+
+    let sent = &server.requests()[0];
+    assert_eq!((sent.method.as_str(), sent.path.as_str()), ("POST", "/api/chat"));
+    let body = sent.json();
+    assert_eq!(body["model"], "llama3");
+    assert_eq!(body["stream"], false);
+    assert_eq!(body["messages"][0], json!({ "role": "system", "content": SystemPrompts::defaults("en").single_system }));
+    assert_eq!(body["messages"][1], json!({ "role": "user", "content": "Describe the product", "images": ["iVBO"] }));
+    assert_eq!(body["format"], to_json_schema(&schema));
+    // thinking_budget は送らず、max_output_tokens は num_predict になる
+    assert_eq!(body["options"], json!({ "temperature": 0.5, "top_p": 0.25, "top_k": 40, "num_predict": 256, "seed": 7 }));
+
+    // スキーマが無ければ "json"、パラメータが無ければ空の options
+    llm.generate_structured(request("plain")).await.unwrap();
+    let body = server.requests()[1].json();
+    assert_eq!((&body["format"], &body["options"]), (&json!("json"), &json!({})));
+    assert!(body["messages"][1].get("images").is_none());
+  }
+
+  #[tokio::test]
+  async fn done_reason_length_is_a_blocked_error() {
+    let server = server().await;
+    let llm = OllamaProvider::new(server.base_url.clone(), "llama3".into(), SharedHttp::default());
+    let err = llm.generate_structured(request("too long")).await.unwrap_err();
+    match err.downcast_ref::<GenerateError>() {
+      Some(GenerateError::Blocked { stage, reason, .. }) => assert_eq!((*stage, *reason), (Stage::Structure, FinishReason::MaxTokens)),
+      other => panic!("unexpected error {:?}", other),
+    }
+
+    // 画像以外の添付は送信前に断る
+    let err = llm
+      .generate_structured(GenerateRequest { attachments: vec![attachment("application/pdf", "JVBE")], ..request("pdf") })
+      .await
+      .unwrap_err();
+    assert!(err.to_string().contains("non-image attachments"), "{}", err);
+    assert_eq!(server.requests().len(), 1);
+  }
+}
@@ -0,0 +1,294 @@
+use crate::attachments::Attachment;
+use crate::http_pool::SharedHttp;
+use crate::gemini::GenerateResponse;
+use crate::usage::TokenUsage;
+use crate::provider::{
+  json_vector, to_json_schema, with_timeout, BoxFuture, ChatOutput, EmbedRequest, EmbedResponse, FinishReason, GenerateError, GenerateRequest, GenerationParams, LlmProvider, Stage,
+  TextRequest,
+};
+use anyhow::{anyhow, Result};
+use serde_json::json;
+
+// OpenAI 互換 `/v1/chat/completions` バックエンド（vLLM, llama.cpp server など）
+// 検索グラウンディングは非対応のため、常に単発の構造化出力になる
+pub struct OpenAiCompatProvider {
+  http: SharedHttp,
+  base_url: String,
+  model: String,
+  api_key: Option<String>,
+}
+
+impl OpenAiCompatProvider {
+  pub fn new(base_url: String, model: String, api_key: Option<String>, http: SharedHttp) -> Self {
+    Self {
+      http,
+      base_url: base_url.trim_end_matches('/').to_string(),
+      model,
+      api_key: api_key.filter(|k| !k.trim().is_empty()),
+    }
+  }
+
+  // 本文テキストと使用量・応答モデル名を返す
+  async fn chat(&self, body: serde_json::Value, stage: Stage, timeout_secs: u64) -> Result<ChatOutput> {
+    let value = with_timeout(stage, timeout_secs, self.post(body)).await?;
+    // 出力上限・コンテンツフィルタで止まった応答は理由付きのエラーにする
+    let reason = match value.pointer("/choices/0/finish_reason").and_then(|r| r.as_str()) {
+      Some("length") => Some(FinishReason::MaxTokens),
+      Some("content_filter") => Some(FinishReason::Safety),
+      _ => None,
+    };
+    if let Some(reason) = reason {
+      let detail = format!("finish reason {}", value.pointer("/choices/0/finish_reason").and_then(|r| r.as_str()).unwrap_or_default());
+      return Err(GenerateError::Blocked { stage, reason, detail }.into());
+    }
+    let text = value
+      .pointer("/choices/0/message/content")
+      .and_then(|c| c.as_str())
+      .map(|s| s.to_string())
+      .ok_or_else(|| anyhow!("choices[0].message.content not found in response"))?;
+    Ok(ChatOutput {
+      text,
+      usage: value.get("usage").and_then(TokenUsage::from_openai),
+      model: value.get("model").and_then(|m| m.as_str()).map(|s| s.to_string()).or_else(|| Some(self.model.clone())),
+    })
+  }
+
+
+  async fn post(&self, body: serde_json::Value) -> Result<serde_json::Value> {
+    self.post_to("chat/completions", body).await
+  }
+
+  async fn post_to(&self, path: &str, body: serde_json::Value) -> Result<serde_json::Value> {
+    let url = format!("{}/{}", self.base_url, path);
+    let mut req = self.http.client.post(&url).json(&body);
+    if let Some(key) = &self.api_key {
+      req = req.bearer_auth(key);
+    }
+    let resp = self.http.send(req).await?;
+    let status = resp.status();
+    if !status.is_success() {
+      let body = resp.text().await.unwrap_or_default();
+      return Err(anyhow!("bad response from server; code {}; description: {}", status.as_u16(), body));
+    }
+    Ok(resp.json().await?)
+  }
+}
+
+impl LlmProvider for OpenAiCompatProvider {
+  fn name(&self) -> &'static str {
+    "openai_compatible"
+  }
+
+  fn model(&self) -> &str {
+    &self.model
+  }
+
+  fn generate_structured(&self, req: GenerateRequest) -> BoxFuture<'_, Result<GenerateResponse>> {
+    Box::pin(async move {
+      let response_format = match &req.response_schema {
+        Some(schema) => json!({
+          "type": "json_schema",
+          "json_schema": { "name": "row", "strict": true, "schema": to_json_schema(schema) },
+        }),
+        None => json!({ "type": "json_object" }),
+      };
+      let mut body = json!({
+        "model": req.model.as_deref().unwrap_or(&self.model),
+        "messages": [
+          { "role": "system", "content": req.prompts.single_system },
+          { "role": "user", "content": user_content(&req.prompt, &req.attachments) },
+        ],
+        "response_format": response_format,
+      });
+      apply_params(&mut body, &req.params.structure);
+      let out = self.chat(body, Stage::Structure, req.timeouts.structure_secs).await?;
+      Ok(out.into_response())
+    })
+  }
+
+  // `/v1/embeddings`（task_type は非対応のため無視）
+  fn embed(&self, req: EmbedRequest) -> BoxFuture<'_, Result<EmbedResponse>> {
+    Box::pin(async move {
+      let mut body = json!({ "model": self.model, "input": req.text });
+      if let Some(dims) = req.dimensions {
+        body["dimensions"] = json!(dims);
+      }
+      let value = with_timeout(Stage::Embed, req.timeout_secs, self.post_to("embeddings", body)).await?;
+      let vector = value
+        .pointer("/data/0/embedding")
+        .and_then(json_vector)
+        .ok_or_else(|| anyhow!("data[0].embedding not found in response"))?;
+      Ok(EmbedResponse {
+        vector,
+        usage: value.get("usage").and_then(TokenUsage::from_openai),
+        model: value.get("model").and_then(|m| m.as_str()).map(|s| s.to_string()).or_else(|| Some(self.model.clone())),
+      })
+    })
+  }
+
+  fn generate_text(&self, req: TextRequest) -> BoxFuture<'_, Result<GenerateResponse>> {
+    Box::pin(async move {
+      let mut body = json!({
+        "model": self.model,
+        "messages": [{ "role": "user", "content": req.prompt }],
+      });
+      apply_params(&mut body, &req.params);
+      let out = self.chat(body, Stage::Text, req.timeout_secs).await?;
+      Ok(out.into_response())
+    })
+  }
+}
+
+// 添付がある場合は content を配列にし、画像は image_url、PDF は file パートとして data URL で送る
+fn user_content(prompt: &str, attachments: &[Attachment]) -> serde_json::Value {
+  if attachments.is_empty() {
+    return json!(prompt);
+  }
+  let mut parts = vec![json!({ "type": "text", "text": prompt })];
+  for a in attachments {
+    if a.is_image() {
+      parts.push(json!({ "type": "image_url", "image_url": { "url": a.data_url() } }));
+    } else {
+      let filename = std::path::Path::new(&a.path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+      parts.push(json!({ "type": "file", "file": { "filename": filename, "file_data": a.data_url() } }));
+    }
+  }
+  serde_json::Value::Array(parts)
+}
+
+// 生成パラメータをリクエストボディへ反映（top_k は vLLM / llama.cpp の拡張パラメータ。thinking_budget は非対応）
+fn apply_params(body: &mut serde_json::Value, params: &GenerationParams) {
+  let Some(obj) = body.as_object_mut() else {
+    return;
+  };
+  if let Some(v) = params.temperature {
+    obj.insert("temperature".into(), json!(v));
+  }
+  if let Some(v) = params.top_p {
+    obj.insert("top_p".into(), json!(v));
+  }
+  if let Some(v) = params.top_k {
+    obj.insert("top_k".into(), json!(v));
+  }
+  if let Some(v) = params.max_output_tokens {
+    obj.insert("max_tokens".into(), json!(v));
+  }
+  if let Some(v) = params.seed {
+    obj.insert("seed".into(), json!(v));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::prompts::SystemPrompts;
+  use crate::provider::{StageParams, StageTimeouts};
+  use crate::test_server::{serve, Reply};
+  use std::sync::Arc;
+
+  fn attachment(path: &str, mime_type: &str, data_base64: &str) -> Attachment {
+    Attachment {
+      column: "file".into(),
+      path: path.into(),
+      mime_type: mime_type.into(),
+      size_bytes: 3,
+      sha256: String::new(),
+      data_base64: data_base64.into(),
+    }
+  }
+
+  fn request(prompt: &str) -> GenerateRequest {
+    GenerateRequest::new(prompt.into(), StageTimeouts::uniform(10), Arc::new(SystemPrompts::defaults("en")))
+  }
+
+  // プロンプトに応じて、正常終了・出力上限・コンテンツフィルタの応答を返す
+  async fn server() -> crate::test_server::TestServer {
+    serve(|req, _| {
+      let body = req.json();
+      let prompt = body.pointer("/messages/1/content/0/text").or_else(|| body.pointer("/messages/1/content")).and_then(|p| p.as_str()).unwrap_or_default().to_string();
+      let finish_reason = match prompt.as_str() {
+        "too long" => "length",
+        "filtered" => "content_filter",
+        _ => "stop",
+      };
+      Reply::json(json!({
+        "model": "served-model",
+        "choices": [{ "message": { "role": "assistant", "content": "{\"name\":\"Widget\"}" }, "finish_reason": finish_reason }],
+        "usage": { "prompt_tokens": 12, "completion_tokens": 4, "total_tokens": 16 },
+      }))
+    })
+    .await
+  }
+
+  #[tokio::test]
+  async fn maps_the_request_and_reads_the_response() {
+    let server = server().await;
+    let llm = OpenAiCompatProvider::new(format!("{}/v1/", server.base_url), "local-model".into(), Some("secret".into()), SharedHttp::default());
+    let schema = json!({
+      "type": "OBJECT",
+      "properties": { "name": { "type": "STRING" }, "maker": { "type": "STRING" } },
+      "required": ["name"],
+      "propertyOrdering": ["name", "maker"],
+    });
+    let params = GenerationParams { temperature: Some(0.5), top_p: Some(0.25), top_k: Some(40), max_output_tokens: Some(256), seed: Some(7), thinking_budget: Some(100) };
+    let resp = llm
+      .generate_structured(GenerateRequest {
+        response_schema: Some(schema.clone()),
+        params: StageParams { search: GenerationParams::default(), structure: params },
+        attachments: vec![attachment("/data/photo.png", "image/png", "iVBO"), attachment("/data/spec.pdf", "application/pdf", "JVBE")],
+        ..request("Describe the product")
+      })
+      .await
+      .unwrap();
+    assert_eq!(resp.text, "{\"name\":\"Widget\"}");
+    assert_eq!(resp.model.as_deref(), Some("served-model"));
+    assert_eq!(resp.usage.and_then(|u| u.structure).map(|u| (u.prompt_tokens, u.candidates_tokens)), Some((12, 4)));
+
+    let sent = &server.requests()[0];
+    assert_eq!((sent.method.as_str(), sent.path.as_str()), ("POST", "/v1/chat/completions"));
+    assert_eq!(sent.header("authorization"), Some("Bearer secret"));
+    let body = sent.json();
+    assert_eq!(body["model"], "local-model");
+    assert_eq!(body["messages"][0], json!({ "role": "system", "content": SystemPrompts::defaults("en").single_system }));
+    assert_eq!(
+      body["messages"][1]["content"],
+      json!([
+        { "type": "text", "text": "Describe the product" },
+        { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBO" } },
+        { "type": "file", "file": { "filename": "spec.pdf", "file_data": "data:application/pdf;base64,JVBE" } },
+      ])
+    );
+    let format = &body["response_format"];
+    assert_eq!(format["type"], "json_schema");
+    assert_eq!(format["json_schema"]["name"], "row");
+    assert_eq!(format["json_schema"]["strict"], true);
+    assert_eq!(format["json_schema"]["schema"], to_json_schema(&schema));
+    assert_eq!(format["json_schema"]["schema"]["additionalProperties"], false);
+    assert!(format["json_schema"]["schema"].get("propertyOrdering").is_none());
+    assert_eq!(
+      (&body["temperature"], &body["top_p"], &body["top_k"], &body["max_tokens"], &body["seed"]),
+      (&json!(0.5), &json!(0.25), &json!(40), &json!(256), &json!(7))
+    );
+    // thinking_budget は送らない
+    assert!(body.get("thinking_budget").is_none() && body.get("max_output_tokens").is_none());
+  }
+
+  #[tokio::test]
+  async fn maps_finish_reasons_to_blocked_errors() {
+    let server = server().await;
+    let llm = OpenAiCompatProvider::new(format!("{}/v1", server.base_url), "local-model".into(), None, SharedHttp::default());
+    for (prompt, want) in [("too long", FinishReason::MaxTokens), ("filtered", FinishReason::Safety)] {
+      let err = llm.generate_structured(request(prompt)).await.unwrap_err();
+      match err.downcast_ref::<GenerateError>() {
+        Some(GenerateError::Blocked { stage, reason, .. }) => assert_eq!((*stage, *reason), (Stage::Structure, want), "{}", prompt),
+        other => panic!("{}: unexpected error {:?}", prompt, other),
+      }
+    }
+    // スキーマが無ければ JSON モード、API キーが無ければ Authorization を付けない
+    assert!(llm.generate_structured(request("ok")).await.is_ok());
+    let sent = server.requests();
+    assert!(sent.iter().all(|r| r.header("authorization").is_none()));
+    assert_eq!(sent[2].json()["response_format"], json!({ "type": "json_object" }));
+    assert_eq!(sent[2].json()["messages"][1]["content"], "ok");
+  }
+}
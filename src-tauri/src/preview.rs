@@ -0,0 +1,384 @@
+use crate::attachments;
+use crate::fetch;
+use crate::gemini::DEFAULT_GEMINI_MODEL;
+use crate::http_pool::SharedHttp;
+use crate::processor::{self, ExecutionMode, ProcessConfig, Row};
+use crate::prompts::{SystemPrompts, NOTES_PLACEHOLDER, OUTPUT_PLACEHOLDER};
+use crate::provider::{self, ProviderKind, ProviderSettings};
+use crate::suggest;
+use crate::usage::{self, TokenUsage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// 実行前の確認（dry run）: 全行のプロンプトを組み立て、欠けた列・空欄を報告し、
+// トークン数・リクエスト数・所要時間・概算コストを見積もる（生成のエンドポイントには何も送らない）
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PreviewOptions {
+  // true なら countTokens も呼ばず、ローカルの概算でトークン数を数える
+  #[serde(default)]
+  pub offline: bool,
+  // 1行あたりの stage1 のメモ（stage2 の入力にもなる）のトークン数の想定（未指定は 1000）
+  #[serde(default)]
+  pub expected_notes_tokens: Option<u64>,
+  // 1行あたりの構造化出力のトークン数の想定（未指定は 300）
+  #[serde(default)]
+  pub expected_output_tokens: Option<u64>,
+  // 1行（1サンプル）の処理にかかる秒数の想定（未指定は検索ありで 15、なしで 5）
+  #[serde(default)]
+  pub expected_row_secs: Option<f64>,
+}
+
+const DEFAULT_NOTES_TOKENS: u64 = 1000;
+const DEFAULT_OUTPUT_TOKENS: u64 = 300;
+// 検証段の出力（フィールドごとの判定）の想定
+const DEFAULT_VERIFY_OUTPUT_TOKENS: u64 = 200;
+const DEFAULT_TWO_STAGE_ROW_SECS: f64 = 15.0;
+const DEFAULT_SINGLE_ROW_SECS: f64 = 5.0;
+// countTokens 1回で数えるプロンプトの数
+const COUNT_TOKENS_CHUNK: usize = 100;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RowIssue {
+  index: u32,
+  // テンプレートの {{列名}} のうち、行に列が無いもの
+  missing: Vec<String>,
+  // 列はあるが値が空（空文字・空白のみ・null）のもの
+  empty: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RunPreview {
+  rows: u32,
+  // 行ごとの組み立て済みプロンプト（{{file:列名}} はファイル名に置き換え済み）
+  prompts: Vec<String>,
+  issues: Vec<RowIssue>,
+  rows_with_missing: u32,
+  rows_with_empty: u32,
+  // count_tokens（API で計測）| approximate（ローカルの概算）
+  token_count_method: &'static str,
+  // 組み立てたプロンプトだけのトークン数の合計
+  prompt_tokens: u64,
+  // システムプロンプト・メモ・応答スキーマなどを含めた見積もり（全行・全サンプル）
+  estimated_input_tokens: u64,
+  estimated_output_tokens: u64,
+  // 生成のリクエスト数（stage1 は行・共有メモのキーごとに1、それ以外の段はサンプルごとに1）
+  requests: u64,
+  // stage1（検索・URL のメモ収集）を実行する回数
+  search_runs: u64,
+  // batch 実行では None（完了までの時間は Batch API 次第）
+  estimated_duration_secs: Option<f64>,
+  // rate_limit | concurrency（所要時間を決めている側）
+  duration_bound: Option<&'static str>,
+  model: String,
+  estimated_cost_usd: Option<f64>,
+  // 見積もりに含めていないもの・トークン計測のフォールバックなど
+  notes: Vec<String>,
+}
+
+#[tauri::command]
+pub async fn preview_run(rows: Vec<Row>, config: ProcessConfig, options: Option<PreviewOptions>) -> Result<RunPreview, String> {
+  let options = options.unwrap_or_default();
+  let mut notes = Vec::new();
+  let template = config.prompt_template.as_str();
+  let attachment_columns = attachments::attachment_columns(template);
+  let placeholders = suggest::placeholders(template);
+
+  // プロンプトの組み立てと、欠けた列・空欄の検出
+  let mut prompts = Vec::with_capacity(rows.len());
+  let mut issues = Vec::new();
+  for (idx, row) in rows.iter().enumerate() {
+    prompts.push(attachments::replace_markers(&processor::render_prompt(template, &row.0), &attachment_columns, &row.0));
+    let mut missing = Vec::new();
+    let mut empty = Vec::new();
+    for name in &placeholders {
+      match row.0.get(name) {
+        None => missing.push(name.clone()),
+        Some(serde_json::Value::Null) => empty.push(name.clone()),
+        Some(serde_json::Value::String(s)) if s.trim().is_empty() => empty.push(name.clone()),
+        Some(_) => {}
+      }
+    }
+    if !missing.is_empty() || !empty.is_empty() {
+      issues.push(RowIssue { index: idx as u32, missing, empty });
+    }
+  }
+
+  // process_rows と同じ条件で、段の構成・サンプル数を決める
+  let batch_mode = config.execution_mode == ExecutionMode::Batch;
+  let gemini_online = config.provider == ProviderKind::Gemini && !batch_mode;
+  let two_stage = gemini_online
+    && (config.enable_web_search || config.url_column.as_ref().is_some_and(|c| !c.trim().is_empty()) || !config.tools.is_empty());
+  let verify = config.verify && two_stage;
+  let samples = if batch_mode { 1 } else { config.samples.unwrap_or(1).max(1) as u64 };
+
+  // stage1 の実行回数: 検索メモはサンプル間で共有し、notes_cache_key があれば同じキーの行とも共有する
+  // （URL グラウンディングの行はページが行ごとのため、サンプルごとに実行する）
+  let url_column = config.url_column.as_deref().filter(|c| !c.trim().is_empty());
+  let notes_cache_key = config.notes_cache_key.as_deref().filter(|k| !k.trim().is_empty());
+  let notes_key_columns = notes_cache_key.map(suggest::placeholders).unwrap_or_default();
+  let notes_cache = match notes_cache_key {
+    Some(_) if !two_stage || !config.enable_web_search || !attachment_columns.is_empty() => None,
+    Some(_) if notes_key_columns.is_empty() || notes_key_columns.iter().any(|c| !rows.iter().any(|r| r.0.contains_key(c))) => {
+      notes.push("the notes cache key does not reference known columns and the run would be rejected".to_string());
+      None
+    }
+    key => key,
+  };
+  let mut search_runs = 0;
+  let mut shared_keys = HashSet::new();
+  for row in rows.iter().filter(|_| two_stage) {
+    let has_urls = url_column
+      .and_then(|c| row.0.get(c))
+      .is_some_and(|v| !fetch::extract_urls(&processor::value_to_string(v)).is_empty());
+    if has_urls {
+      search_runs += samples;
+    } else if config.enable_web_search {
+      let key = notes_cache.and_then(|expr| processor::render_notes_key(expr, &notes_key_columns, &row.0));
+      // 同じキーのメモが既にあれば検索しない（キーを決められない行は共有しない）
+      let reused = match key {
+        Some(key) => !shared_keys.insert(key),
+        None => false,
+      };
+      if !reused {
+        search_runs += 1;
+      }
+    }
+  }
+
+  // 全行で共通の入力（システムプロンプト・指示・応答スキーマ）。stage1 の分は stage1 の実行ごと、それ以外はサンプルごとに数える
+  let system_prompts = SystemPrompts::resolve(&config.system_prompts);
+  let search_texts = if search_runs > 0 {
+    vec![system_prompts.search_system.clone(), system_prompts.search_instruction.clone()]
+  } else {
+    Vec::new()
+  };
+  let mut overhead_texts = if two_stage {
+    vec![system_prompts.structure_system.clone(), system_prompts.structure_input.replace(NOTES_PLACEHOLDER, "")]
+  } else {
+    vec![system_prompts.single_system.clone()]
+  };
+  if verify {
+    overhead_texts.push(system_prompts.verify_system.clone());
+    overhead_texts.push(system_prompts.verify_input.replace(NOTES_PLACEHOLDER, "").replace(OUTPUT_PLACEHOLDER, ""));
+  }
+  if let Some(schema) = config.response_schema.as_ref() {
+    overhead_texts.push(schema.to_string());
+  }
+
+  // トークン数: gemini は countTokens（失敗時・offline・他のバックエンドはローカルの概算）
+  let model = match config.provider {
+    ProviderKind::Gemini => config.model.clone().filter(|m| !m.trim().is_empty()).unwrap_or_else(|| DEFAULT_GEMINI_MODEL.to_string()),
+    _ => config.model.clone().unwrap_or_default(),
+  };
+  let counted = if options.offline || config.provider != ProviderKind::Gemini {
+    None
+  } else {
+    match count_with_api(&config, &model, &prompts, &[&search_texts, &overhead_texts]).await {
+      Ok(counts) => Some(counts),
+      Err(e) => {
+        notes.push(format!("token counting failed and a local approximation was used instead: {}", e));
+        None
+      }
+    }
+  };
+  let (token_count_method, prompt_tokens, search_overhead_tokens, overhead_tokens) = match counted {
+    Some((p, groups)) => ("count_tokens", p, groups[0], groups[1]),
+    None => (
+      "approximate",
+      prompts.iter().map(|p| approx_tokens(p)).sum(),
+      search_texts.iter().map(|t| approx_tokens(t)).sum(),
+      overhead_texts.iter().map(|t| approx_tokens(t)).sum(),
+    ),
+  };
+
+  // 行（サンプル）あたりの想定を全体へ広げる
+  let notes_tokens = if two_stage { options.expected_notes_tokens.unwrap_or(DEFAULT_NOTES_TOKENS) } else { 0 };
+  let output_tokens = options.expected_output_tokens.unwrap_or(DEFAULT_OUTPUT_TOKENS);
+  let runs = rows.len() as u64 * samples;
+  // stage1 に送る行のプロンプトは行ごとに異なるため、1行あたりの平均で数える
+  let prompt_per_row = prompt_tokens.checked_div(rows.len() as u64).unwrap_or(0);
+  let tools_stage = two_stage && !config.tools.is_empty();
+  // 行のプロンプトを送る段: stage1（実行ごと）・ツール段（サンプルごと）・単発構造化（サンプルごと）
+  let prompt_sends = search_runs + if tools_stage || !two_stage { runs } else { 0 };
+  let mut input_per_run = overhead_tokens + notes_tokens;
+  let mut output_per_run = output_tokens;
+  let mut requests_per_run = if tools_stage { 2 } else { 1 };
+  if tools_stage {
+    // ツール段はメモ（検索があればそのメモ）を受け取り、メモを書き足す
+    output_per_run += notes_tokens;
+    if search_runs > 0 {
+      input_per_run += notes_tokens;
+    }
+  }
+  if verify {
+    input_per_run += notes_tokens + output_tokens;
+    output_per_run += DEFAULT_VERIFY_OUTPUT_TOKENS;
+    requests_per_run += 1;
+  }
+  let estimated_input_tokens = prompt_per_row * prompt_sends + search_overhead_tokens * search_runs + input_per_run * runs;
+  let estimated_output_tokens = notes_tokens * search_runs + output_per_run * runs;
+  let requests = search_runs + requests_per_run * runs;
+
+  // 所要時間: キーごとの RPM の合計で決まる時間と、並列数で決まる時間の遅い方
+  let (estimated_duration_secs, duration_bound) = if batch_mode || runs == 0 {
+    (None, None)
+  } else {
+    let rpm: u64 = processor::key_entries(&config.api_key, &config.api_keys)
+      .iter()
+      .map(|k| k.rate_limit_rpm.unwrap_or(config.rate_limit_rpm).max(1) as u64)
+      .sum();
+    let rate_secs = runs as f64 * 60.0 / rpm as f64;
+    let row_secs = options.expected_row_secs.unwrap_or(if two_stage { DEFAULT_TWO_STAGE_ROW_SECS } else { DEFAULT_SINGLE_ROW_SECS });
+    let concurrency_secs = runs as f64 * row_secs / config.concurrency.max(1) as f64;
+    if rate_secs >= concurrency_secs {
+      (Some(rate_secs), Some("rate_limit"))
+    } else {
+      (Some(concurrency_secs), Some("concurrency"))
+    }
+  };
+
+  let usage_estimate = TokenUsage {
+    prompt_tokens: estimated_input_tokens,
+    candidates_tokens: estimated_output_tokens,
+    total_tokens: estimated_input_tokens + estimated_output_tokens,
+    ..Default::default()
+  };
+  let price_factor = if batch_mode { usage::BATCH_PRICE_FACTOR } else { 1.0 };
+  let estimated_cost_usd =
+    usage::estimate_cost(&usage::price_table_with(config.pricing.as_ref()), &model, &usage_estimate).map(|c| c * price_factor);
+  if estimated_cost_usd.is_none() {
+    notes.push(format!("no price is known for model '{}'", model));
+  }
+
+  if batch_mode {
+    notes.push("batch jobs can take up to 24 hours, so no duration is estimated".to_string());
+  }
+  if two_stage && config.enable_web_search {
+    notes.push("search tool usage and grounding fees are not included".to_string());
+  }
+  if gemini_online && !config.tools.is_empty() {
+    notes.push("local tool rounds are not included".to_string());
+  }
+  if !config.fallback_models.is_empty() {
+    notes.push("retries on fallback models are not included".to_string());
+  }
+  if config.context_cache {
+    notes.push("context cache discounts are not included".to_string());
+  }
+  if !attachment_columns.is_empty() {
+    notes.push("attached files are not counted".to_string());
+  }
+
+  Ok(RunPreview {
+    rows: rows.len() as u32,
+    prompts,
+    rows_with_missing: issues.iter().filter(|i| !i.missing.is_empty()).count() as u32,
+    rows_with_empty: issues.iter().filter(|i| !i.empty.is_empty()).count() as u32,
+    issues,
+    token_count_method,
+    prompt_tokens,
+    estimated_input_tokens,
+    estimated_output_tokens,
+    requests,
+    search_runs,
+    estimated_duration_secs,
+    duration_bound,
+    model,
+    estimated_cost_usd,
+    notes,
+  })
+}
+
+// 行のプロンプトの合計と、共通の入力（グループごと）のトークン数を countTokens で数える
+async fn count_with_api(config: &ProcessConfig, model: &str, prompts: &[String], groups: &[&[String]]) -> anyhow::Result<(u64, Vec<u64>)> {
+  let key = processor::key_entries(&config.api_key, &config.api_keys).into_iter().next().map(|k| k.key).unwrap_or_default();
+  let llm = provider::build_provider(&ProviderSettings {
+    kind: config.provider,
+    api_key: key,
+    base_url: config.base_url.clone(),
+    model: Some(model.to_string()),
+    http: SharedHttp::new(&config.http_pool)?,
+  })?;
+  let mut prompt_tokens = 0;
+  for chunk in prompts.chunks(COUNT_TOKENS_CHUNK) {
+    prompt_tokens += llm.count_tokens(chunk.to_vec(), config.timeout_secs).await?;
+  }
+  let mut group_tokens = Vec::with_capacity(groups.len());
+  for texts in groups {
+    group_tokens.push(if texts.is_empty() { 0 } else { llm.count_tokens(texts.to_vec(), config.timeout_secs).await? });
+  }
+  Ok((prompt_tokens, group_tokens))
+}
+
+// ローカルの概算（ASCII はおよそ4文字で1トークン、それ以外の文字は1文字1トークンとして数える）
+fn approx_tokens(text: &str) -> u64 {
+  let ascii = text.chars().filter(|c| c.is_ascii()).count() as u64;
+  let other = text.chars().count() as u64 - ascii;
+  ascii.div_ceil(4) + other
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn to_rows(values: serde_json::Value) -> Vec<Row> {
+    serde_json::from_value(values).unwrap()
+  }
+
+  fn config(extra: serde_json::Value) -> ProcessConfig {
+    let mut base = serde_json::json!({
+      "api_key": "k",
+      "concurrency": 2,
+      "rate_limit_rpm": 60,
+      "timeout_secs": 30,
+      "prompt_template": "Tell me about {{company}}",
+      "samples": 3,
+    });
+    base.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+    serde_json::from_value(base).unwrap()
+  }
+
+  fn offline() -> Option<PreviewOptions> {
+    Some(PreviewOptions { offline: true, ..Default::default() })
+  }
+
+  #[tokio::test]
+  async fn search_runs_once_per_row_or_per_shared_notes_key() {
+    let rows = to_rows(serde_json::json!([
+      { "company": "Acme", "country": "JP" },
+      { "company": "Acme", "country": "JP" },
+      { "company": "Globex", "country": "US" },
+      { "company": "Initech", "country": "" },
+    ]));
+
+    // 検索メモはサンプル間で共有する: 4行 × stage1 1回、構造化は 4行 × 3サンプル
+    let preview = preview_run(rows.clone(), config(serde_json::json!({})), offline()).await.unwrap();
+    assert_eq!(preview.search_runs, 4);
+    assert_eq!(preview.requests, 4 + 12);
+
+    // 同じキーの行は stage1 を共有する（キーの列が空の行は共有しない）
+    let keyed = config(serde_json::json!({ "notes_cache_key": "{{company}} {{country}}" }));
+    let preview = preview_run(rows.clone(), keyed, offline()).await.unwrap();
+    assert_eq!(preview.search_runs, 3);
+    assert_eq!(preview.requests, 3 + 12);
+
+    // URL のある行は URL グラウンディングになり、サンプルごとに実行する
+    let with_urls = to_rows(serde_json::json!([
+      { "company": "Acme", "site": "https://acme.example" },
+      { "company": "Globex", "site": "" },
+    ]));
+    let preview = preview_run(with_urls, config(serde_json::json!({ "url_column": "site" })), offline()).await.unwrap();
+    assert_eq!(preview.search_runs, 3 + 1);
+    assert_eq!(preview.requests, 4 + 6);
+  }
+
+  #[tokio::test]
+  async fn single_stage_counts_the_prompt_for_every_sample() {
+    let rows = to_rows(serde_json::json!([{ "company": "Acme" }]));
+    let preview = preview_run(rows, config(serde_json::json!({ "enable_web_search": false })), offline()).await.unwrap();
+    assert_eq!(preview.search_runs, 0);
+    assert_eq!(preview.requests, 3);
+    assert!(preview.estimated_input_tokens >= preview.prompt_tokens * 3);
+  }
+}
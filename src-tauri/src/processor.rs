@@ -1,8 +1,26 @@
-use crate::gemini;
+use crate::attachments;
+use crate::batch::{self, BatchClient, BatchOptions};
+use crate::fetch::{self, HttpPageFetcher, PageFetcher};
+use crate::http_pool::{HttpPoolConfig, HttpStats, HttpSummary, SharedHttp};
+use crate::consensus::{self, Consistency};
+use crate::key_pool::{self, ApiKeyEntry, DispatchStrategy, KeyLease, KeyPool};
+use crate::notes_cache::{NotesCache, SharedNotes};
+use crate::npy;
+use crate::tools::{self, LocalToolRun, LocalTools, ToolCallLog, ToolConfig};
+use crate::gemini::{self, GenerateResponse};
+use crate::prompts::{SystemPromptConfig, SystemPrompts};
+use crate::usage::{self, ModelPrice, StageUsage, TokenUsage, UsageTotals};
+use crate::sources;
+use crate::suggest;
+use crate::verify::{self, FieldVerdict};
+use crate::provider::{
+  self, classify_error, EmbedRequest, EmbedResponse, ErrorClass, GenerateError, GenerateRequest, GenerationParams, PartialSink, PrefixCacheRequest, ProviderKind, ProviderSettings,
+  SafetySetting, StageParams, StageTimeouts, UrlGrounding, UsageSink,
+};
 use anyhow::Result;
-use governor::{Quota, RateLimiter};
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroU32, sync::Arc};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::Arc};
 use tauri::{AppHandle, Emitter, Manager};
 use tokio::{sync::Semaphore, task::JoinSet};
 use tokio_util::sync::CancellationToken;
@@ -23,12 +41,116 @@ pub struct ProcessConfig {
   pub enable_web_search: bool,
   #[serde(default)]
   pub response_schema: Option<serde_json::Value>,
+  // 使用する LLM バックエンド（未指定は gemini）
+  #[serde(default)]
+  pub provider: ProviderKind,
+  // openai_compatible / ollama 用の接続先（例: http://localhost:8000/v1）
+  #[serde(default)]
+  pub base_url: Option<String>,
+  // モデル名（gemini は未指定で既定モデル、openai_compatible / ollama は必須）
+  #[serde(default)]
+  pub model: Option<String>,
+  // 段階別の生成パラメータ（temperature, top_p, top_k, max_output_tokens, seed, thinking_budget）
+  #[serde(default)]
+  pub generation: StageParams,
+  // システムプロンプトの言語（ja / en / de）と個別上書き
+  #[serde(default)]
+  pub system_prompts: SystemPromptConfig,
+  // 段階別タイムアウト（未指定は timeout_secs）。stage1=検索メモ収集, stage2=構造化
+  #[serde(default)]
+  pub search_timeout_secs: Option<u64>,
+  #[serde(default)]
+  pub structure_timeout_secs: Option<u64>,
+  // モデル別単価（USD / 100万トークン）。既定の単価表に上書きされる
+  #[serde(default)]
+  pub pricing: Option<HashMap<String, ModelPrice>>,
+  // online: 行ごとに即時リクエスト / batch: Gemini Batch API（割引あり・完了まで数時間かかりうる）
+  #[serde(default)]
+  pub execution_mode: ExecutionMode,
+  #[serde(default)]
+  pub batch: BatchOptions,
+  // テンプレートの最初の {{列}} より前（全行で共通の接頭辞）をコンテキストキャッシュに載せる（gemini・online のみ）
+  #[serde(default)]
+  pub context_cache: bool,
+  // キャッシュの保持期間（秒、未指定は 3600）
+  #[serde(default)]
+  pub context_cache_ttl_secs: Option<u64>,
+  // ストリーミングで生成し、途中経過を processing:row_partial で通知する（gemini・online のみ）
+  #[serde(default)]
+  pub stream: bool,
+  // 添付ファイル（テンプレートの {{file:列名}}）1件あたりの上限バイト数（未指定は 15MB）
+  #[serde(default)]
+  pub max_attachment_bytes: Option<u64>,
+  // 1行の添付ファイルの合計上限バイト数（未指定は 15MB）
+  #[serde(default)]
+  pub max_total_attachment_bytes: Option<u64>,
+  // URL グラウンディング: この列の URL のページ本文から stage1 のメモを作る（URL が空の行は通常どおり）
+  #[serde(default)]
+  pub url_column: Option<String>,
+  // 取得したページ本文の上限（文字数、未指定は 20000）
+  #[serde(default)]
+  pub max_page_chars: Option<usize>,
+  // 取得するレスポンス本文の上限（バイト、未指定は 5MB。超えた分は受信せずに切り詰める）
+  #[serde(default)]
+  pub max_page_bytes: Option<usize>,
+  // 行処理中にモデルから呼び出せるローカル参照ツール（CSV / JSON を検索する関数。gemini・online のみ）
+  #[serde(default)]
+  pub tools: Vec<ToolConfig>,
+  // 1行あたりの関数呼び出しの往復回数の上限（未指定は 5）
+  #[serde(default)]
+  pub max_tool_rounds: Option<u32>,
+  // 複数の API キー（指定時は api_key より優先）。キーごとに RPM を持ち、行ごとに振り分ける
+  #[serde(default)]
+  pub api_keys: Vec<ApiKeyEntry>,
+  // round_robin | least_loaded
+  #[serde(default)]
+  pub key_dispatch: DispatchStrategy,
+  // quota エラーを返したキーを退避させる秒数（未指定は 60。認証エラーはその実行の間ずっと退避）
+  #[serde(default)]
+  pub key_retire_secs: Option<u64>,
+  // 429 / 503 などで主モデルが失敗した行を順に再試行する代替モデル（online のみ）
+  #[serde(default)]
+  pub fallback_models: Vec<String>,
+  // HTTP 接続プール（ホストあたりのアイドル接続数・HTTP/2 keep-alive）。クライアントは実行ごとに1つを全行で共有する
+  #[serde(default)]
+  pub http_pool: HttpPoolConfig,
+  // 安全性フィルタのしきい値（gemini のみ。例: { "category": "DANGEROUS_CONTENT", "threshold": "BLOCK_ONLY_HIGH" }）
+  #[serde(default)]
+  pub safety_settings: Vec<SafetySetting>,
+  // stage1 のメモを共有するキーの式（例: "{{artist_name}}"）。同じキーの行は最初の1行だけ検索し、そのメモで構造化する
+  #[serde(default)]
+  pub notes_cache_key: Option<String>,
+  // 指定時は共有メモをファイルへ保存し、この秒数の間は次回以降の実行でも再利用する
+  #[serde(default)]
+  pub notes_cache_ttl_secs: Option<u64>,
+  // self-consistency: 1行あたりの構造化生成の回数（未指定は 1）。2以上ならフィールドごとに多数決・中央値でまとめ、一致率を付ける
+  #[serde(default)]
+  pub samples: Option<u32>,
+  // 構造化の後に、各フィールドが stage1 のメモで裏付けられるかを判定する（gemini・online。メモのある行のみ）
+  #[serde(default)]
+  pub verify: bool,
+  // verify 時、unsupported と判定されたフィールドを null にして返す（raw と response ログの responseText は元のまま）
+  #[serde(default)]
+  pub null_unsupported_fields: bool,
+  // 応答スキーマに _sources（フィールド → stage1 の出典 URL）を足し、フィールドごとの出典を返す（検索・URL グラウンディング時のみ）
+  #[serde(default)]
+  pub field_sources: bool,
 }
 
 fn default_enable_web_search() -> bool {
   true
 }
 
+const DEFAULT_CONTEXT_CACHE_TTL_SECS: u64 = 3600;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionMode {
+  #[default]
+  Online,
+  Batch,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Row(pub serde_json::Map<String, serde_json::Value>);
 
@@ -37,71 +159,319 @@ pub async fn process_rows(app: AppHandle, rows: Vec<Row>, config: ProcessConfig)
   let cancel = CancellationToken::new();
   app.state::<CancelHolder>().0.set(cancel.clone()).ok();
 
-  let limiter = Arc::new(RateLimiter::direct(
-    Quota::per_minute(NonZeroU32::new(config.rate_limit_rpm.max(1)).unwrap()),
-  ));
   let semaphore = Arc::new(Semaphore::new(config.concurrency.max(1)));
 
   let total = rows.len() as u32;
   let mut set = JoinSet::new();
   let app_clone = app.clone();
   let prompt_template = config.prompt_template.clone();
-  let enable_web_search = config.enable_web_search;
+  let keys = key_entries(&config.api_key, &config.api_keys);
+  // 実行内の全リクエスト（全キーの生成・Batch API・URL 取得）で1つのクライアントを共有する
+  let http = SharedHttp::new(&config.http_pool.clone().with_default_idle(config.concurrency)).map_err(|e| e.to_string())?;
+  let settings = ProviderSettings {
+    kind: config.provider,
+    api_key: String::new(),
+    base_url: config.base_url.clone(),
+    model: config.model.clone(),
+    http: http.clone(),
+  };
+  let pool = build_key_pool(&keys, config.rate_limit_rpm, config.key_dispatch, config.key_retire_secs, &settings)?;
+  // モデル名・対応機能の判定とコンテキストキャッシュには先頭キーのバックエンドを使う
+  let llm = pool.primary();
+  let batch_mode = config.execution_mode == ExecutionMode::Batch;
+  if batch_mode && config.provider != ProviderKind::Gemini {
+    return Err("batch execution mode is only available with the gemini provider".into());
+  }
+  // 検索非対応のバックエンド・バッチ実行では検索なしの単発構造化にフォールバック（各 request ログにも理由を残す）
+  let web_search_disabled_reason = if batch_mode && config.enable_web_search {
+    Some("web search is not available in batch execution mode and was disabled".to_string())
+  } else {
+    provider::web_search_unavailable_reason(llm.as_ref(), config.enable_web_search)
+  };
+  let enable_web_search = config.enable_web_search && web_search_disabled_reason.is_none();
+  if let Some(reason) = web_search_disabled_reason.as_ref() {
+    let _ = app.emit("processing:notice", reason.clone());
+    let _ = app.emit("processing:debug", reason.clone());
+  }
   let response_schema = config.response_schema.clone();
-
-  // --- Run ID と ログファイルパスの準備 ---
-  let run_id = {
-    // エポックミリ秒 + 下位4桁の16進を付与した簡易ID（外部クレート不使用）
-    let now = std::time::SystemTime::now()
-      .duration_since(std::time::UNIX_EPOCH)
-      .unwrap_or_default();
-    let millis = now.as_millis();
-    let suffix = (millis as u64) & 0xFFFF;
-    format!("{}-{:04x}", millis, suffix)
+  // 行を走らせる前に、リクエストに付けるときと同じ変換で検証する
+  gemini::api_safety_settings(&config.safety_settings).map_err(|e| e.to_string())?;
+  let safety_settings = if config.safety_settings.is_empty() || config.provider == ProviderKind::Gemini {
+    config.safety_settings.clone()
+  } else {
+    let reason = format!("safety settings are only applied with the gemini provider and were ignored for '{}'", llm.name());
+    let _ = app.emit("processing:notice", reason.clone());
+    let _ = app.emit("processing:debug", reason);
+    Vec::new()
   };
+  let attachment_columns = Arc::new(attachments::attachment_columns(&prompt_template));
+  if batch_mode && !attachment_columns.is_empty() {
+    return Err("file attachments ({{file:column}}) are not supported in batch execution mode".into());
+  }
+  let max_attachment_bytes = config.max_attachment_bytes.unwrap_or(attachments::DEFAULT_MAX_ATTACHMENT_BYTES);
+  let max_total_attachment_bytes =
+    config.max_total_attachment_bytes.unwrap_or(attachments::DEFAULT_MAX_TOTAL_ATTACHMENT_BYTES);
 
-  // AppData 配下に logs ディレクトリを用意（例: %AppData%/staf/logs）
-  let logs_path: PathBuf = {
-    // tauri 2 のパスリゾルバ（取れない場合は temp_dir ）
-    let base = app
-      .path()
-      .app_data_dir()
-      .unwrap_or(std::env::temp_dir())
-      .join("staf")
-      .join("logs");
-    if let Err(e) = tokio::fs::create_dir_all(&base).await {
-      let _ = app.emit("processing:debug", format!("log dir create error: {}", e));
+  // URL グラウンディングは2段階パイプライン（検索対応のバックエンド・online 実行）でのみ使える
+  let url_column = config.url_column.clone().filter(|c| !c.trim().is_empty());
+  let url_fetcher: Option<Arc<dyn PageFetcher>> = match url_column.as_ref() {
+    // 差し替えの取得処理が登録されていればそれを使う
+    Some(_) if llm.supports_web_search() && !batch_mode => Some(app.state::<PageFetcherHolder>().0.get().cloned().unwrap_or_else(|| {
+      Arc::new(HttpPageFetcher::new(
+        http.client.clone(),
+        config.max_page_chars.unwrap_or(fetch::DEFAULT_MAX_PAGE_CHARS),
+        config.search_timeout_secs.unwrap_or(config.timeout_secs),
+      )
+      .with_max_bytes(config.max_page_bytes.unwrap_or(fetch::MAX_PAGE_BYTES)))
+    })),
+    Some(_) => {
+      let reason = "URL grounding is not available with this provider or execution mode and was disabled".to_string();
+      let _ = app.emit("processing:notice", reason.clone());
+      let _ = app.emit("processing:debug", reason);
+      None
     }
-    base
+    None => None,
   };
 
-  let log_file_path = logs_path.join(format!("run-{}.jsonl", run_id));
+  // ローカルツールは参照データを実行開始時に一度だけ読み込む（読み込めなければ実行しない）
+  let local_tools: Option<Arc<LocalTools>> = if config.tools.is_empty() {
+    None
+  } else if llm.supports_local_tools() && !batch_mode {
+    Some(Arc::new(LocalTools::load(&config.tools).await.map_err(|e| e.to_string())?))
+  } else {
+    let reason = "local tools are not available with this provider or execution mode and were disabled".to_string();
+    let _ = app.emit("processing:notice", reason.clone());
+    let _ = app.emit("processing:debug", reason);
+    None
+  };
+  let max_tool_rounds = config.max_tool_rounds.unwrap_or(tools::DEFAULT_MAX_TOOL_ROUNDS);
 
-  // ログ追記用の簡易ロック
-  let log_lock = Arc::new(tokio::sync::Mutex::new(()));
+  // 主モデル → 代替モデルの順に試すモデルの並び（重複・空欄は除く）
+  let mut model_chain = vec![llm.model().to_string()];
+  for m in &config.fallback_models {
+    let m = m.trim();
+    if !m.is_empty() && !model_chain.iter().any(|c| c == m) {
+      model_chain.push(m.to_string());
+    }
+  }
+  if batch_mode && model_chain.len() > 1 {
+    let reason = "fallback models are not used in batch execution mode".to_string();
+    let _ = app.emit("processing:notice", reason.clone());
+    let _ = app.emit("processing:debug", reason);
+    model_chain.truncate(1);
+  }
+  let model_chain = Arc::new(model_chain);
+  let samples = config.samples.unwrap_or(1).max(1);
+  if batch_mode && samples > 1 {
+    let reason = "multiple samples per row are not used in batch execution mode".to_string();
+    let _ = app.emit("processing:notice", reason.clone());
+    let _ = app.emit("processing:debug", reason);
+  }
+  if batch_mode && config.context_cache {
+    let reason = "context cache is not used in batch execution mode".to_string();
+    let _ = app.emit("processing:notice", reason.clone());
+    let _ = app.emit("processing:debug", reason);
+  }
+  if batch_mode && config.stream {
+    let reason = "streaming is not available in batch execution mode and was disabled".to_string();
+    let _ = app.emit("processing:notice", reason.clone());
+    let _ = app.emit("processing:debug", reason);
+  }
+  // 検証段は stage1 のメモ（検索・URL グラウンディング・ローカルツール）がある行だけで行う
+  let verify = config.verify && llm.supports_web_search() && !batch_mode;
+  let verify_disabled_reason = if config.verify && !verify {
+    Some("field verification is not available with this provider or execution mode and was disabled")
+  } else if verify && !enable_web_search && url_fetcher.is_none() && local_tools.is_none() {
+    Some("field verification only runs on rows with collected notes (web search, URL grounding or local tools)")
+  } else {
+    None
+  };
+  if let Some(reason) = verify_disabled_reason {
+    let _ = app.emit("processing:notice", reason.to_string());
+    let _ = app.emit("processing:debug", reason.to_string());
+  }
+  // 出典の対応は stage1 の出典（検索・URL グラウンディング）があり、スキーマのトップレベルにプロパティがある場合のみ
+  let sources_schema = match (config.field_sources, response_schema.as_ref()) {
+    (true, _) if !enable_web_search && url_fetcher.is_none() => {
+      let reason = "field sources need web search or URL grounding and were disabled".to_string();
+      let _ = app.emit("processing:notice", reason.clone());
+      let _ = app.emit("processing:debug", reason);
+      None
+    }
+    (true, schema) => {
+      let augmented = schema.and_then(sources::augment_schema);
+      if augmented.is_none() {
+        let reason = "field sources need a response schema with top-level properties and were disabled".to_string();
+        let _ = app.emit("processing:notice", reason.clone());
+        let _ = app.emit("processing:debug", reason);
+      }
+      augmented
+    }
+    (false, _) => None,
+  };
+  // _sources を足したスキーマは出典のある行（検索あり・URL のある行）にだけ使う
+  let sources_schema = sources_schema.map(Arc::new);
 
-  let success_count = Arc::new(AtomicU32::new(0));
-  let error_count = Arc::new(AtomicU32::new(0));
-  let progress = Arc::new(AtomicU32::new(0));
+  let mut ctx = RunContext::new(
+    &app,
+    total,
+    usage::price_table_with(config.pricing.as_ref()),
+    if batch_mode { usage::BATCH_PRICE_FACTOR } else { 1.0 },
+    http.stats.clone(),
+  )
+  .await;
+  ctx.null_unsupported = verify && config.null_unsupported_fields;
+  let ctx = Arc::new(ctx);
   let active_requests = Arc::new(AtomicU32::new(0));
+  let system_prompts = Arc::new(SystemPrompts::resolve(&config.system_prompts));
+
+  if batch_mode {
+    let prompts: Vec<(String, Row)> = rows
+      .into_iter()
+      .map(|row| (render_prompt(&prompt_template, &row.0), row))
+      .collect();
+    let client = BatchClient::new(http.client.clone(), keys[0].key.clone(), config.batch.base_url.clone());
+    let job = BatchJob {
+      model: llm.model().to_string(),
+      response_schema,
+      params: config.generation.structure.clone(),
+      system_prompts,
+      web_search_disabled_reason,
+      poll_interval_secs: config.batch.poll_interval_secs.unwrap_or(batch::DEFAULT_POLL_INTERVAL_SECS).max(1),
+      key_alias: pool.primary_alias().to_string(),
+      safety_settings,
+    };
+    tokio::spawn(run_batch(ctx, client, cancel, prompts, job));
+    return Ok(());
+  }
+
+  // 共有メモ（検索ありの online 実行のみ。添付があると行ごとにメモが変わるため使わない）
+  let notes_cache_key = config.notes_cache_key.clone().filter(|k| !k.trim().is_empty());
+  let notes_key_columns = notes_cache_key.as_deref().map(suggest::placeholders).unwrap_or_default();
+  let notes_cache: Option<Arc<NotesCache>> = match notes_cache_key.as_ref() {
+    Some(_) if !enable_web_search || !attachment_columns.is_empty() => {
+      let _ = app.emit("processing:notice", "notes cache requires web search and no file attachments and was disabled".to_string());
+      None
+    }
+    // 列を参照しないキー・どの行にも無い列を参照するキーは、別々のエンティティのメモを共有してしまうので受け付けない
+    Some(_) if notes_key_columns.is_empty() => {
+      return Err("notes cache key must reference at least one column ({{column}})".into());
+    }
+    Some(_) if notes_key_columns.iter().any(|c| !rows.iter().any(|r| r.0.contains_key(c))) => {
+      let unknown: Vec<&str> =
+        notes_key_columns.iter().filter(|c| !rows.iter().any(|r| r.0.contains_key(*c))).map(|c| c.as_str()).collect();
+      return Err(format!("notes cache key references unknown column(s): {}", unknown.join(", ")));
+    }
+    Some(_) => match config.notes_cache_ttl_secs {
+      Some(ttl) => {
+        let path = app.path().app_data_dir().unwrap_or(std::env::temp_dir()).join("staf").join("notes_cache.json");
+        match NotesCache::load(path, ttl).await {
+          Ok(cache) => Some(Arc::new(cache)),
+          Err(e) => {
+            let _ = app.emit("processing:notice", format!("saved notes cache could not be loaded; starting empty: {}", e));
+            Some(Arc::new(NotesCache::in_memory()))
+          }
+        }
+      }
+      None => Some(Arc::new(NotesCache::in_memory())),
+    },
+    None => None,
+  };
+  // 同じキーでもモデル・テンプレート・検索用システムプロンプトが違えばメモは共有しない
+  let notes_cache_scope = {
+    let digest = Sha256::digest(format!("{}\n{}\n{}", prompt_template, notes_cache_key.as_deref().unwrap_or_default(), system_prompts.search_system));
+    let hex: String = digest.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+    format!("{}:{}", llm.model(), hex)
+  };
+
+  let stream = config.stream && llm.supports_streaming() && samples == 1;
+  if config.stream && !llm.supports_streaming() {
+    let _ = app.emit("processing:notice", format!("streaming is not supported by provider '{}' and was disabled", llm.name()));
+  } else if config.stream && !stream {
+    // サンプルごとの途中経過が同じ行に混ざるため
+    let _ = app.emit("processing:notice", "streaming is not used with multiple samples per row and was disabled".to_string());
+  }
+
+  // 共通接頭辞をキャッシュへ登録できた場合、各行は接頭辞を除いた残りだけを送る
+  let mut context_cache_name: Option<String> = None;
+  let mut row_template = prompt_template.clone();
+  if config.context_cache && (url_fetcher.is_some() || local_tools.is_some()) {
+    let _ = app.emit("processing:notice", "context cache is not used together with URL grounding or local tools and was skipped".to_string());
+  } else if config.context_cache && pool.len() > 1 {
+    // キャッシュはキーのプロジェクトに属するため、複数キーの間では共有できない
+    let _ = app.emit("processing:notice", "context cache is not used with multiple API keys and was skipped".to_string());
+  } else if config.context_cache {
+    match split_static_prefix(&prompt_template) {
+      Some((prefix, suffix)) => {
+        let req = PrefixCacheRequest {
+          prefix: prefix.to_string(),
+          enable_web_search,
+          prompts: system_prompts.clone(),
+          ttl_secs: config.context_cache_ttl_secs.unwrap_or(DEFAULT_CONTEXT_CACHE_TTL_SECS),
+        };
+        match llm.create_prefix_cache(req).await {
+          Ok(Some(name)) => {
+            let _ = app.emit("processing:debug", format!("context cache created: {} (prefix len={})", name, prefix.len()));
+            let record = serde_json::json!({
+              "type": "context_cache",
+              "runId": ctx.run_id,
+              "timestampMs": now_ms(),
+              "name": name,
+              "prefixLength": prefix.len(),
+              "prefix": prefix,
+            });
+            if let Err(e) = append_jsonl(&ctx.log_file_path, &ctx.log_lock, record).await {
+              let _ = app.emit("processing:debug", format!("context cache: log error -> {}", e));
+            }
+            row_template = suffix.to_string();
+            context_cache_name = Some(name);
+          }
+          Ok(None) => {
+            let _ = app.emit("processing:notice", format!("context cache is not supported by provider '{}' and was skipped", llm.name()));
+          }
+          // 接頭辞がモデルの最小トークン数に満たない場合なども、キャッシュなしで続行する
+          Err(e) => {
+            let _ = app.emit("processing:notice", format!("context cache could not be created; continuing without it: {}", e));
+          }
+        }
+      }
+      None => {
+        let _ = app.emit("processing:notice", "context cache was skipped: the template has no static prefix before its first placeholder".to_string());
+      }
+    }
+  }
 
   for (idx, row) in rows.into_iter().enumerate() {
     let sem = semaphore.clone();
-    let limiter = limiter.clone();
-    let api_key = config.api_key.clone();
-    let timeout_secs = config.timeout_secs;
+    let pool = pool.clone();
+    let llm = llm.clone();
+    let timeouts = StageTimeouts {
+      search_secs: config.search_timeout_secs.unwrap_or(config.timeout_secs),
+      structure_secs: config.structure_timeout_secs.unwrap_or(config.timeout_secs),
+    };
     let app = app_clone.clone();
+    let ctx = ctx.clone();
     let cancel = cancel.clone();
-    let success_count = success_count.clone();
-    let error_count = error_count.clone();
-    let progress = progress.clone();
     let active_requests = active_requests.clone();
+    let generation = config.generation.clone();
+    let system_prompts = system_prompts.clone();
     let prompt_template = prompt_template.clone();
-    let log_file_path = log_file_path.clone();
-    let log_lock = log_lock.clone();
-    let run_id = run_id.clone();
-    let enable_web_search = enable_web_search;
+    let row_template = row_template.clone();
+    let context_cache_name = context_cache_name.clone();
+    let attachment_columns = attachment_columns.clone();
+    let url_column = url_column.clone();
+    let url_fetcher = url_fetcher.clone();
+    let local_tools = local_tools.clone();
+    let model_chain = model_chain.clone();
+    let web_search_disabled_reason = web_search_disabled_reason.clone();
     let response_schema = response_schema.clone();
+    let sources_schema = sources_schema.clone();
+    let safety_settings = safety_settings.clone();
+    let notes_cache = notes_cache.clone();
+    let shared_notes = match (notes_cache.as_ref(), notes_cache_key.as_ref()) {
+      (Some(cache), Some(expr)) => render_notes_key(expr, &notes_key_columns, &row.0).map(|key| cache.shared(&notes_cache_scope, &key)),
+      _ => None,
+    };
 
     set.spawn(async move {
       // デバッグ: タスク開始
@@ -119,162 +489,183 @@ pub async fn process_rows(app: AppHandle, rows: Vec<Row>, config: ProcessConfig)
         return;
       }
 
-      // レートリミッタ（1リクエスト分の許可が出るまで待機）
+      // API キーの割り当て（そのキーのレートリミッタで1リクエスト分の許可が出るまで待機）
       let _ = app.emit("processing:debug", format!("row {}: waiting rate limiter", idx));
-      let _ = limiter.until_ready().await;
-      let _ = app.emit("processing:debug", format!("row {}: rate limiter ready", idx));
+      let mut lease = match pool.acquire().await {
+        Ok(lease) => lease,
+        Err(e) => {
+          let meta = RowMeta { default_model: llm.model().to_string(), ..Default::default() };
+          ctx.finish_row(idx, std::time::Instant::now(), meta, Err(e)).await;
+          ctx.advance_progress(idx);
+          return;
+        }
+      };
+      let _ = app.emit("processing:debug", format!("row {}: rate limiter ready (key '{}')", idx, lease.alias));
 
-      // プロンプト生成（単純置換）
-      let prompt = render_prompt(&prompt_template, &row.0);
+      // プロンプト生成（単純置換。{{file:列名}} はファイル名に置き換える）
+      let prompt = attachments::replace_markers(&render_prompt(&prompt_template, &row.0), &attachment_columns, &row.0);
       let _ = app.emit("processing:debug", format!("row {}: prompt prepared (len={})", idx, prompt.len()));
 
+      // 添付ファイルの読み込み（ファイルが無い・大きすぎる場合はリクエストせずにこの行をエラーにする）
+      let row_attachments = match attachments::load(&attachment_columns, &row.0, max_attachment_bytes, max_total_attachment_bytes).await {
+        Ok(a) => a,
+        Err(e) => {
+          let meta = RowMeta { default_model: llm.model().to_string(), key_alias: Some(lease.alias.clone()), ..Default::default() };
+          ctx.finish_row(idx, std::time::Instant::now(), meta, Err(e)).await;
+          ctx.advance_progress(idx);
+          return;
+        }
+      };
+
       // 実際にHTTPリクエストを送信する時点でアクティブリクエスト数を増加
       let _ = app.emit("processing:debug", format!("row {}: sending request", idx));
       let current_active = active_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
       let _ = app.emit("processing:active_requests", ActiveRequestsEvent { count: current_active });
       let started = std::time::Instant::now();
 
+      // URL グラウンディング対象の URL（列が空・URL なしの行は通常の検索／単発）
+      let url_grounding = match (url_column.as_ref(), url_fetcher.as_ref()) {
+        (Some(col), Some(fetcher)) => {
+          let urls = row.0.get(col).map(|v| fetch::extract_urls(&value_to_string(v))).unwrap_or_default();
+          (!urls.is_empty()).then(|| UrlGrounding { urls, fetcher: fetcher.clone() })
+        }
+        _ => None,
+      };
+      let field_sources = sources_schema.is_some() && (enable_web_search || url_grounding.is_some());
+      let response_schema = match sources_schema.as_ref() {
+        Some(schema) if field_sources => Some(schema.as_ref().clone()),
+        _ => response_schema,
+      };
+
       // 送信前ログ（request）: Structured Response + optional google_search
       let schema_len = response_schema.as_ref().map(|s| s.to_string().len()).unwrap_or(0);
       let request_body = serde_json::json!({
+        "provider": llm.name(),
+        "model": llm.model(),
+        "fallbackModels": &model_chain[1..],
+        "generation": generation,
+        "webSearchDisabledReason": web_search_disabled_reason,
         "structuredResponse": true,
-        "tools": if enable_web_search { serde_json::json!([{ "google_search": {} }]) } else { serde_json::json!([]) },
+        "tools": if enable_web_search && url_grounding.is_none() { serde_json::json!([{ "google_search": {} }]) } else { serde_json::json!([]) },
+        "urlGrounding": url_grounding.as_ref().map(|g| g.urls.clone()),
+        "safetySettings": safety_settings,
+        "notesCacheKey": shared_notes.as_ref().map(|n| n.key.clone()),
+        "localTools": local_tools.as_ref().map(|t| t.names()),
         "hasResponseSchema": response_schema.is_some(),
         "responseSchemaLength": schema_len,
+        "contextCache": context_cache_name,
+        "attachments": row_attachments.iter().map(|a| a.log_json()).collect::<Vec<_>>(),
         "prompt": prompt,
       });
 
       let request_record = serde_json::json!({
         "type": "request",
-        "runId": run_id,
+        "runId": ctx.run_id,
         "rowIndex": idx as u32,
-        "timestampMs": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis(),
+        "timestampMs": now_ms(),
         "prompt": prompt,
         "requestBody": request_body,
+        "keyAlias": lease.alias,
         "inputRow": serde_json::Value::Object(row.0.clone()),
       });
-      if let Err(e) = append_jsonl(&log_file_path, &log_lock, request_record).await {
-        let _ = app.emit("processing:debug", format!("row {}: request log error -> {}", idx, e));
-      }
-      
-      let res = gemini::generate_events_with_search_once(
-        api_key,
-        prompt.clone(),
-        timeout_secs,
+      ctx.log(idx, "request", request_record).await;
+
+      // キャッシュ使用時は接頭辞を除いた残りだけを送る（ログには全文を残す）
+      let prefix_cached = context_cache_name.is_some();
+      let tool_log = ToolCallLog::default();
+      let send_prompt = if prefix_cached {
+        attachments::replace_markers(&render_prompt(&row_template, &row.0), &attachment_columns, &row.0)
+      } else {
+        prompt.clone()
+      };
+      // 途中経過（stage1 のメモ / 構造化の JSON 断片）をそのまま UI へ流す
+      let partial = stream.then(|| {
+        let app = app.clone();
+        PartialSink::new(move |stage, delta| {
+          let _ = app.emit("processing:row_partial", RowPartialEvent {
+            index: idx as u32,
+            stage: stage.as_str(),
+            delta: delta.to_string(),
+          });
+        })
+      });
+      let req = GenerateRequest {
         enable_web_search,
         response_schema,
-      ).await;
-
-      match res {
-        Ok(resp) => {
-          // 中間ノート・構造化入力のログを（存在する場合）先に保存
-          if let Some(notes) = resp.intermediate_notes.as_ref() {
-            let intermediate_record = serde_json::json!({
-              "type": "intermediate",
-              "runId": run_id,
-              "rowIndex": idx as u32,
-              "timestampMs": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis(),
-              "notes": notes,
-            });
-            if let Err(e) = append_jsonl(&log_file_path, &log_lock, intermediate_record).await {
-              let _ = app.emit("processing:debug", format!("row {}: intermediate log error -> {}", idx, e));
-            }
-          }
-          if let Some(s2in) = resp.stage2_input.as_ref() {
-            let stage2_input_record = serde_json::json!({
-              "type": "stage2_input",
-              "runId": run_id,
-              "rowIndex": idx as u32,
-              "timestampMs": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis(),
-              "text": s2in,
-            });
-            if let Err(e) = append_jsonl(&log_file_path, &log_lock, stage2_input_record).await {
-              let _ = app.emit("processing:debug", format!("row {}: stage2_input log error -> {}", idx, e));
-            }
-          }
-
-          let resp_text = resp.text;
-          let _ = app.emit("processing:debug", format!("row {}: response received (text_len={})", idx, resp_text.len()));
-
-          match parse_response_text(&resp_text) {
-            Ok(parsed) => {
-              success_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-              let _ = app.emit("processing:row", RowEvent {
-                index: idx as u32,
-                status: "success".into(),
-                data: Some(parsed.clone()),
-                raw: Some(resp_text.clone()),
-                error: None,
-              });
-
-              // 応答ログ（success）
-              let duration_ms = started.elapsed().as_millis() as u64;
-          let response_record = serde_json::json!({
-                "type": "response",
-                "runId": run_id,
-                "rowIndex": idx as u32,
-                "timestampMs": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis(),
-                "status": "success",
-                "durationMs": duration_ms,
-                "responseText": resp_text,
-              });
-              if let Err(e) = append_jsonl(&log_file_path, &log_lock, response_record).await {
-                let _ = app.emit("processing:debug", format!("row {}: response log error -> {}", idx, e));
-              }
-            }
-            Err(parse_err) => {
-              error_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-              let _ = app.emit("processing:row", RowEvent {
-                index: idx as u32,
-                status: "error".into(),
-                data: None,
-                raw: Some(resp_text.clone()),
-                error: Some(parse_err.clone()),
-              });
-
-              // 応答ログ（error: JSON未検出）
-              let duration_ms = started.elapsed().as_millis() as u64;
-              let response_record = serde_json::json!({
-                "type": "response",
-                "runId": run_id,
-                "rowIndex": idx as u32,
-                "timestampMs": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis(),
-                "status": "error",
-                "durationMs": duration_ms,
-                "error": parse_err,
-                "responseText": resp_text,
-              });
-              if let Err(e) = append_jsonl(&log_file_path, &log_lock, response_record).await {
-                let _ = app.emit("processing:debug", format!("row {}: response log error -> {}", idx, e));
-              }
+        params: generation,
+        prefix_cached,
+        partial,
+        url_grounding,
+        attachments: row_attachments,
+        local_tools: local_tools.map(|tools| LocalToolRun { tools, log: tool_log.clone(), max_rounds: max_tool_rounds }),
+        safety_settings,
+        // 複数サンプルでは検索メモを1回だけ作り、構造化だけを繰り返す
+        shared_notes: shared_notes.or_else(|| {
+          (samples > 1).then(|| SharedNotes { key: format!("row-{}", idx), cell: Default::default() })
+        }),
+        verify,
+        field_sources,
+        ..GenerateRequest::new(send_prompt, timeouts, system_prompts)
+      };
+      // samples > 1 なら同じリクエストを N 回生成し、フィールドごとにまとめる（サンプルごとに1リクエスト分のレート制限を受ける）
+      let emit = |event: &'static str, message: String| {
+        let _ = app.emit(event, message);
+      };
+      let generation_run = RowGeneration { emit: &emit, idx, pool: &pool, model_chain: &model_chain, full_prompt: &prompt };
+      let mut attempts: Vec<ModelAttempt> = Vec::new();
+      let mut spent = StageUsage::default();
+      let mut sample_results = Vec::with_capacity(samples as usize);
+      for n in 0..samples {
+        if n > 0 {
+          match pool.acquire().await {
+            Ok(next) => lease = next,
+            Err(e) => {
+              sample_results.push(Err(e));
+              break;
             }
           }
         }
-        Err(err) => {
-          let _ = app.emit("processing:debug", format!("row {}: request error -> {}", idx, err));
-          error_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-          let _ = app.emit("processing:row", RowEvent {
-            index: idx as u32,
-            status: "error".into(),
-            data: None,
-            raw: None,
-            error: Some(err.to_string()),
-          });
+        sample_results.push(generation_run.run(&mut lease, &req, &mut attempts, &mut spent).await);
+      }
+      let (res, consistency) = if samples > 1 {
+        ctx.log_samples(idx, &sample_results).await;
+        merge_samples(samples, sample_results)
+      } else {
+        (sample_results.pop().unwrap_or_else(|| Err(anyhow::anyhow!("no sample was generated"))), None)
+      };
 
-          // 応答ログ（error）
-          let duration_ms = started.elapsed().as_millis() as u64;
-          let response_record = serde_json::json!({
-            "type": "response",
-            "runId": run_id,
-            "rowIndex": idx as u32,
-            "timestampMs": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis(),
-            "status": "error",
-            "durationMs": duration_ms,
-            "error": err.to_string(),
-          });
-          if let Err(e) = append_jsonl(&log_file_path, &log_lock, response_record).await {
-            let _ = app.emit("processing:debug", format!("row {}: response log error -> {}", idx, e));
-          }
+      // 関数呼び出しの履歴（失敗した行でも、それまでの呼び出しを残す）
+      // 呼び出し中にパニックしたタスクがあっても、記録済みの履歴は書き出す
+      let calls = std::mem::take(&mut *tool_log.lock().unwrap_or_else(std::sync::PoisonError::into_inner));
+      for call in calls {
+        let tool_call_record = serde_json::json!({
+          "type": "tool_call",
+          "runId": ctx.run_id,
+          "rowIndex": idx as u32,
+          "timestampMs": now_ms(),
+          "round": call.round,
+          "name": call.name,
+          "args": call.args,
+          "result": call.result,
+          "error": call.error,
+          "durationMs": call.duration_ms,
+        });
+        ctx.log(idx, "tool_call", tool_call_record).await;
+      }
+
+      let meta = RowMeta {
+        default_model: attempts.last().map(|a| a.model.clone()).unwrap_or_else(|| llm.model().to_string()),
+        key_alias: Some(lease.alias.clone()),
+        attempts,
+        consistency,
+        spent: Some(spent),
+        field_sources,
+      };
+      ctx.finish_row(idx, started, meta, res).await;
+      // 中断に備えて、共有メモを一定間隔でファイルへ保存する
+      if let Some(cache) = notes_cache.as_ref() {
+        if let Err(e) = cache.checkpoint().await {
+          let _ = app.emit("processing:debug", format!("notes cache checkpoint error -> {}", e));
         }
       }
 
@@ -282,23 +673,822 @@ pub async fn process_rows(app: AppHandle, rows: Vec<Row>, config: ProcessConfig)
       let current_active = active_requests.fetch_sub(1, std::sync::atomic::Ordering::Relaxed) - 1;
       let _ = app.emit("processing:active_requests", ActiveRequestsEvent { count: current_active });
 
-      let current = progress.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
-      let _ = app.emit("processing:progress", ProgressEvent { current, total });
-      let _ = app.emit("processing:debug", format!("row {}: progress {} / {}", idx, current, total));
+      ctx.advance_progress(idx);
     });
   }
 
   // 完了待ち（キャンセルで途中停止可）
   tokio::spawn(async move {
     while let Some(_joined) = set.join_next().await {}
-    let success = success_count.load(std::sync::atomic::Ordering::Relaxed);
-    let errors = error_count.load(std::sync::atomic::Ordering::Relaxed);
-    let _ = app_clone.emit("processing:done", DoneEvent { success, errors });
+    if let Err(e) = llm.release_prefix_cache().await {
+      let _ = ctx.app.emit("processing:debug", format!("context cache delete error -> {}", e));
+    }
+    if let Some(cache) = notes_cache {
+      if let Err(e) = cache.save().await {
+        let _ = ctx.app.emit("processing:notice", format!("notes cache could not be saved: {}", e));
+      }
+    }
+    ctx.emit_done();
   });
 
   Ok(())
 }
 
+// 埋め込みモードの設定（並列数・レート制限・API キーの扱いは ProcessConfig と同じ）
+#[derive(Debug, Deserialize, Clone)]
+pub struct EmbedConfig {
+  pub api_key: String,
+  pub concurrency: usize,
+  pub rate_limit_rpm: u32,
+  pub timeout_secs: u64,
+  // 行ごとに埋め込む本文のテンプレート（{{列名}} を置換）
+  pub prompt_template: String,
+  #[serde(default)]
+  pub provider: ProviderKind,
+  #[serde(default)]
+  pub base_url: Option<String>,
+  // 埋め込みモデル（gemini は未指定で gemini-embedding-001、openai_compatible / ollama は必須）
+  #[serde(default)]
+  pub model: Option<String>,
+  // gemini の taskType（例: CLUSTERING, SEMANTIC_SIMILARITY, RETRIEVAL_DOCUMENT）
+  #[serde(default)]
+  pub task_type: Option<String>,
+  // 出力次元数（未指定はモデルの既定）
+  #[serde(default)]
+  pub dimensions: Option<u32>,
+  // 指定時はベクトルを返さず、この .npy ファイル（float32, 行数 × 次元数。失敗行は NaN）へ書き出す
+  #[serde(default)]
+  pub output_path: Option<String>,
+  #[serde(default)]
+  pub api_keys: Vec<ApiKeyEntry>,
+  #[serde(default)]
+  pub key_dispatch: DispatchStrategy,
+  #[serde(default)]
+  pub key_retire_secs: Option<u64>,
+  #[serde(default)]
+  pub http_pool: HttpPoolConfig,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EmbedResult {
+  run_id: String,
+  dimensions: usize,
+  // output_path 未指定時の行ごとのベクトル（失敗・中断した行は null）
+  vectors: Option<Vec<Option<Vec<f32>>>>,
+  output_path: Option<String>,
+  success: u32,
+  errors: u32,
+}
+
+// 行ごとにテンプレートを埋め込みベクトルへ変換する（全行の完了まで待って結果を返す）
+// 進捗・中断・JSONL ログ・processing:done は process_rows と共通
+#[tauri::command]
+pub async fn embed_rows(app: AppHandle, rows: Vec<Row>, config: EmbedConfig) -> Result<EmbedResult, String> {
+  let cancel = CancellationToken::new();
+  app.state::<CancelHolder>().0.set(cancel.clone()).ok();
+
+  let semaphore = Arc::new(Semaphore::new(config.concurrency.max(1)));
+  let total = rows.len() as u32;
+  let keys = key_entries(&config.api_key, &config.api_keys);
+  let http = SharedHttp::new(&config.http_pool.clone().with_default_idle(config.concurrency)).map_err(|e| e.to_string())?;
+  let model = match config.provider {
+    ProviderKind::Gemini => config
+      .model
+      .clone()
+      .filter(|m| !m.trim().is_empty())
+      .or_else(|| Some(gemini::DEFAULT_GEMINI_EMBEDDING_MODEL.to_string())),
+    _ => config.model.clone(),
+  };
+  let settings = ProviderSettings {
+    kind: config.provider,
+    api_key: String::new(),
+    base_url: config.base_url.clone(),
+    model,
+    http: http.clone(),
+  };
+  let pool = build_key_pool(&keys, config.rate_limit_rpm, config.key_dispatch, config.key_retire_secs, &settings)?;
+  let llm = pool.primary();
+  let ctx = Arc::new(RunContext::new(&app, total, usage::default_price_table(), 1.0, http.stats.clone()).await);
+  let vectors: Arc<std::sync::Mutex<Vec<Option<Vec<f32>>>>> = Arc::new(std::sync::Mutex::new(vec![None; rows.len()]));
+  // 次元数は最初に成功した行に合わせる（行の結果を出す前に照合し、異なる行はその場で失敗にする）
+  let expected_dimensions: Arc<OnceCell<usize>> = Arc::default();
+
+  let mut set = JoinSet::new();
+  for (idx, row) in rows.into_iter().enumerate() {
+    let sem = semaphore.clone();
+    let pool = pool.clone();
+    let llm = llm.clone();
+    let app = app.clone();
+    let ctx = ctx.clone();
+    let cancel = cancel.clone();
+    let vectors = vectors.clone();
+    let expected_dimensions = expected_dimensions.clone();
+    let prompt_template = config.prompt_template.clone();
+    let req = EmbedRequest {
+      text: String::new(),
+      timeout_secs: config.timeout_secs,
+      task_type: config.task_type.clone().filter(|t| !t.trim().is_empty()),
+      dimensions: config.dimensions,
+    };
+
+    set.spawn(async move {
+      let _permit = match sem.acquire().await {
+        Ok(permit) => permit,
+        Err(_) => return,
+      };
+      if cancel.is_cancelled() {
+        return;
+      }
+      let mut lease = match pool.acquire().await {
+        Ok(lease) => lease,
+        Err(e) => {
+          ctx.finish_embedding(idx, std::time::Instant::now(), llm.model(), None, Err(e)).await;
+          ctx.advance_progress(idx);
+          return;
+        }
+      };
+
+      let text = render_prompt(&prompt_template, &row.0);
+      let request_record = serde_json::json!({
+        "type": "request",
+        "runId": ctx.run_id,
+        "rowIndex": idx as u32,
+        "timestampMs": now_ms(),
+        "prompt": text,
+        "requestBody": {
+          "provider": llm.name(),
+          "mode": "embedding",
+          "model": llm.model(),
+          "taskType": req.task_type,
+          "dimensions": req.dimensions,
+          "prompt": text,
+        },
+        "keyAlias": lease.alias,
+        "inputRow": serde_json::Value::Object(row.0.clone()),
+      });
+      ctx.log(idx, "request", request_record).await;
+
+      let started = std::time::Instant::now();
+      let req = EmbedRequest { text, ..req };
+      // quota / 認証エラーのキーは退避させ、別のキーで再試行する（キーの本数まで）
+      let mut attempts = 1;
+      let res = loop {
+        let res = lease.provider.embed(req.clone()).await;
+        let Err(err) = &res else {
+          break res;
+        };
+        let Some(notice) = pool.report_failure(&lease, err) else {
+          break res;
+        };
+        let _ = app.emit("processing:notice", notice.clone());
+        if attempts >= pool.len() {
+          break res;
+        }
+        match pool.acquire().await {
+          Ok(next) => {
+            lease = next;
+            attempts += 1;
+          }
+          Err(_) => break res,
+        }
+      };
+      let res = check_dimensions(res, &expected_dimensions);
+      if let Some(vector) = ctx.finish_embedding(idx, started, llm.model(), Some(&lease.alias), res).await {
+        vectors.lock().unwrap()[idx] = Some(vector);
+      }
+      ctx.advance_progress(idx);
+    });
+  }
+  while let Some(_joined) = set.join_next().await {}
+
+  let vectors = std::mem::take(&mut *vectors.lock().unwrap());
+  let dimensions = expected_dimensions.get().copied().unwrap_or(0);
+  let output_path = config.output_path.clone().filter(|p| !p.trim().is_empty());
+  let written = match output_path.as_ref() {
+    Some(path) => npy::write_f32_matrix(std::path::Path::new(path), &vectors, dimensions).await,
+    None => Ok(()),
+  };
+  // 書き出しに失敗しても processing:done は送る（UI が完了待ちのままにならないように）
+  ctx.emit_done();
+  written.map_err(|e| e.to_string())?;
+  Ok(EmbedResult {
+    run_id: ctx.run_id.clone(),
+    dimensions,
+    vectors: if output_path.is_some() { None } else { Some(vectors) },
+    output_path,
+    success: ctx.success_count.load(std::sync::atomic::Ordering::Relaxed),
+    errors: ctx.error_count.load(std::sync::atomic::Ordering::Relaxed),
+  })
+}
+
+// 埋め込みの次元数を最初に成功した行の次元数と照合する（空のベクトル・異なる次元数はエラーにする）
+fn check_dimensions(res: Result<EmbedResponse>, expected: &OnceCell<usize>) -> Result<EmbedResponse> {
+  let resp = res?;
+  if resp.vector.is_empty() {
+    return Err(anyhow::anyhow!("embedding response contained an empty vector"));
+  }
+  let expected = *expected.get_or_init(|| resp.vector.len());
+  if resp.vector.len() != expected {
+    return Err(anyhow::anyhow!("embedding dimension mismatch: expected {}, got {}", expected, resp.vector.len()));
+  }
+  Ok(resp)
+}
+
+// バッチ実行の設定（行ごとのプロンプト以外）
+struct BatchJob {
+  model: String,
+  response_schema: Option<serde_json::Value>,
+  params: GenerationParams,
+  system_prompts: Arc<SystemPrompts>,
+  web_search_disabled_reason: Option<String>,
+  poll_interval_secs: u64,
+  key_alias: String,
+  safety_settings: Vec<SafetySetting>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct BatchEvent {
+  name: String,
+  state: String,
+}
+
+// Batch API で全行をまとめて処理する。結果は通常と同じ processing:row / processing:done で通知する
+async fn run_batch(ctx: Arc<RunContext>, client: BatchClient, cancel: CancellationToken, prompts: Vec<(String, Row)>, job: BatchJob) {
+  let app = ctx.app.clone();
+  let started = std::time::Instant::now();
+
+  // 送信前ログ（request）と入力 JSONL の作成
+  let mut jsonl = String::new();
+  for (idx, (prompt, row)) in prompts.iter().enumerate() {
+    let line = batch::build_request_line(idx, prompt, &job.system_prompts, job.response_schema.as_ref(), &job.params, &job.safety_settings);
+    jsonl.push_str(&line.to_string());
+    jsonl.push('\n');
+    let request_record = serde_json::json!({
+      "type": "request",
+      "runId": ctx.run_id,
+      "rowIndex": idx as u32,
+      "timestampMs": now_ms(),
+      "prompt": prompt,
+      "requestBody": {
+        "provider": "gemini",
+        "executionMode": "batch",
+        "model": job.model,
+        "generation": { "structure": job.params },
+        "webSearchDisabledReason": job.web_search_disabled_reason,
+        "structuredResponse": true,
+        "batchKey": batch::row_key(idx),
+        "safetySettings": job.safety_settings,
+        "hasResponseSchema": job.response_schema.is_some(),
+        "prompt": prompt,
+      },
+      "inputRow": serde_json::Value::Object(row.0.clone()),
+    });
+    ctx.log(idx, "request", request_record).await;
+  }
+
+  let outcome: Result<Vec<(usize, Result<GenerateResponse>)>> = async {
+    let display_name = format!("staf-run-{}", ctx.run_id);
+    let file_name = client.upload_jsonl(&display_name, jsonl.into_bytes()).await?;
+    let _ = app.emit("processing:debug", format!("batch: input uploaded as {}", file_name));
+    let mut status = client.create(&job.model, &display_name, &file_name).await?;
+    ctx.log_batch_status(&status).await;
+
+    // 状態ポーリング（キャンセル時はバッチも取り消す）
+    while !status.is_terminal() {
+      tokio::select! {
+        _ = cancel.cancelled() => {
+          if let Err(e) = client.cancel(&status.name).await {
+            let _ = app.emit("processing:debug", format!("batch: cancel error -> {}", e));
+          }
+          return Err(anyhow::anyhow!("batch {} was aborted", status.name));
+        }
+        _ = tokio::time::sleep(std::time::Duration::from_secs(job.poll_interval_secs)) => {}
+      }
+      let next = client.get(&status.name).await?;
+      if next.state != status.state {
+        ctx.log_batch_status(&next).await;
+      }
+      status = next;
+    }
+
+    if !status.is_succeeded() {
+      return Err(anyhow::anyhow!(
+        "batch {} finished with {}{}",
+        status.name,
+        status.state,
+        status.error.as_ref().map(|e| format!(": {}", e)).unwrap_or_default()
+      ));
+    }
+    let responses_file = status
+      .responses_file
+      .clone()
+      .ok_or_else(|| anyhow::anyhow!("batch {} succeeded without a responses file", status.name))?;
+    let output = client.download(&responses_file).await?;
+    Ok(batch::parse_results(&output))
+  }
+  .await;
+
+  // 結果を行番号へ戻す（結果が無い行・バッチ全体の失敗はその行のエラーとして扱う）
+  let mut results: Vec<Option<Result<GenerateResponse>>> = (0..prompts.len()).map(|_| None).collect();
+  let batch_error = match outcome {
+    Ok(parsed) => {
+      for (idx, res) in parsed {
+        if let Some(slot) = results.get_mut(idx) {
+          *slot = Some(res);
+        }
+      }
+      None
+    }
+    Err(e) => {
+      let _ = app.emit("processing:debug", format!("batch: error -> {}", e));
+      Some(e.to_string())
+    }
+  };
+  for (idx, res) in results.into_iter().enumerate() {
+    let res = res.unwrap_or_else(|| match &batch_error {
+      Some(e) => Err(anyhow::anyhow!(e.clone())),
+      None => Err(anyhow::anyhow!("no result for this row in batch output")),
+    });
+    let meta = RowMeta { default_model: job.model.clone(), key_alias: Some(job.key_alias.clone()), ..Default::default() };
+    ctx.finish_row(idx, started, meta, res).await;
+    ctx.advance_progress(idx);
+  }
+  ctx.emit_done();
+}
+
+// 1回の実行で行タスク間に共有する状態（ログ出力・件数・使用量の集計）
+struct RunContext {
+  app: AppHandle,
+  run_id: String,
+  log_file_path: PathBuf,
+  // ログ追記用の簡易ロック
+  log_lock: tokio::sync::Mutex<()>,
+  total: u32,
+  success_count: AtomicU32,
+  error_count: AtomicU32,
+  timeout_count: AtomicU32,
+  progress: AtomicU32,
+  usage_totals: UsageTotals,
+  price_table: HashMap<String, ModelPrice>,
+  // 単価表に掛ける係数（Batch API の割引など）
+  price_factor: f64,
+  http_stats: Arc<HttpStats>,
+  // 共有メモを再利用した（stage1 の検索を省いた）行数
+  notes_cache_hits: AtomicU32,
+  // 検証で unsupported と判定されたフィールドを null にする
+  null_unsupported: bool,
+}
+
+impl RunContext {
+  // Run ID と JSONL ログのパス（AppData 配下の staf/logs/run-<id>.jsonl）を用意する
+  async fn new(
+    app: &AppHandle,
+    total: u32,
+    price_table: HashMap<String, ModelPrice>,
+    price_factor: f64,
+    http_stats: Arc<HttpStats>,
+  ) -> Self {
+    let run_id = {
+      // エポックミリ秒 + 下位4桁の16進を付与した簡易ID（外部クレート不使用）
+      let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+      let millis = now.as_millis();
+      let suffix = (millis as u64) & 0xFFFF;
+      format!("{}-{:04x}", millis, suffix)
+    };
+
+    // AppData 配下に logs ディレクトリを用意（例: %AppData%/staf/logs）
+    let logs_path: PathBuf = {
+      // tauri 2 のパスリゾルバ（取れない場合は temp_dir ）
+      let base = app
+        .path()
+        .app_data_dir()
+        .unwrap_or(std::env::temp_dir())
+        .join("staf")
+        .join("logs");
+      if let Err(e) = tokio::fs::create_dir_all(&base).await {
+        let _ = app.emit("processing:debug", format!("log dir create error: {}", e));
+      }
+      base
+    };
+
+    let log_file_path = logs_path.join(format!("run-{}.jsonl", run_id));
+
+    Self {
+      app: app.clone(),
+      run_id,
+      log_file_path,
+      log_lock: tokio::sync::Mutex::new(()),
+      total,
+      success_count: AtomicU32::new(0),
+      error_count: AtomicU32::new(0),
+      timeout_count: AtomicU32::new(0),
+      progress: AtomicU32::new(0),
+      usage_totals: UsageTotals::default(),
+      price_table,
+      price_factor,
+      http_stats,
+      notes_cache_hits: AtomicU32::new(0),
+      null_unsupported: false,
+    }
+  }
+
+  // JSONL へ追記し、失敗はデバッグイベントで通知する
+  async fn log(&self, idx: usize, label: &str, record: serde_json::Value) {
+    if let Err(e) = append_jsonl(&self.log_file_path, &self.log_lock, record).await {
+      let _ = self.app.emit("processing:debug", format!("row {}: {} log error -> {}", idx, label, e));
+    }
+  }
+
+  // 1行分の生成結果を解釈し、processing:row イベント・response ログ・集計へ反映する
+  async fn finish_row(&self, idx: usize, started: std::time::Instant, meta: RowMeta, res: Result<GenerateResponse>) {
+    let RowMeta { default_model, key_alias, attempts, consistency, spent, field_sources } = meta;
+    let (default_model, key_alias, attempts) = (default_model.as_str(), key_alias.as_deref(), attempts.as_slice());
+    let app = &self.app;
+    let run_id = &self.run_id;
+    match res {
+      Ok(resp) => {
+        // 中間ノート・構造化入力のログを（存在する場合）先に保存
+        if let Some(notes) = resp.intermediate_notes.as_ref() {
+          let intermediate_record = serde_json::json!({
+            "type": "intermediate",
+            "runId": run_id,
+            "rowIndex": idx as u32,
+            "timestampMs": now_ms(),
+            "notes": notes,
+          });
+          self.log(idx, "intermediate", intermediate_record).await;
+        }
+        if let Some(s2in) = resp.stage2_input.as_ref() {
+          let stage2_input_record = serde_json::json!({
+            "type": "stage2_input",
+            "runId": run_id,
+            "rowIndex": idx as u32,
+            "timestampMs": now_ms(),
+            "text": s2in,
+          });
+          self.log(idx, "stage2_input", stage2_input_record).await;
+        }
+
+        let resp_text = resp.text;
+        let grounding_metadata = resp.grounding_metadata;
+        let notes_cache_hit = resp.notes_cache_hit;
+        let verification = resp.verification;
+        let verification_error = resp.verification_error;
+        if let Some(e) = verification_error.as_ref() {
+          let _ = app.emit("processing:debug", format!("row {}: verification failed and was skipped: {}", idx, e));
+        }
+        if notes_cache_hit == Some(true) {
+          self.notes_cache_hits.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        // トークン使用量と概算コスト（パース失敗時も消費済みとして計上。失敗した試行・サンプルの分も含む）
+        let row_usage = spent.or(resp.usage);
+        let model = resp.model.or_else(|| Some(default_model.to_string()));
+        let row_total = row_usage.as_ref().map(|u| u.total()).unwrap_or_default();
+        let estimated_cost_usd = model
+          .as_deref()
+          .and_then(|m| usage::estimate_cost(&self.price_table, m, &row_total))
+          .map(|c| c * self.price_factor);
+        let cache_savings = model
+          .as_deref()
+          .and_then(|m| usage::estimate_cache_savings(&self.price_table, m, &row_total))
+          .map(|c| c * self.price_factor);
+        self.usage_totals.record(&row_total, estimated_cost_usd, cache_savings);
+        let _ = app.emit("processing:debug", format!("row {}: response received (text_len={})", idx, resp_text.len()));
+
+        match parse_response_text(&resp_text) {
+          Ok(mut parsed) => {
+            self.success_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            // null にする前に取り出す（_sources 自体は出力のフィールドではない）
+            let field_sources = field_sources.then(|| sources::take_sources(&mut parsed, grounding_metadata.as_ref()));
+            let nulled_fields = match verification.as_ref() {
+              Some(verdicts) if self.null_unsupported => verify::null_unsupported(&mut parsed, verdicts),
+              _ => Vec::new(),
+            };
+            let _ = app.emit("processing:row", RowEvent {
+              index: idx as u32,
+              status: "success".into(),
+              data: Some(parsed.clone()),
+              raw: Some(resp_text.clone()),
+              error: None,
+              error_code: None,
+              grounding_metadata: grounding_metadata.clone(),
+              usage: row_usage.clone(),
+              model: model.clone(),
+              estimated_cost_usd,
+              attempts: attempts.to_vec(),
+              consistency: consistency.clone(),
+              verification: verification.clone(),
+              verification_error: verification_error.clone(),
+              sources: field_sources.as_ref().map(|s| s.sources.clone()),
+            });
+
+            // 応答ログ（success）
+            let duration_ms = started.elapsed().as_millis() as u64;
+            let response_record = serde_json::json!({
+              "type": "response",
+              "runId": run_id,
+              "rowIndex": idx as u32,
+              "timestampMs": now_ms(),
+              "status": "success",
+              "durationMs": duration_ms,
+              "responseText": resp_text,
+              "groundingMetadata": grounding_metadata,
+              "usage": row_usage,
+              "model": model,
+              "estimatedCostUsd": estimated_cost_usd,
+              "keyAlias": key_alias,
+              "attempts": attempts,
+              "notesCacheHit": notes_cache_hit,
+              "consistency": consistency,
+              "verification": verification,
+              "verificationError": verification_error,
+              "nulledFields": nulled_fields,
+              "fieldSources": field_sources.as_ref().map(|s| &s.sources),
+              "rejectedSources": field_sources.as_ref().map(|s| &s.rejected),
+            });
+            self.log(idx, "response", response_record).await;
+          }
+          Err(parse_err) => {
+            self.error_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let _ = app.emit("processing:row", RowEvent {
+              index: idx as u32,
+              status: "error".into(),
+              data: None,
+              raw: Some(resp_text.clone()),
+              error: Some(parse_err.clone()),
+              error_code: Some(INVALID_JSON_ERROR_CODE),
+              grounding_metadata: grounding_metadata.clone(),
+              usage: row_usage.clone(),
+              model: model.clone(),
+              estimated_cost_usd,
+              attempts: attempts.to_vec(),
+              consistency: consistency.clone(),
+              verification: verification.clone(),
+              verification_error: verification_error.clone(),
+              sources: None,
+            });
+
+            // 応答ログ（error: JSON未検出）
+            let duration_ms = started.elapsed().as_millis() as u64;
+            let response_record = serde_json::json!({
+              "type": "response",
+              "runId": run_id,
+              "rowIndex": idx as u32,
+              "timestampMs": now_ms(),
+              "status": "error",
+              "durationMs": duration_ms,
+              "error": parse_err,
+              "errorCode": INVALID_JSON_ERROR_CODE,
+              "responseText": resp_text,
+              "groundingMetadata": grounding_metadata,
+              "usage": row_usage,
+              "model": model,
+              "estimatedCostUsd": estimated_cost_usd,
+              "keyAlias": key_alias,
+              "attempts": attempts,
+              "notesCacheHit": notes_cache_hit,
+              "consistency": consistency,
+              // success と同じ形に揃える（JSON として読めないため null にしたフィールド・出典は無い）
+              "verification": verification,
+              "verificationError": verification_error,
+              "nulledFields": Vec::<String>::new(),
+              "fieldSources": serde_json::Value::Null,
+              "rejectedSources": serde_json::Value::Null,
+            });
+            self.log(idx, "response", response_record).await;
+          }
+        }
+      }
+      Err(err) => {
+        let _ = app.emit("processing:debug", format!("row {}: request error -> {}", idx, err));
+        self.error_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // タイムアウトは通常のエラーと区別して報告する
+        let timeout_stage = match err.downcast_ref::<GenerateError>() {
+          Some(GenerateError::Timeout { stage, .. }) => Some(stage.as_str()),
+          _ => None,
+        };
+        if timeout_stage.is_some() {
+          self.timeout_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        let status = if timeout_stage.is_some() { "timeout" } else { "error" };
+        // 安全性フィルタ・出力上限などで止まった行を絞り込んで再実行できるよう、種類をコードで残す
+        let error_code = provider::error_code(&err);
+        // 失敗した行でも、完了していた段の使用量は消費済みとして計上する（単価は最後に試したモデル）
+        let row_usage = spent.filter(|u| u.total().total_tokens > 0);
+        let mut estimated_cost_usd = None;
+        if let Some(row_total) = row_usage.as_ref().map(|u| u.total()) {
+          estimated_cost_usd = usage::estimate_cost(&self.price_table, default_model, &row_total).map(|c| c * self.price_factor);
+          let cache_savings = usage::estimate_cache_savings(&self.price_table, default_model, &row_total).map(|c| c * self.price_factor);
+          self.usage_totals.record(&row_total, estimated_cost_usd, cache_savings);
+        }
+        let _ = app.emit("processing:row", RowEvent {
+          index: idx as u32,
+          status: status.into(),
+          data: None,
+          raw: None,
+          error: Some(err.to_string()),
+          error_code,
+          grounding_metadata: None,
+          usage: row_usage.clone(),
+          // 最後に試したモデル（試行がない場合は None）
+          model: attempts.last().map(|a| a.model.clone()),
+          estimated_cost_usd,
+          attempts: attempts.to_vec(),
+          consistency: None,
+          verification: None,
+          verification_error: None,
+          sources: None,
+        });
+
+        // 応答ログ（error / timeout）
+        let duration_ms = started.elapsed().as_millis() as u64;
+        let response_record = serde_json::json!({
+          "type": "response",
+          "runId": run_id,
+          "rowIndex": idx as u32,
+          "timestampMs": now_ms(),
+          "status": status,
+          "durationMs": duration_ms,
+          "error": err.to_string(),
+          "errorCode": error_code,
+          "timeoutStage": timeout_stage,
+          "usage": row_usage,
+          "estimatedCostUsd": estimated_cost_usd,
+          "model": attempts.last().map(|a| a.model.clone()),
+          "keyAlias": key_alias,
+          "attempts": attempts,
+        });
+        self.log(idx, "response", response_record).await;
+      }
+    }
+  }
+
+  // 1行分の埋め込み結果を processing:embedding_row イベント・response ログ・集計へ反映し、成功時はベクトルを返す
+  // （ベクトル本体は大きいため JSONL には次元数のみ残す）
+  async fn finish_embedding(
+    &self,
+    idx: usize,
+    started: std::time::Instant,
+    default_model: &str,
+    key_alias: Option<&str>,
+    res: Result<EmbedResponse>,
+  ) -> Option<Vec<f32>> {
+    let duration_ms = started.elapsed().as_millis() as u64;
+    match res {
+      Ok(resp) => {
+        self.success_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let model = resp.model.unwrap_or_else(|| default_model.to_string());
+        let row_usage = resp.usage.unwrap_or_default();
+        let estimated_cost_usd = usage::estimate_cost(&self.price_table, &model, &row_usage);
+        self.usage_totals.record(&row_usage, estimated_cost_usd, None);
+        let _ = self.app.emit("processing:embedding_row", EmbeddingRowEvent {
+          index: idx as u32,
+          status: "success",
+          dimensions: resp.vector.len(),
+          error: None,
+          error_code: None,
+        });
+        let response_record = serde_json::json!({
+          "type": "response",
+          "runId": self.run_id,
+          "rowIndex": idx as u32,
+          "timestampMs": now_ms(),
+          "status": "success",
+          "durationMs": duration_ms,
+          "dimensions": resp.vector.len(),
+          "usage": resp.usage,
+          "model": model,
+          "keyAlias": key_alias,
+        });
+        self.log(idx, "response", response_record).await;
+        Some(resp.vector)
+      }
+      Err(err) => {
+        let _ = self.app.emit("processing:debug", format!("row {}: embedding error -> {}", idx, err));
+        self.error_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let timed_out = matches!(err.downcast_ref::<GenerateError>(), Some(GenerateError::Timeout { .. }));
+        if timed_out {
+          self.timeout_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        let status = if timed_out { "timeout" } else { "error" };
+        let error_code = provider::error_code(&err);
+        let _ = self.app.emit("processing:embedding_row", EmbeddingRowEvent {
+          index: idx as u32,
+          status,
+          dimensions: 0,
+          error: Some(err.to_string()),
+          error_code,
+        });
+        let response_record = serde_json::json!({
+          "type": "response",
+          "runId": self.run_id,
+          "rowIndex": idx as u32,
+          "timestampMs": now_ms(),
+          "status": status,
+          "durationMs": duration_ms,
+          "error": err.to_string(),
+          "errorCode": error_code,
+          "model": default_model,
+          "keyAlias": key_alias,
+        });
+        self.log(idx, "response", response_record).await;
+        None
+      }
+    }
+  }
+
+  // self-consistency の各サンプルを JSONL（type: sample）に残す
+  async fn log_samples(&self, idx: usize, results: &[Result<GenerateResponse>]) {
+    for (n, res) in results.iter().enumerate() {
+      let record = serde_json::json!({
+        "type": "sample",
+        "runId": self.run_id,
+        "rowIndex": idx as u32,
+        "timestampMs": now_ms(),
+        "sampleIndex": n as u32,
+        "status": if res.is_ok() { "success" } else { "error" },
+        "responseText": res.as_ref().ok().map(|r| r.text.clone()),
+        "error": res.as_ref().err().map(|e| e.to_string()),
+        "usage": res.as_ref().ok().and_then(|r| r.usage.clone()),
+        "model": res.as_ref().ok().and_then(|r| r.model.clone()),
+      });
+      self.log(idx, "sample", record).await;
+    }
+  }
+
+  // バッチの状態遷移をイベントと JSONL（type: batch）に残す
+  async fn log_batch_status(&self, status: &batch::BatchStatus) {
+    let _ = self.app.emit("processing:batch", BatchEvent { name: status.name.clone(), state: status.state.clone() });
+    let record = serde_json::json!({
+      "type": "batch",
+      "runId": self.run_id,
+      "timestampMs": now_ms(),
+      "batchName": status.name,
+      "state": status.state,
+      "error": status.error,
+    });
+    if let Err(e) = append_jsonl(&self.log_file_path, &self.log_lock, record).await {
+      let _ = self.app.emit("processing:debug", format!("batch: log error -> {}", e));
+    }
+  }
+
+  fn advance_progress(&self, idx: usize) {
+    let current = self.progress.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+    let _ = self.app.emit("processing:progress", ProgressEvent { current, total: self.total });
+    let _ = self.app.emit("processing:debug", format!("row {}: progress {} / {}", idx, current, self.total));
+  }
+
+  fn emit_done(&self) {
+    let success = self.success_count.load(std::sync::atomic::Ordering::Relaxed);
+    let errors = self.error_count.load(std::sync::atomic::Ordering::Relaxed);
+    let timeouts = self.timeout_count.load(std::sync::atomic::Ordering::Relaxed);
+    let summary = self.usage_totals.snapshot();
+    let _ = self.app.emit("processing:done", DoneEvent {
+      success,
+      errors,
+      timeouts,
+      usage: summary.usage,
+      estimated_cost_usd: summary.estimated_cost_usd,
+      unpriced_rows: summary.unpriced_rows,
+      cache_hit_rows: summary.cache_hit_rows,
+      estimated_cache_savings_usd: summary.estimated_cache_savings_usd,
+      http: self.http_stats.snapshot(),
+      notes_cache_hits: self.notes_cache_hits.load(std::sync::atomic::Ordering::Relaxed),
+    });
+  }
+}
+
+fn now_ms() -> u128 {
+  std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+// API キーの一覧（api_keys 未指定なら api_key 1本を "default" として使う）
+pub(crate) fn key_entries(api_key: &str, api_keys: &[ApiKeyEntry]) -> Vec<ApiKeyEntry> {
+  if api_keys.is_empty() {
+    vec![ApiKeyEntry { alias: "default".into(), key: api_key.to_string(), rate_limit_rpm: None }]
+  } else {
+    api_keys.to_vec()
+  }
+}
+
+// キーごとに settings（api_key 以外）でバックエンドを作り、プールにまとめる
+fn build_key_pool(
+  keys: &[ApiKeyEntry],
+  rate_limit_rpm: u32,
+  dispatch: DispatchStrategy,
+  retire_secs: Option<u64>,
+  settings: &ProviderSettings,
+) -> Result<Arc<KeyPool>, String> {
+  let pool = KeyPool::new(keys, rate_limit_rpm, dispatch, retire_secs.unwrap_or(key_pool::DEFAULT_RETIRE_SECS), |key| {
+    provider::build_provider(&ProviderSettings { api_key: key.to_string(), ..settings.clone() })
+  })
+  .map_err(|e| e.to_string())?;
+  Ok(Arc::new(pool))
+}
+
 #[tauri::command]
 pub async fn abort_processing(app: AppHandle) -> Result<(), String> {
   if let Some(token) = app.state::<CancelHolder>().0.get() {
@@ -326,15 +1516,229 @@ struct RowEvent {
   data: Option<serde_json::Value>,
   raw: Option<String>,
   error: Option<String>,
+  // safety | recitation | max_tokens | finish_other | timeout | quota | auth | server | invalid_json（分類できないエラーは None）
+  error_code: Option<&'static str>,
+  // 検索グラウンディング時の出典（webSearchQueries / groundingChunks / groundingSupports）
+  grounding_metadata: Option<serde_json::Value>,
+  // 段階別トークン使用量と、応答モデルの単価表による概算コスト
+  usage: Option<StageUsage>,
+  model: Option<String>,
+  estimated_cost_usd: Option<f64>,
+  // 試したモデルの履歴（成功した最後の要素が model。バッチ実行では空）
+  attempts: Vec<ModelAttempt>,
+  // samples > 1 の場合のサンプル数とフィールドごとの一致率（data はまとめた値）
+  consistency: Option<Consistency>,
+  // verify 時のフィールドごとの判定（supported / unsupported / uncertain）
+  verification: Option<Vec<FieldVerdict>>,
+  // 検証段が失敗した場合の理由（data は検証なしの構造化結果）
+  verification_error: Option<String>,
+  // field_sources 時のフィールドごとの出典 URL（stage1 の出典にあったもののみ）
+  sources: Option<std::collections::BTreeMap<String, Vec<String>>>,
+}
+
+// finish_row に渡す行の付帯情報
+#[derive(Debug, Default)]
+struct RowMeta {
+  // 応答にモデル名が無い場合に使う（最後に試したモデル）
+  default_model: String,
+  // その行を処理した API キーの別名（キー本体は記録しない）
+  key_alias: Option<String>,
+  // 試したモデルとキーの履歴（代替モデルへ切り替えた行・全モデルで失敗した行の確認用）
+  attempts: Vec<ModelAttempt>,
+  consistency: Option<Consistency>,
+  // この行で消費した使用量（失敗した試行・サンプルで完了していた段も含む。None なら応答の usage）
+  spent: Option<StageUsage>,
+  // 応答の _sources を取り出し、stage1 の出典にある URL だけを残す（_sources を求めた行のみ）
+  field_sources: bool,
+}
+
+// 1行分の生成（キー・代替モデルでの再試行を含む）に必要な共有情報
+// emit: processing:notice / processing:debug の送信（イベント名, 本文）
+struct RowGeneration<'a> {
+  emit: &'a (dyn Fn(&'static str, String) + Sync),
+  idx: usize,
+  pool: &'a Arc<KeyPool>,
+  model_chain: &'a [String],
+  // 代替モデルに送る全文（キャッシュは主モデル専用のため）
+  full_prompt: &'a str,
+}
+
+impl RowGeneration<'_> {
+  // 各キーで主モデル → 代替モデルの順に試し、quota / サーバーエラーなら次のモデルへ進む
+  // それでも失敗し、quota / 認証エラーならそのキーを退避させ、別のキーで主モデルからやり直す（キーの本数まで）
+  // spent には成功した試行の使用量と、失敗した試行で完了していた段の使用量を加える
+  async fn run(
+    &self,
+    lease: &mut KeyLease,
+    req: &GenerateRequest,
+    attempts: &mut Vec<ModelAttempt>,
+    spent: &mut StageUsage,
+  ) -> Result<GenerateResponse> {
+    let (emit, idx, pool, model_chain) = (self.emit, self.idx, self.pool, self.model_chain);
+    let mut key_attempts = 1;
+    loop {
+      let mut model_idx = 0;
+      let res = loop {
+        let mut model_req = req.clone();
+        if model_idx > 0 {
+          model_req.model = Some(model_chain[model_idx].clone());
+          model_req.prefix_cached = false;
+          model_req.prompt = self.full_prompt.to_string();
+        }
+        let sink = UsageSink::default();
+        model_req.spent = Some(sink.clone());
+        let res = lease.provider.generate_structured(model_req).await;
+        match &res {
+          Ok(resp) => spent.add(&resp.usage.clone().unwrap_or_default()),
+          Err(_) => spent.add(&sink.take()),
+        }
+        attempts.push(ModelAttempt {
+          model: model_chain[model_idx].clone(),
+          key_alias: lease.alias.clone(),
+          error: res.as_ref().err().map(|e| e.to_string()),
+        });
+        let Err(err) = &res else {
+          break res;
+        };
+        if model_idx + 1 >= model_chain.len() || !matches!(classify_error(err), ErrorClass::Quota | ErrorClass::Server) {
+          break res;
+        }
+        model_idx += 1;
+        emit(
+          "processing:debug",
+          format!("row {}: {} failed, retrying with fallback model {} -> {}", idx, model_chain[model_idx - 1], model_chain[model_idx], err),
+        );
+      };
+      let Err(err) = &res else {
+        return res;
+      };
+      let Some(notice) = pool.report_failure(lease, err) else {
+        return res;
+      };
+      emit("processing:notice", notice.clone());
+      emit("processing:debug", format!("row {}: {}", idx, notice));
+      if key_attempts >= pool.len() {
+        return res;
+      }
+      match pool.acquire().await {
+        Ok(next) => {
+          *lease = next;
+          key_attempts += 1;
+        }
+        Err(_) => return res,
+      }
+    }
+  }
+}
+
+// 複数サンプルを1つの応答にまとめる（本文・出典は最初に成功したサンプル、使用量は全サンプルの合計）
+// JSON として解釈できたサンプルが無ければ最初のサンプルの本文のまま返し、finish_row で解析エラーになる
+fn merge_samples(samples: u32, results: Vec<Result<GenerateResponse>>) -> (Result<GenerateResponse>, Option<Consistency>) {
+  let mut parsed = Vec::new();
+  // 検証を行ったサンプルの値と判定（まとめた値の判定を選ぶのに使う）
+  let mut checked: Vec<(serde_json::Value, Vec<FieldVerdict>)> = Vec::new();
+  let mut usage = StageUsage::default();
+  let mut base: Option<GenerateResponse> = None;
+  let mut first_err = None;
+  let mut verification_error = None;
+  for res in results {
+    match res {
+      Ok(resp) => {
+        if let Some(u) = resp.usage.as_ref() {
+          usage.add(u);
+        }
+        if verification_error.is_none() {
+          verification_error = resp.verification_error.clone();
+        }
+        if let Ok(value) = parse_response_text(&resp.text) {
+          if let Some(verdicts) = resp.verification.as_ref() {
+            checked.push((value.clone(), verdicts.clone()));
+          }
+          parsed.push(value);
+        }
+        base.get_or_insert(resp);
+      }
+      Err(e) => {
+        first_err.get_or_insert(e);
+      }
+    }
+  }
+  let Some(mut base) = base else {
+    return (Err(first_err.unwrap_or_else(|| anyhow::anyhow!("no sample was generated"))), None);
+  };
+  base.usage = Some(usage);
+  let mut agreement = Default::default();
+  if let Some((merged, ratios)) = consensus::merge(&parsed) {
+    base.verification = (!checked.is_empty()).then(|| verify::for_merged(&merged, &checked));
+    base.text = merged.to_string();
+    agreement = ratios;
+  }
+  // 検証できたサンプルが1つも無いときだけ、検証段の失敗理由を残す
+  base.verification_error = if base.verification.is_some() { None } else { verification_error };
+  (Ok(base), Some(Consistency { samples, valid_samples: parsed.len() as u32, agreement }))
+}
+
+// 1回の生成の試行（モデル・キーと失敗時のエラー）
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ModelAttempt {
+  model: String,
+  key_alias: String,
+  error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct EmbeddingRowEvent {
+  index: u32,
+  status: &'static str,
+  dimensions: usize,
+  error: Option<String>,
+  error_code: Option<&'static str>,
+}
+
+// 応答から JSON を取り出せなかった行の error_code
+const INVALID_JSON_ERROR_CODE: &str = "invalid_json";
+
+// ストリーミング中の増分（同じ index・stage の delta を連結すると、その段の出力全文になる）
+#[derive(Debug, Serialize, Clone)]
+struct RowPartialEvent {
+  index: u32,
+  // "search"（stage1 メモ）| "structure"（構造化 JSON）
+  stage: &'static str,
+  delta: String,
 }
 
 #[derive(Debug, Serialize, Clone)]
 struct DoneEvent {
   success: u32,
+  // timeouts を含むエラー件数
   errors: u32,
+  timeouts: u32,
+  // 実行全体のトークン使用量と概算コスト（unpriced_rows 行は単価不明のため費用に含まれない）
+  usage: TokenUsage,
+  estimated_cost_usd: f64,
+  unpriced_rows: u32,
+  // コンテキストキャッシュが効いた行数と概算節約額（キャッシュ読み出しトークン数は usage.cached_tokens）
+  cache_hit_rows: u32,
+  estimated_cache_savings_usd: f64,
+  // バックエンドへのリクエスト数と平均 TTFB（レスポンスヘッダーが届くまで）
+  http: HttpSummary,
+  // 共有メモを再利用して stage1 の検索を省いた行数
+  notes_cache_hits: u32,
+}
+
+// テンプレートを最初の {{ の前後で「全行共通の接頭辞」と「行ごとの残り」に分ける
+// 接頭辞が空白のみ、またはプレースホルダーがない場合は None
+fn split_static_prefix(template: &str) -> Option<(&str, &str)> {
+  let pos = template.find("{{")?;
+  let (prefix, suffix) = template.split_at(pos);
+  if prefix.trim().is_empty() {
+    return None;
+  }
+  Some((prefix, suffix))
 }
 
-fn render_prompt(template: &str, row: &serde_json::Map<String, serde_json::Value>) -> String {
+pub(crate) fn render_prompt(template: &str, row: &serde_json::Map<String, serde_json::Value>) -> String {
   let mut out = template.to_string();
   for (k, v) in row.iter() {
     let needle = format!("{{{{{}}}}}", k);
@@ -343,7 +1747,15 @@ fn render_prompt(template: &str, row: &serde_json::Map<String, serde_json::Value
   out
 }
 
-fn value_to_string(v: &serde_json::Value) -> String {
+// 共有メモのキー式を行の値で展開する（キーの列が無い・空の行は共有しない）
+pub(crate) fn render_notes_key(expr: &str, columns: &[String], row: &serde_json::Map<String, serde_json::Value>) -> Option<String> {
+  let resolved = columns.iter().all(|c| row.get(c).is_some_and(|v| !v.is_null() && !value_to_string(v).trim().is_empty()));
+  let key = render_prompt(expr, row);
+  let key = key.trim();
+  (resolved && !key.is_empty()).then(|| key.to_string())
+}
+
+pub(crate) fn value_to_string(v: &serde_json::Value) -> String {
   match v {
     serde_json::Value::String(s) => s.clone(),
     _ => v.to_string(),
@@ -374,6 +1786,232 @@ async fn append_jsonl(path: &PathBuf, lock: &tokio::sync::Mutex<()>, value: serd
 #[derive(Default)]
 pub struct CancelHolder(pub OnceCell<CancellationToken>);
 
+// URL グラウンディングのページ取得の差し替え（setup で登録する。未登録なら HttpPageFetcher）
+#[derive(Default)]
+pub struct PageFetcherHolder(pub OnceCell<Arc<dyn PageFetcher>>);
+
 
 // 既定スキーマはフロントエンド側で生成し、ここでは使用しない
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::{BoxFuture, LlmProvider, TextRequest};
+  use std::sync::Mutex;
+
+  type Respond = fn(&str, &str) -> Result<GenerateResponse>;
+  // (キー, モデル, プロンプト)
+  type Calls = Arc<Mutex<Vec<(String, String, String)>>>;
+
+  // キーとモデルごとに決めた応答を返すバックエンド（呼び出しを calls に記録する）
+  struct FakeProvider {
+    key: String,
+    respond: Respond,
+    calls: Calls,
+  }
+
+  impl LlmProvider for FakeProvider {
+    fn name(&self) -> &'static str {
+      "fake"
+    }
+
+    fn model(&self) -> &str {
+      "primary"
+    }
+
+    fn generate_structured(&self, req: GenerateRequest) -> BoxFuture<'_, Result<GenerateResponse>> {
+      let model = req.model.clone().unwrap_or_else(|| "primary".to_string());
+      self.calls.lock().unwrap().push((self.key.clone(), model.clone(), req.prompt.clone()));
+      let res = (self.respond)(&self.key, &model);
+      // 失敗した呼び出しは、検索段まで完了してから失敗したものとして使用量を残す
+      if let (Err(_), Some(sink)) = (&res, req.spent.as_ref()) {
+        sink.add(&StageUsage { search: Some(TokenUsage { prompt_tokens: 3, total_tokens: 3, ..Default::default() }), ..Default::default() });
+      }
+      Box::pin(async move { res })
+    }
+
+    fn generate_text(&self, _req: TextRequest) -> BoxFuture<'_, Result<GenerateResponse>> {
+      Box::pin(async { Err(anyhow::anyhow!("not used")) })
+    }
+  }
+
+  fn fake_pool(keys: &[&str], respond: Respond, calls: &Calls) -> Arc<KeyPool> {
+    let entries: Vec<ApiKeyEntry> =
+      keys.iter().map(|k| ApiKeyEntry { alias: k.to_string(), key: k.to_string(), rate_limit_rpm: None }).collect();
+    let pool = KeyPool::new(&entries, 6000, DispatchStrategy::RoundRobin, 60, |key| {
+      Ok(Arc::new(FakeProvider { key: key.to_string(), respond, calls: calls.clone() }) as Arc<dyn provider::LlmProvider>)
+    });
+    Arc::new(pool.unwrap())
+  }
+
+  fn ok(text: &str) -> Result<GenerateResponse> {
+    let usage = TokenUsage { prompt_tokens: 10, candidates_tokens: 5, total_tokens: 15, ..Default::default() };
+    Ok(GenerateResponse {
+      text: text.to_string(),
+      usage: Some(StageUsage { structure: Some(usage), ..Default::default() }),
+      ..Default::default()
+    })
+  }
+
+  fn api_error(code: u16) -> Result<GenerateResponse> {
+    Err(anyhow::anyhow!("bad response from server; code {}; description: failed", code))
+  }
+
+  // pool から1行分を生成し、(結果, 試行, 送られたイベント, 消費した使用量) を返す
+  async fn run_row(
+    pool: &Arc<KeyPool>,
+    model_chain: &[String],
+    req: &GenerateRequest,
+  ) -> (Result<GenerateResponse>, Vec<ModelAttempt>, Vec<(&'static str, String)>, StageUsage) {
+    let events = Mutex::new(Vec::new());
+    let emit = |event: &'static str, message: String| events.lock().unwrap().push((event, message));
+    let generation = RowGeneration { emit: &emit, idx: 0, pool, model_chain, full_prompt: "full prompt" };
+    let mut lease = pool.acquire().await.unwrap();
+    let mut attempts = Vec::new();
+    let mut spent = StageUsage::default();
+    let res = generation.run(&mut lease, req, &mut attempts, &mut spent).await;
+    (res, attempts, events.into_inner().unwrap(), spent)
+  }
+
+  fn request() -> GenerateRequest {
+    GenerateRequest {
+      prefix_cached: true,
+      ..GenerateRequest::new("rest of prompt".into(), StageTimeouts::uniform(10), Arc::new(SystemPrompts::defaults("en")))
+    }
+  }
+
+  fn chain() -> Vec<String> {
+    vec!["primary".to_string(), "backup".to_string()]
+  }
+
+  #[tokio::test]
+  async fn server_error_falls_back_to_next_model_with_full_prompt() {
+    let calls = Calls::default();
+    let pool = fake_pool(&["a"], |_, model| if model == "primary" { api_error(503) } else { ok(r#"{"x":1}"#) }, &calls);
+    let (res, attempts, events, spent) = run_row(&pool, &chain(), &request()).await;
+
+    assert_eq!(res.unwrap().text, r#"{"x":1}"#);
+    // 失敗した主モデルで完了していた段の使用量も、成功した代替モデルの分と一緒に数える
+    assert_eq!(spent.search.map(|u| u.total_tokens), Some(3));
+    assert_eq!(spent.total().total_tokens, 18);
+    let tried: Vec<(&str, bool)> = attempts.iter().map(|a| (a.model.as_str(), a.error.is_some())).collect();
+    assert_eq!(tried, vec![("primary", true), ("backup", false)]);
+    // キャッシュは主モデル専用のため、代替モデルには全文を送る
+    let calls = calls.lock().unwrap();
+    assert_eq!(calls[0].2, "rest of prompt");
+    assert_eq!(calls[1].2, "full prompt");
+    assert!(events.iter().any(|(e, m)| *e == "processing:debug" && m.contains("fallback model backup")));
+  }
+
+  #[tokio::test]
+  async fn non_retryable_error_does_not_fall_back() {
+    let calls = Calls::default();
+    let pool = fake_pool(&["a"], |_, _| Err(anyhow::anyhow!("schema rejected")), &calls);
+    let (res, attempts, events, _) = run_row(&pool, &chain(), &request()).await;
+
+    assert!(res.is_err());
+    assert_eq!(attempts.len(), 1);
+    assert!(events.is_empty());
+  }
+
+  #[tokio::test]
+  async fn quota_error_retires_key_and_retries_on_next_key() {
+    let calls = Calls::default();
+    let pool = fake_pool(&["a", "b"], |key, _| if key == "a" { api_error(429) } else { ok(r#"{"x":1}"#) }, &calls);
+    let (res, attempts, events, _) = run_row(&pool, &chain(), &request()).await;
+
+    assert!(res.is_ok());
+    let tried: Vec<(&str, &str)> = attempts.iter().map(|a| (a.key_alias.as_str(), a.model.as_str())).collect();
+    // quota は代替モデルも試してからキーを切り替え、次のキーでは主モデルからやり直す
+    assert_eq!(tried, vec![("a", "primary"), ("a", "backup"), ("b", "primary")]);
+    assert!(events.iter().any(|(e, m)| *e == "processing:notice" && m.contains("API key 'a' hit its quota")));
+    // 退避したキーは以降の行に割り当てられない
+    assert_eq!(pool.acquire().await.unwrap().alias, "b");
+    assert_eq!(pool.acquire().await.unwrap().alias, "b");
+  }
+
+  #[tokio::test]
+  async fn auth_errors_on_every_key_fail_the_row() {
+    let calls = Calls::default();
+    let pool = fake_pool(&["a", "b"], |_, _| api_error(401), &calls);
+    let (res, attempts, events, _) = run_row(&pool, &chain(), &request()).await;
+
+    assert!(res.unwrap_err().to_string().contains("code 401"));
+    let tried: Vec<(&str, &str)> = attempts.iter().map(|a| (a.key_alias.as_str(), a.model.as_str())).collect();
+    assert_eq!(tried, vec![("a", "primary"), ("b", "primary")]);
+    assert_eq!(events.iter().filter(|(e, _)| *e == "processing:notice").count(), 2);
+    assert!(pool.acquire().await.is_err());
+  }
+
+  #[test]
+  fn merge_samples_votes_per_field_and_sums_usage() {
+    let results = vec![
+      ok(r#"{"price":10,"label":"a"}"#),
+      Err(anyhow::anyhow!("timed out")),
+      ok(r#"{"price":30,"label":"a"}"#),
+      ok("not json"),
+      ok(r#"{"price":20,"label":"b"}"#),
+    ];
+    let (res, consistency) = merge_samples(5, results);
+    let resp = res.unwrap();
+
+    assert_eq!(parse_response_text(&resp.text).unwrap(), serde_json::json!({ "price": 20, "label": "a" }));
+    // 使用量は応答のあった全サンプルの合計（JSON として読めなかったサンプルも含む）
+    assert_eq!(resp.usage.unwrap().structure.unwrap().prompt_tokens, 40);
+    let consistency = consistency.unwrap();
+    assert_eq!((consistency.samples, consistency.valid_samples), (5, 3));
+    assert_eq!(consistency.agreement["price"], 1.0 / 3.0);
+    assert_eq!(consistency.agreement["label"], 2.0 / 3.0);
+  }
+
+  #[test]
+  fn merge_samples_keeps_the_output_when_verification_failed() {
+    let failed = |text: &str| ok(text).map(|r| GenerateResponse { verification_error: Some("stage verify timed out".into()), ..r });
+    let (res, _) = merge_samples(2, vec![failed(r#"{"a":1}"#), failed(r#"{"a":1}"#)]);
+    let resp = res.unwrap();
+    assert_eq!(parse_response_text(&resp.text).unwrap(), serde_json::json!({ "a": 1 }));
+    assert!(resp.verification.is_none());
+    assert_eq!(resp.verification_error.as_deref(), Some("stage verify timed out"));
+
+    // 検証できたサンプルがあれば、その判定を使い失敗理由は残さない
+    let verdict = FieldVerdict { field: "a".into(), verdict: verify::Verdict::Supported, reason: None };
+    let checked = ok(r#"{"a":1}"#).map(|r| GenerateResponse { verification: Some(vec![verdict]), ..r });
+    let (res, _) = merge_samples(2, vec![failed(r#"{"a":1}"#), checked]);
+    let resp = res.unwrap();
+    assert_eq!(resp.verification.map(|v| v.len()), Some(1));
+    assert!(resp.verification_error.is_none());
+  }
+
+  #[test]
+  fn merge_samples_without_any_success_returns_first_error() {
+    let (res, consistency) = merge_samples(2, vec![Err(anyhow::anyhow!("first")), Err(anyhow::anyhow!("second"))]);
+    assert_eq!(res.unwrap_err().to_string(), "first");
+    assert!(consistency.is_none());
+  }
+
+  #[test]
+  fn embedding_dimensions_follow_the_first_successful_row() {
+    let embedded = |n: usize| Ok(EmbedResponse { vector: vec![0.5; n], ..Default::default() });
+    let expected = OnceCell::new();
+    assert!(check_dimensions(Err(anyhow::anyhow!("quota")), &expected).is_err());
+    assert!(check_dimensions(embedded(0), &expected).is_err());
+    assert_eq!(expected.get(), None);
+    assert_eq!(check_dimensions(embedded(3), &expected).unwrap().vector.len(), 3);
+    let err = check_dimensions(embedded(4), &expected).unwrap_err();
+    assert_eq!(err.to_string(), "embedding dimension mismatch: expected 3, got 4");
+    assert!(check_dimensions(embedded(3), &expected).is_ok());
+  }
+
+  #[test]
+  fn notes_key_is_only_rendered_when_every_key_column_has_a_value() {
+    let columns = suggest::placeholders("{{company}} / {{country}}");
+    let row = |v: serde_json::Value| v.as_object().unwrap().clone();
+    let key = |r: serde_json::Value| render_notes_key("{{company}} / {{country}}", &columns, &row(r));
+    assert_eq!(key(serde_json::json!({ "company": "Acme", "country": "JP", "other": 1 })).as_deref(), Some("Acme / JP"));
+    assert_eq!(key(serde_json::json!({ "company": "Acme", "country": 81 })).as_deref(), Some("Acme / 81"));
+    assert_eq!(key(serde_json::json!({ "company": "Acme" })), None);
+    assert_eq!(key(serde_json::json!({ "company": "Acme", "country": "  " })), None);
+    assert_eq!(key(serde_json::json!({ "company": "Acme", "country": null })), None);
+  }
+}
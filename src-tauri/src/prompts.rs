@@ -0,0 +1,239 @@
+use crate::fetch::FetchedPage;
+use serde::{Deserialize, Serialize};
+
+// 2段階パイプライン（検索メモ収集 → 構造化）と単発構造化で使うシステムプロンプト群
+// テンプレートごとに言語と個別の上書きを指定できる
+
+// stage2 入力のメモ差し込み位置
+pub const NOTES_PLACEHOLDER: &str = "{{notes}}";
+
+// 検証段の入力の構造化結果差し込み位置
+pub const OUTPUT_PLACEHOLDER: &str = "{{output}}";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SystemPrompts {
+  // stage1 のシステムプロンプト
+  pub search_system: String,
+  // stage1 でユーザープロンプトの後ろに付ける収集指示
+  pub search_instruction: String,
+  // stage2 のシステムプロンプト
+  pub structure_system: String,
+  // stage2 のユーザーメッセージ（{{notes}} にメモを差し込む）
+  pub structure_input: String,
+  // 検索なし単発構造化のシステムプロンプト
+  pub single_system: String,
+  // URL グラウンディング時の stage1 システムプロンプト（検索の代わりに取得済みページ本文からメモを作る）
+  pub url_system: String,
+  // URL グラウンディング時にユーザープロンプトとページ本文の間に入れる収集指示
+  pub url_instruction: String,
+  // ローカルツール段のシステムプロンプト
+  pub tool_system: String,
+  // ローカルツール段でユーザープロンプト（と検索メモ）の間に入れる指示
+  pub tool_instruction: String,
+  // 検証段（stage3）のシステムプロンプト
+  pub verify_system: String,
+  // 検証段のユーザーメッセージ（{{notes}} にメモ、{{output}} に構造化結果を差し込む）
+  pub verify_input: String,
+  // 出典の対応（_sources）を求める場合に stage2 の入力の後ろへ付ける指示（続けて stage1 の出典 URL を並べる）
+  pub sources_instruction: String,
+}
+
+// ProcessConfig から受け取る指定（language: "ja" | "en" | "de"、未指定は ja）
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SystemPromptConfig {
+  #[serde(default)]
+  pub language: Option<String>,
+  #[serde(default)]
+  pub search_system: Option<String>,
+  #[serde(default)]
+  pub search_instruction: Option<String>,
+  #[serde(default)]
+  pub structure_system: Option<String>,
+  #[serde(default)]
+  pub structure_input: Option<String>,
+  #[serde(default)]
+  pub single_system: Option<String>,
+  #[serde(default)]
+  pub url_system: Option<String>,
+  #[serde(default)]
+  pub url_instruction: Option<String>,
+  #[serde(default)]
+  pub tool_system: Option<String>,
+  #[serde(default)]
+  pub tool_instruction: Option<String>,
+  #[serde(default)]
+  pub verify_system: Option<String>,
+  #[serde(default)]
+  pub verify_input: Option<String>,
+  #[serde(default)]
+  pub sources_instruction: Option<String>,
+}
+
+impl SystemPrompts {
+  pub fn defaults(language: &str) -> Self {
+    match language {
+      "en" => Self {
+        search_system: "Follow the requirements of the given task, gather evidence with the Google Search tool, and summarize only the facts you could confirm as bullet points. You may include URLs or source names.".into(),
+        search_instruction: "Following the instructions above, collect facts, prioritizing reliable sources. Output the result concisely as bullet-point notes.".into(),
+        structure_system: "Based only on the content of the given notes, return JSON that strictly follows the specified schema. Use null for unknown or uncertain values.".into(),
+        structure_input: "Structure the following notes into JSON according to the specified schema.\n\n--- Notes ---\n{{notes}}\n----------------\n".into(),
+        single_system: "Strictly follow the specified schema and return JSON only. Use null for unknown or uncertain values.".into(),
+        url_system: "Using only the text of the given web pages as evidence, summarize the facts relevant to the task requirements as bullet points. Do not guess anything the pages do not state. You may include URLs as sources.".into(),
+        url_instruction: "Following the instructions above, collect facts from the page contents below. Output the result concisely as bullet-point notes.".into(),
+        tool_system: "Use the available functions to look up the reference data needed for the given task, and summarize only the facts confirmed by the function results as bullet points.".into(),
+        tool_instruction: "Following the instructions above, call functions as needed to check the reference data. If notes gathered by search follow, take them into account as well. Output the result concisely as bullet-point notes.".into(),
+        verify_system: "Check each top-level field of the given JSON against the notes. Answer supported if the notes back the value, unsupported if the notes do not mention it or contradict it, and uncertain if the notes are not enough to decide. Fields whose value is null are supported.".into(),
+        verify_input: "Check each field of the JSON below against the notes.\n\n--- Notes ---\n{{notes}}\n----------------\n\n--- JSON ---\n{{output}}\n----------------\n".into(),
+        sources_instruction: "In _sources, list for each field the URLs from the sources below that back its value. Copy the URLs exactly as written and do not use any URL that is not listed. Use an empty array for fields without a source.\n\n--- Sources ---".into(),
+      },
+      "de" => Self {
+        search_system: "Befolge die Anforderungen der gegebenen Aufgabe, sammle Belege mit dem Google-Suchwerkzeug und fasse nur die bestätigten Fakten als Stichpunkte zusammen. URLs oder Quellennamen dürfen enthalten sein.".into(),
+        search_instruction: "Sammle gemäß den obigen Anweisungen Fakten und bevorzuge dabei zuverlässige Quellen. Gib das Ergebnis knapp als Stichpunkt-Notizen aus.".into(),
+        structure_system: "Gib ausschließlich auf Grundlage der gegebenen Notizen JSON zurück, das dem angegebenen Schema strikt folgt. Verwende null für unbekannte oder unsichere Werte.".into(),
+        structure_input: "Strukturiere die folgenden Notizen gemäß dem angegebenen Schema als JSON.\n\n--- Notizen ---\n{{notes}}\n----------------\n".into(),
+        single_system: "Folge strikt dem angegebenen Schema und gib ausschließlich JSON zurück. Verwende null für unbekannte oder unsichere Werte.".into(),
+        url_system: "Fasse ausschließlich auf Grundlage des Textes der gegebenen Webseiten die für die Aufgabe relevanten Fakten als Stichpunkte zusammen. Errate nichts, was nicht auf den Seiten steht. URLs dürfen als Quellen angegeben werden.".into(),
+        url_instruction: "Sammle gemäß den obigen Anweisungen Fakten aus den folgenden Seiteninhalten. Gib das Ergebnis knapp als Stichpunkt-Notizen aus.".into(),
+        tool_system: "Nutze die verfügbaren Funktionen, um die für die Aufgabe benötigten Referenzdaten nachzuschlagen, und fasse nur die durch die Funktionsergebnisse bestätigten Fakten als Stichpunkte zusammen.".into(),
+        tool_instruction: "Rufe gemäß den obigen Anweisungen bei Bedarf Funktionen auf, um die Referenzdaten zu prüfen. Falls unten Notizen aus der Suche folgen, berücksichtige sie ebenfalls. Gib das Ergebnis knapp als Stichpunkt-Notizen aus.".into(),
+        verify_system: "Prüfe jedes Feld der obersten Ebene des gegebenen JSON anhand der Notizen. Antworte mit supported, wenn die Notizen den Wert belegen, mit unsupported, wenn die Notizen ihn nicht erwähnen oder ihm widersprechen, und mit uncertain, wenn die Notizen für eine Entscheidung nicht ausreichen. Felder mit dem Wert null gelten als supported.".into(),
+        verify_input: "Prüfe jedes Feld des folgenden JSON anhand der Notizen.\n\n--- Notizen ---\n{{notes}}\n----------------\n\n--- JSON ---\n{{output}}\n----------------\n".into(),
+        sources_instruction: "Gib in _sources für jedes Feld die URLs aus den folgenden Quellen an, die seinen Wert belegen. Übernimm die URLs exakt und verwende keine URL, die nicht aufgeführt ist. Verwende ein leeres Array für Felder ohne Quelle.\n\n--- Quellen ---".into(),
+      },
+      _ => Self {
+        search_system: "与えられたタスクの要件に従い、Google検索ツールで根拠を収集し、確認できた事実のみを箇条書きで要約してください。URLや出典名を含めても構いません。".into(),
+        search_instruction: "上の指示に従い、信頼できる情報源を優先して事実を収集してください。結果は箇条書きのメモとして簡潔に出力してください。".into(),
+        structure_system: "与えられたメモの内容だけに基づき、指定されたスキーマに厳密に従うJSONを返してください。未知や不確実な値はnullを使用してください。".into(),
+        structure_input: "以下のメモを、指定のスキーマに従ってJSONへ構造化してください。\n\n--- メモ ---\n{{notes}}\n----------------\n".into(),
+        single_system: "指定されたスキーマに厳密に従い、JSONのみを返してください。未知や不確実な値はnullを使用してください。".into(),
+        url_system: "与えられたWebページの本文だけを根拠に、タスクの要件に関係する事実を箇条書きで要約してください。ページに書かれていないことは推測しないでください。出典としてURLを含めても構いません。".into(),
+        url_instruction: "上の指示に従い、以下のページ本文から事実を収集してください。結果は箇条書きのメモとして簡潔に出力してください。".into(),
+        tool_system: "与えられたタスクに必要な参照データを、利用可能な関数で調べてください。関数の結果で確認できた事実のみを箇条書きで要約してください。".into(),
+        tool_instruction: "上の指示に従い、必要に応じて関数を呼び出して参照データを確認してください。以下に検索で得たメモがある場合は、その内容も踏まえてください。結果は箇条書きのメモとして簡潔に出力してください。".into(),
+        verify_system: "与えられたJSONのトップレベルの各フィールドを、メモの内容と照合してください。メモに根拠があれば supported、メモに記載が無いかメモと矛盾すれば unsupported、メモだけでは判断できなければ uncertain としてください。値が null のフィールドは supported としてください。".into(),
+        verify_input: "以下のJSONの各フィールドを、メモと照合してください。\n\n--- メモ ---\n{{notes}}\n----------------\n\n--- JSON ---\n{{output}}\n----------------\n".into(),
+        sources_instruction: "_sources には、各フィールドの値の根拠となるURLを以下の出典から選んで列挙してください。URLは記載どおりに写し、記載の無いURLは使わないでください。根拠の無いフィールドは空の配列にしてください。\n\n--- 出典 ---".into(),
+      },
+    }
+  }
+
+  // 言語の既定値に個別指定（空文字は無視）を上書きする
+  pub fn resolve(config: &SystemPromptConfig) -> Self {
+    let mut p = Self::defaults(config.language.as_deref().unwrap_or("ja"));
+    let pick = |dst: &mut String, src: &Option<String>| {
+      if let Some(v) = src.as_ref().filter(|v| !v.trim().is_empty()) {
+        *dst = v.clone();
+      }
+    };
+    pick(&mut p.search_system, &config.search_system);
+    pick(&mut p.search_instruction, &config.search_instruction);
+    pick(&mut p.structure_system, &config.structure_system);
+    pick(&mut p.structure_input, &config.structure_input);
+    pick(&mut p.single_system, &config.single_system);
+    pick(&mut p.url_system, &config.url_system);
+    pick(&mut p.url_instruction, &config.url_instruction);
+    pick(&mut p.tool_system, &config.tool_system);
+    pick(&mut p.tool_instruction, &config.tool_instruction);
+    pick(&mut p.verify_system, &config.verify_system);
+    pick(&mut p.verify_input, &config.verify_input);
+    pick(&mut p.sources_instruction, &config.sources_instruction);
+    p
+  }
+
+  // stage1 に送るユーザーメッセージ
+  pub fn render_search_input(&self, prompt: &str) -> String {
+    format!("{}\n\n{}", prompt, self.search_instruction)
+  }
+
+  // URL グラウンディング時の stage1 ユーザーメッセージ（取得できなかったページはその旨を残す）
+  pub fn render_url_input(&self, prompt: &str, pages: &[(String, Result<FetchedPage, String>)]) -> String {
+    let mut out = format!("{}\n\n{}\n", prompt, self.url_instruction);
+    for (url, page) in pages {
+      match page {
+        Ok(p) => {
+          let title = p.title.as_deref().map(|t| format!(" ({})", t)).unwrap_or_default();
+          let truncated = if p.truncated { "\n[...]" } else { "" };
+          out.push_str(&format!("\n--- {}{} ---\n{}{}\n", url, title, p.text, truncated));
+        }
+        Err(e) => out.push_str(&format!("\n--- {} ---\n(fetch failed: {})\n", url, e)),
+      }
+    }
+    out
+  }
+
+  // ローカルツール段のユーザーメッセージ（検索メモがあれば末尾に付ける）
+  pub fn render_tool_input(&self, prompt: &str, notes: Option<&str>) -> String {
+    match notes {
+      Some(n) => format!("{}\n\n{}\n\n{}", prompt, self.tool_instruction, n),
+      None => format!("{}\n\n{}", prompt, self.tool_instruction),
+    }
+  }
+
+  // stage2 に送るユーザーメッセージ（{{notes}} がなければ末尾に付ける）
+  pub fn render_structure_input(&self, notes: &str) -> String {
+    if self.structure_input.contains(NOTES_PLACEHOLDER) {
+      self.structure_input.replace(NOTES_PLACEHOLDER, notes)
+    } else {
+      format!("{}\n\n{}", self.structure_input, notes)
+    }
+  }
+
+  // stage2 の入力の後ろに付ける出典の一覧（URL とタイトル）
+  pub fn render_sources_input(&self, urls: &[(String, Option<String>)]) -> String {
+    let mut out = format!("\n\n{}\n", self.sources_instruction);
+    for (url, title) in urls {
+      let title = title.as_deref().map(|t| format!(" ({})", t)).unwrap_or_default();
+      out.push_str(&format!("- {}{}\n", url, title));
+    }
+    out
+  }
+
+  // 検証段に送るユーザーメッセージ（差し込み位置が無ければ末尾に付ける）
+  pub fn render_verify_input(&self, notes: &str, output: &str) -> String {
+    let mut text = self.verify_input.clone();
+    if !text.contains(NOTES_PLACEHOLDER) {
+      text.push_str("\n\n{{notes}}");
+    }
+    if !text.contains(OUTPUT_PLACEHOLDER) {
+      text.push_str("\n\n{{output}}");
+    }
+    // 1回の走査で差し込む（メモ・構造化結果の中に {{notes}} / {{output}} があってもそのまま残す）
+    let mut out = String::with_capacity(text.len() + notes.len() + output.len());
+    let mut rest = text.as_str();
+    loop {
+      let next = [(NOTES_PLACEHOLDER, notes), (OUTPUT_PLACEHOLDER, output)]
+        .into_iter()
+        .filter_map(|(marker, value)| rest.find(marker).map(|at| (at, marker, value)))
+        .min_by_key(|(at, _, _)| *at);
+      let Some((at, marker, value)) = next else {
+        out.push_str(rest);
+        return out;
+      };
+      out.push_str(&rest[..at]);
+      out.push_str(value);
+      rest = &rest[at + marker.len()..];
+    }
+  }
+}
+
+impl Default for SystemPrompts {
+  fn default() -> Self {
+    Self::defaults("ja")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn verify_input_substitutes_each_placeholder_once() {
+    let prompts = SystemPrompts { verify_input: "N: {{notes}}\nO: {{output}}\nN again: {{notes}}".into(), ..Default::default() };
+    let text = prompts.render_verify_input("see {{output}}", r#"{"a":"{{notes}}"}"#);
+    assert_eq!(text, "N: see {{output}}\nO: {\"a\":\"{{notes}}\"}\nN again: see {{output}}");
+
+    // 差し込み位置が無ければ末尾にメモ・構造化結果の順で付ける
+    let prompts = SystemPrompts { verify_input: "check".into(), ..Default::default() };
+    assert_eq!(prompts.render_verify_input("notes", "{}"), "check\n\nnotes\n\n{}");
+  }
+}
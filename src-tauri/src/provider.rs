@@ -0,0 +1,700 @@
+use crate::attachments::Attachment;
+use crate::fetch::PageFetcher;
+use crate::http_pool::SharedHttp;
+use crate::notes_cache::SharedNotes;
+use crate::tools::LocalToolRun;
+use crate::gemini::{GeminiProvider, GenerateResponse};
+use crate::ollama::{OllamaProvider, DEFAULT_OLLAMA_BASE_URL};
+use crate::openai::OpenAiCompatProvider;
+use crate::prompts::SystemPrompts;
+use crate::usage::{StageUsage, TokenUsage};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+  fmt,
+  future::Future,
+  pin::Pin,
+  sync::{Arc, Mutex, PoisonError},
+  time::Duration,
+};
+
+// LLM バックエンドの抽象化。
+// processor は行ループの中でこのトレイトだけを呼び出し、具体的な SDK / HTTP 実装には依存しない。
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// 生成の段階（タイムアウト・エラー報告の単位）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+  // 検索・メモ収集
+  Search,
+  // スキーマ指定の構造化
+  Structure,
+  // ツールなしの自由テキスト
+  Text,
+  // ローカルツール（function calling）
+  Tools,
+  // 埋め込みベクトル
+  Embed,
+  // 構造化結果をメモと照合する検証（stage3）
+  Verify,
+  // 入力トークン数の計測（生成は行わない）
+  CountTokens,
+}
+
+impl Stage {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Stage::Search => "search",
+      Stage::Structure => "structure",
+      Stage::Text => "text",
+      Stage::Tools => "tools",
+      Stage::Embed => "embed",
+      Stage::Verify => "verify",
+      Stage::CountTokens => "count_tokens",
+    }
+  }
+}
+
+impl fmt::Display for Stage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+// バックエンド共通の型付きエラー（anyhow::Error から downcast して判定する）
+#[derive(Debug, thiserror::Error)]
+pub enum GenerateError {
+  #[error("{stage} stage timed out after {secs}s")]
+  Timeout { stage: Stage, secs: u64 },
+  // 安全性フィルタ・引用・出力上限などで応答が打ち切られた（detail は API の finishReason / blockReason）
+  #[error("{stage} stage stopped: {detail}")]
+  Blocked { stage: Stage, reason: FinishReason, detail: String },
+}
+
+impl GenerateError {
+  // RowEvent / JSONL の error_code
+  pub fn code(&self) -> &'static str {
+    match self {
+      GenerateError::Timeout { .. } => "timeout",
+      GenerateError::Blocked { reason, .. } => reason.code(),
+    }
+  }
+}
+
+// 正常終了（STOP）以外の終了理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+  Safety,
+  Recitation,
+  MaxTokens,
+  Other,
+}
+
+impl FinishReason {
+  // Gemini の finishReason / blockReason。正常終了・未指定は None
+  pub fn from_gemini(reason: &str) -> Option<Self> {
+    match reason {
+      "" | "STOP" | "FINISH_REASON_UNSPECIFIED" => None,
+      "SAFETY" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" | "IMAGE_SAFETY" => Some(FinishReason::Safety),
+      "RECITATION" => Some(FinishReason::Recitation),
+      "MAX_TOKENS" => Some(FinishReason::MaxTokens),
+      _ => Some(FinishReason::Other),
+    }
+  }
+
+  pub fn code(&self) -> &'static str {
+    match self {
+      FinishReason::Safety => "safety",
+      FinishReason::Recitation => "recitation",
+      FinishReason::MaxTokens => "max_tokens",
+      FinishReason::Other => "finish_other",
+    }
+  }
+}
+
+// API エラーの分類（キーの退避判定などに使う）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+  // 429 / RESOURCE_EXHAUSTED
+  Quota,
+  // 401 / 403 / API キー不正
+  Auth,
+  // 500 / 503 などサーバー側の一時的な失敗
+  Server,
+  Other,
+}
+
+// "bad response from server; code 429; ..." 形式のメッセージやステータス名から分類する
+pub fn classify_error(err: &anyhow::Error) -> ErrorClass {
+  if err.downcast_ref::<GenerateError>().is_some() {
+    return ErrorClass::Other;
+  }
+  let msg = err.to_string();
+  let has_code = |code: u16| msg.contains(&format!("code {}", code)) || msg.contains(&format!("\"code\": {}", code));
+  if has_code(429) || msg.contains("RESOURCE_EXHAUSTED") {
+    ErrorClass::Quota
+  } else if has_code(401) || has_code(403) || msg.contains("API_KEY_INVALID") || msg.contains("PERMISSION_DENIED") || msg.contains("UNAUTHENTICATED") {
+    ErrorClass::Auth
+  } else if has_code(500) || has_code(502) || has_code(503) || has_code(504) || msg.contains("UNAVAILABLE") {
+    ErrorClass::Server
+  } else {
+    ErrorClass::Other
+  }
+}
+
+// 行のエラーを絞り込み・再実行用のコードにする（型付きエラーは種類ごと、API エラーは分類ごと。該当なしは None）
+pub fn error_code(err: &anyhow::Error) -> Option<&'static str> {
+  if let Some(e) = err.downcast_ref::<GenerateError>() {
+    return Some(e.code());
+  }
+  match classify_error(err) {
+    ErrorClass::Quota => Some("quota"),
+    ErrorClass::Auth => Some("auth"),
+    ErrorClass::Server => Some("server"),
+    ErrorClass::Other => None,
+  }
+}
+
+// 安全性フィルタのしきい値（gemini のみ）
+// category: HARASSMENT | HATE_SPEECH | SEXUALLY_EXPLICIT | DANGEROUS_CONTENT（HARM_CATEGORY_ 接頭辞・小文字も可）
+// threshold: BLOCK_NONE | BLOCK_ONLY_HIGH | BLOCK_MEDIUM_AND_ABOVE | BLOCK_LOW_AND_ABOVE | OFF
+// 値の検証は gemini::api_safety_settings（gemini-rust の型への変換）で行う
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SafetySetting {
+  pub category: String,
+  pub threshold: String,
+}
+
+impl SafetySetting {
+  fn category_name(&self) -> String {
+    let c = self.category.trim().to_ascii_uppercase();
+    c.strip_prefix("HARM_CATEGORY_").map(|s| s.to_string()).unwrap_or(c)
+  }
+
+  fn threshold_name(&self) -> String {
+    self.threshold.trim().to_ascii_uppercase()
+  }
+
+  // REST の safetySettings の1要素
+  pub fn to_api_json(&self) -> serde_json::Value {
+    json!({ "category": format!("HARM_CATEGORY_{}", self.category_name()), "threshold": self.threshold_name() })
+  }
+}
+
+// 段階ごとのタイムアウト秒数（0 は無制限）
+#[derive(Debug, Clone, Copy)]
+pub struct StageTimeouts {
+  pub search_secs: u64,
+  pub structure_secs: u64,
+}
+
+impl StageTimeouts {
+  pub fn uniform(secs: u64) -> Self {
+    Self { search_secs: secs, structure_secs: secs }
+  }
+}
+
+// 1リクエスト分の Future にタイムアウトを掛け、超過時は GenerateError::Timeout を返す
+pub async fn with_timeout<T, E, F>(stage: Stage, secs: u64, fut: F) -> Result<T>
+where
+  E: Into<anyhow::Error>,
+  F: Future<Output = std::result::Result<T, E>>,
+{
+  let res = if secs == 0 {
+    fut.await
+  } else {
+    match tokio::time::timeout(Duration::from_secs(secs), fut).await {
+      Ok(res) => res,
+      Err(_) => return Err(GenerateError::Timeout { stage, secs }.into()),
+    }
+  };
+  res.map_err(Into::into)
+}
+
+// 生成パラメータ（未指定はバックエンドの既定値）
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GenerationParams {
+  #[serde(default)]
+  pub temperature: Option<f32>,
+  #[serde(default)]
+  pub top_p: Option<f32>,
+  #[serde(default)]
+  pub top_k: Option<i32>,
+  #[serde(default)]
+  pub max_output_tokens: Option<i32>,
+  #[serde(default)]
+  pub seed: Option<i32>,
+  // 思考トークン予算（Gemini 2.5 系のみ。0 で思考なし）
+  #[serde(default)]
+  pub thinking_budget: Option<i32>,
+}
+
+// 段階別の生成パラメータ（search = stage1 検索メモ収集, structure = stage2 構造化 / 単発）
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StageParams {
+  #[serde(default)]
+  pub search: GenerationParams,
+  #[serde(default)]
+  pub structure: GenerationParams,
+}
+
+// ストリーミング中の増分テキストの受け口（段と、新たに届いたテキスト片を受け取る）
+type PartialFn = dyn Fn(Stage, &str) + Send + Sync;
+
+#[derive(Clone)]
+pub struct PartialSink(Arc<PartialFn>);
+
+impl PartialSink {
+  pub fn new(f: impl Fn(Stage, &str) + Send + Sync + 'static) -> Self {
+    Self(Arc::new(f))
+  }
+
+  pub fn emit(&self, stage: Stage, delta: &str) {
+    (self.0)(stage, delta)
+  }
+}
+
+impl fmt::Debug for PartialSink {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("PartialSink")
+  }
+}
+
+// 完了した段の使用量の受け口（途中の段で失敗した生成でも、それまでの消費を集計できるようにする）
+#[derive(Debug, Clone, Default)]
+pub struct UsageSink(Arc<Mutex<StageUsage>>);
+
+impl UsageSink {
+  pub fn add(&self, usage: &StageUsage) {
+    self.0.lock().unwrap_or_else(PoisonError::into_inner).add(usage);
+  }
+
+  pub fn take(&self) -> StageUsage {
+    std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner))
+  }
+}
+
+// URL グラウンディング: 検索の代わりに指定 URL のページ本文から stage1 のメモを作る
+#[derive(Clone)]
+pub struct UrlGrounding {
+  pub urls: Vec<String>,
+  pub fetcher: Arc<dyn PageFetcher>,
+}
+
+impl fmt::Debug for UrlGrounding {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("UrlGrounding").field("urls", &self.urls).finish_non_exhaustive()
+  }
+}
+
+// 1行分の構造化生成リクエスト（バックエンド非依存）
+#[derive(Debug, Clone)]
+pub struct GenerateRequest {
+  pub prompt: String,
+  pub timeouts: StageTimeouts,
+  pub enable_web_search: bool,
+  pub response_schema: Option<serde_json::Value>,
+  pub params: StageParams,
+  pub prompts: Arc<SystemPrompts>,
+  // true の場合 prompt は接頭辞を除いた残りで、接頭辞は create_prefix_cache で登録済み
+  pub prefix_cached: bool,
+  // 指定時はストリーミングで生成し、届いたテキストを逐次渡す（非対応のバックエンドは無視して一括生成）
+  pub partial: Option<PartialSink>,
+  // プロンプトと一緒に送る添付ファイル（検索あり: stage1 / 検索なし: 単発構造化に付ける）
+  pub attachments: Vec<Attachment>,
+  // 指定時は enable_web_search より優先して URL グラウンディングを行う（supports_web_search のバックエンドのみ）
+  pub url_grounding: Option<UrlGrounding>,
+  // 指定時は構造化の前にローカルツールを呼び出せる段を挟み、その結果をメモに加える
+  pub local_tools: Option<LocalToolRun>,
+  // 指定時はバックエンドの既定モデルの代わりに使う（代替モデルでの再試行）
+  pub model: Option<String>,
+  // 安全性フィルタのしきい値（空なら API の既定。非対応のバックエンドは無視）
+  pub safety_settings: Vec<SafetySetting>,
+  // 指定時は stage1 のメモを同じキーの行と共有する（最初の1行だけ検索する）
+  pub shared_notes: Option<SharedNotes>,
+  // true なら構造化の後に、各フィールドがメモで裏付けられるかを判定する段を挟む（メモがある2段階パイプラインのみ）
+  pub verify: bool,
+  // true なら stage2 の入力に stage1 の出典 URL を並べ、応答スキーマの _sources にフィールドごとの出典を書かせる
+  pub field_sources: bool,
+  // 指定時は段が完了するごとにその使用量を加える（非対応のバックエンドは何もしない。成功時は usage と同じ内容）
+  pub spent: Option<UsageSink>,
+}
+
+impl GenerateRequest {
+  // 必須の項目だけを指定し、それ以外は既定（検索・キャッシュ・ストリーミング・添付・ツール・検証なし）にする
+  // 個別の項目は構造体更新構文で上書きする: GenerateRequest { enable_web_search: true, ..GenerateRequest::new(..) }
+  pub fn new(prompt: String, timeouts: StageTimeouts, prompts: Arc<SystemPrompts>) -> Self {
+    Self {
+      prompt,
+      timeouts,
+      enable_web_search: false,
+      response_schema: None,
+      params: StageParams::default(),
+      prompts,
+      prefix_cached: false,
+      partial: None,
+      attachments: Vec::new(),
+      url_grounding: None,
+      local_tools: None,
+      model: None,
+      safety_settings: Vec::new(),
+      shared_notes: None,
+      verify: false,
+      field_sources: false,
+      spent: None,
+    }
+  }
+}
+
+// プロンプト共通接頭辞のコンテキストキャッシュ登録リクエスト
+// キャッシュを参照するリクエストではシステムプロンプト・ツールを指定できないため、どちらの段で使うかに合わせて一緒に登録する
+#[derive(Debug, Clone)]
+pub struct PrefixCacheRequest {
+  pub prefix: String,
+  // true なら stage1（検索メモ収集）、false なら単発構造化のリクエストで使う
+  pub enable_web_search: bool,
+  pub prompts: Arc<SystemPrompts>,
+  pub ttl_secs: u64,
+}
+
+// ツールなしの自由テキスト生成リクエスト
+#[derive(Debug, Clone)]
+pub struct TextRequest {
+  pub prompt: String,
+  pub timeout_secs: u64,
+  pub params: GenerationParams,
+}
+
+// 埋め込みベクトルの生成リクエスト（モデルはバックエンド作成時に指定した埋め込みモデル）
+#[derive(Debug, Clone)]
+pub struct EmbedRequest {
+  pub text: String,
+  pub timeout_secs: u64,
+  // 用途（gemini の taskType。例: CLUSTERING, SEMANTIC_SIMILARITY, RETRIEVAL_DOCUMENT）
+  pub task_type: Option<String>,
+  // 出力次元数（対応するモデルのみ。未指定はモデルの既定）
+  pub dimensions: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EmbedResponse {
+  pub vector: Vec<f32>,
+  pub usage: Option<TokenUsage>,
+  pub model: Option<String>,
+}
+
+// JSON の数値配列をベクトルとして読む（空・数値以外を含む場合は None）
+pub fn json_vector(value: &serde_json::Value) -> Option<Vec<f32>> {
+  let values = value.as_array()?;
+  let vector: Vec<f32> = values.iter().filter_map(|v| v.as_f64()).map(|v| v as f32).collect();
+  (!vector.is_empty() && vector.len() == values.len()).then_some(vector)
+}
+
+// HTTP バックエンド（openai_compatible / ollama）共通: 1回のチャット呼び出し結果
+pub struct ChatOutput {
+  pub text: String,
+  pub usage: Option<TokenUsage>,
+  pub model: Option<String>,
+}
+
+impl ChatOutput {
+  // 単発構造化（stage2 相当）のレスポンスへ変換
+  pub fn into_response(self) -> GenerateResponse {
+    GenerateResponse {
+      text: self.text,
+      usage: Some(StageUsage { search: None, structure: self.usage, tools: None, verify: None }),
+      model: self.model,
+      ..Default::default()
+    }
+  }
+}
+
+pub trait LlmProvider: Send + Sync {
+  // ログ・デバッグ表示用の識別子
+  fn name(&self) -> &'static str;
+
+  // 実際に使用するモデル名（request ログに記録する）
+  fn model(&self) -> &str;
+
+  // 検索グラウンディング（2段階: 検索メモ収集 → 構造化）に対応しているか
+  fn supports_web_search(&self) -> bool {
+    false
+  }
+
+  // ローカルツール（GenerateRequest.local_tools）に対応しているか
+  fn supports_local_tools(&self) -> bool {
+    false
+  }
+
+  // ストリーミング生成（GenerateRequest.partial）に対応しているか
+  fn supports_streaming(&self) -> bool {
+    false
+  }
+
+  // スキーマ指定の構造化出力。enable_web_search かつ対応している場合は検索グラウンディングを行う
+  fn generate_structured(&self, req: GenerateRequest) -> BoxFuture<'_, Result<GenerateResponse>>;
+
+  // ツールなしの自由テキスト生成
+  fn generate_text(&self, req: TextRequest) -> BoxFuture<'_, Result<GenerateResponse>>;
+
+  // 埋め込みベクトル（非対応のバックエンドはエラー）
+  fn embed(&self, _req: EmbedRequest) -> BoxFuture<'_, Result<EmbedResponse>> {
+    let name = self.name();
+    Box::pin(async move { Err(anyhow!("embeddings are not supported by provider '{}'", name)) })
+  }
+
+  // texts をまとめて送ったときの入力トークン数（非対応のバックエンドはエラー）
+  fn count_tokens(&self, _texts: Vec<String>, _timeout_secs: u64) -> BoxFuture<'_, Result<u64>> {
+    let name = self.name();
+    Box::pin(async move { Err(anyhow!("token counting is not supported by provider '{}'", name)) })
+  }
+
+  // 全行で共通のプロンプト接頭辞をコンテキストキャッシュへ登録し、キャッシュ名を返す（非対応なら None）
+  fn create_prefix_cache(&self, _req: PrefixCacheRequest) -> BoxFuture<'_, Result<Option<String>>> {
+    Box::pin(async { Ok(None) })
+  }
+
+  // 登録したキャッシュを削除する（実行終了時。未登録なら何もしない）
+  fn release_prefix_cache(&self) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async { Ok(()) })
+  }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+  #[default]
+  Gemini,
+  OpenaiCompatible,
+  Ollama,
+}
+
+// バックエンド生成に必要な接続設定
+#[derive(Debug, Clone, Default)]
+pub struct ProviderSettings {
+  pub kind: ProviderKind,
+  pub api_key: String,
+  pub base_url: Option<String>,
+  pub model: Option<String>,
+  // 実行内で共有する HTTP クライアントと TTFB の集計先（全キーのバックエンドで同じものを使う）
+  pub http: SharedHttp,
+}
+
+pub fn build_provider(settings: &ProviderSettings) -> Result<Arc<dyn LlmProvider>> {
+  match settings.kind {
+    ProviderKind::Gemini => Ok(Arc::new(GeminiProvider::new(
+      settings.api_key.clone(),
+      settings.model.clone().filter(|m| !m.trim().is_empty()),
+      settings.http.clone(),
+    ))),
+    ProviderKind::OpenaiCompatible => {
+      let base_url = settings
+        .base_url
+        .clone()
+        .filter(|u| !u.trim().is_empty())
+        .ok_or_else(|| anyhow!("base_url is required for openai_compatible provider"))?;
+      let model = settings
+        .model
+        .clone()
+        .filter(|m| !m.trim().is_empty())
+        .ok_or_else(|| anyhow!("model is required for openai_compatible provider"))?;
+      Ok(Arc::new(OpenAiCompatProvider::new(base_url, model, Some(settings.api_key.clone()), settings.http.clone())))
+    }
+    ProviderKind::Ollama => {
+      let base_url = settings
+        .base_url
+        .clone()
+        .filter(|u| !u.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_OLLAMA_BASE_URL.to_string());
+      let model = settings
+        .model
+        .clone()
+        .filter(|m| !m.trim().is_empty())
+        .ok_or_else(|| anyhow!("model is required for ollama provider"))?;
+      Ok(Arc::new(OllamaProvider::new(base_url, model, settings.http.clone())))
+    }
+  }
+}
+
+// Web検索を要求されたが対応していないバックエンドの場合、その旨の説明を返す
+pub fn web_search_unavailable_reason(provider: &dyn LlmProvider, requested: bool) -> Option<String> {
+  if requested && !provider.supports_web_search() {
+    Some(format!("web search is not supported by provider '{}' and was disabled", provider.name()))
+  } else {
+    None
+  }
+}
+
+// Gemini 向けの response_schema を JSON Schema（strict モード）へ変換する
+// - 型名は小文字化（"STRING" → "string"）
+// - nullable: true は ["t", "null"] へ
+// - object には additionalProperties: false を付与し、required に全プロパティを並べる
+//   （元の required に無いプロパティは null を許す型にして、省略可能の意味を残す）
+// - strict モードで受け付けられないキー（propertyOrdering など）は落とす
+pub(crate) fn to_json_schema(schema: &serde_json::Value) -> serde_json::Value {
+  let Some(obj) = schema.as_object() else {
+    return schema.clone();
+  };
+  let mut out = serde_json::Map::new();
+  let nullable = obj.get("nullable").and_then(|v| v.as_bool()).unwrap_or(false);
+  let required: Vec<&str> = obj.get("required").and_then(|r| r.as_array()).map(|r| r.iter().filter_map(|n| n.as_str()).collect()).unwrap_or_default();
+  for (k, v) in obj.iter() {
+    match k.as_str() {
+      "type" => {
+        let t = v.as_str().map(|s| s.to_ascii_lowercase());
+        let value = match t {
+          Some(t) if nullable => json!([t, "null"]),
+          Some(t) => json!(t),
+          None => v.clone(),
+        };
+        out.insert(k.clone(), value);
+      }
+      "properties" => {
+        let props = v
+          .as_object()
+          .map(|p| {
+            p.iter()
+              .map(|(name, s)| {
+                let converted = to_json_schema(s);
+                let converted = if required.contains(&name.as_str()) { converted } else { allow_null(converted) };
+                (name.clone(), converted)
+              })
+              .collect()
+          })
+          .unwrap_or_default();
+        out.insert(k.clone(), serde_json::Value::Object(props));
+      }
+      "items" => {
+        out.insert(k.clone(), to_json_schema(v));
+      }
+      "anyOf" => {
+        out.insert(k.clone(), v.as_array().map(|a| a.iter().map(to_json_schema).collect()).unwrap_or_else(|| v.clone()));
+      }
+      "enum" => {
+        let mut values = v.as_array().cloned().unwrap_or_default();
+        if nullable && !values.contains(&serde_json::Value::Null) {
+          values.push(serde_json::Value::Null);
+        }
+        out.insert(k.clone(), serde_json::Value::Array(values));
+      }
+      k if STRICT_SCHEMA_KEYS.contains(&k) => {
+        out.insert(k.to_string(), v.clone());
+      }
+      // nullable・required（下で作り直す）・propertyOrdering など
+      _ => {}
+    }
+  }
+  if let Some(props) = out.get("properties").and_then(|p| p.as_object()) {
+    let names: Vec<serde_json::Value> = props.keys().map(|n| json!(n)).collect();
+    out.insert("required".into(), serde_json::Value::Array(names));
+  }
+  let is_object = out.get("type").map(|t| t == "object" || t.as_array().is_some_and(|a| a.contains(&json!("object")))).unwrap_or(false);
+  if is_object {
+    out.insert("additionalProperties".into(), json!(false));
+  }
+  serde_json::Value::Object(out)
+}
+
+// strict モードの JSON Schema でそのまま渡せるキー（type・properties・items・anyOf・enum は個別に変換する）
+const STRICT_SCHEMA_KEYS: &[&str] = &["description", "title", "format", "pattern", "minimum", "maximum", "minItems", "maxItems", "const"];
+
+// 省略可能なプロパティを、null も受け付ける型にする
+fn allow_null(mut schema: serde_json::Value) -> serde_json::Value {
+  let Some(obj) = schema.as_object_mut() else {
+    return schema;
+  };
+  match obj.get_mut("type") {
+    Some(serde_json::Value::String(t)) => {
+      let t = t.clone();
+      obj.insert("type".into(), json!([t, "null"]));
+    }
+    Some(serde_json::Value::Array(types)) if !types.contains(&json!("null")) => types.push(json!("null")),
+    _ => {}
+  }
+  if let Some(serde_json::Value::Array(values)) = obj.get_mut("enum") {
+    if !values.contains(&serde_json::Value::Null) {
+      values.push(serde_json::Value::Null);
+    }
+  }
+  schema
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn json_schema_requires_every_property_and_marks_optional_ones_nullable() {
+    let schema = json!({
+      "type": "OBJECT",
+      "properties": {
+        "name": { "type": "STRING", "description": "company name" },
+        "employees": { "type": "INTEGER", "nullable": true },
+        "category": { "type": "STRING", "enum": ["a", "b"] },
+        "offices": {
+          "type": "ARRAY",
+          "items": {
+            "type": "OBJECT",
+            "properties": { "city": { "type": "STRING" }, "main": { "type": "BOOLEAN" } },
+            "required": ["city"],
+            "propertyOrdering": ["city", "main"],
+          },
+        },
+      },
+      "required": ["name", "employees", "offices"],
+      "propertyOrdering": ["name", "employees", "category", "offices"],
+    });
+    let mut converted = to_json_schema(&schema);
+    // required の並びは Map のキー順（preserve_order の有無）に依るので、並べ替えて比べる
+    sort_required(&mut converted);
+    assert_eq!(
+      converted,
+      json!({
+        "type": "object",
+        "properties": {
+          "name": { "type": "string", "description": "company name" },
+          "employees": { "type": ["integer", "null"] },
+          "category": { "type": ["string", "null"], "enum": ["a", "b", null] },
+          "offices": {
+            "type": "array",
+            "items": {
+              "type": "object",
+              "properties": { "city": { "type": "string" }, "main": { "type": ["boolean", "null"] } },
+              "required": ["city", "main"],
+              "additionalProperties": false,
+            },
+          },
+        },
+        "required": ["category", "employees", "name", "offices"],
+        "additionalProperties": false,
+      })
+    );
+  }
+
+  fn sort_required(schema: &mut serde_json::Value) {
+    if let Some(serde_json::Value::Array(names)) = schema.get_mut("required") {
+      names.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+    }
+    if let Some(props) = schema.get_mut("properties").and_then(|p| p.as_object_mut()) {
+      props.values_mut().for_each(sort_required);
+    }
+    if let Some(items) = schema.get_mut("items") {
+      sort_required(items);
+    }
+  }
+
+  #[test]
+  fn json_schema_without_required_makes_every_property_nullable() {
+    let mut converted = to_json_schema(&json!({
+      "type": "OBJECT",
+      "properties": { "score": { "type": "NUMBER", "nullable": true }, "tags": { "type": "ARRAY", "items": { "type": "STRING" } } },
+    }));
+    sort_required(&mut converted);
+    assert_eq!(converted["required"], json!(["score", "tags"]));
+    assert_eq!(converted["properties"]["score"]["type"], json!(["number", "null"]));
+    assert_eq!(converted["properties"]["tags"]["type"], json!(["array", "null"]));
+    assert_eq!(converted["properties"]["tags"]["items"], json!({ "type": "string" }));
+  }
+}
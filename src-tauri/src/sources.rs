@@ -0,0 +1,176 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// 出力フィールドごとの出典: 構造化の応答スキーマに _sources（フィールド → URL の配列）を足し、
+// 応答から取り出した URL は stage1 の出典（groundingChunks）に現れたものだけを残す
+
+pub const SOURCES_FIELD: &str = "_sources";
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct FieldSources {
+  // フィールド名 → 裏付けの URL（stage1 の出典にあったもののみ）
+  pub sources: BTreeMap<String, Vec<String>>,
+  // stage1 の出典に無かったため捨てた URL
+  pub rejected: Vec<String>,
+}
+
+// 応答スキーマのトップレベルのプロパティごとに URL 配列を持つ _sources を足す
+// トップレベルにプロパティの無いスキーマ（または既に _sources を持つスキーマ）は None
+pub fn augment_schema(schema: &serde_json::Value) -> Option<serde_json::Value> {
+  let props = schema.get("properties")?.as_object()?;
+  if props.is_empty() || props.contains_key(SOURCES_FIELD) {
+    return None;
+  }
+  let source_props: serde_json::Map<String, serde_json::Value> = props
+    .keys()
+    .map(|k| (k.clone(), serde_json::json!({ "type": "ARRAY", "items": { "type": "STRING" } })))
+    .collect();
+  let mut out = schema.clone();
+  let obj = out.as_object_mut()?;
+  obj["properties"][SOURCES_FIELD] = serde_json::json!({ "type": "OBJECT", "properties": source_props });
+  for key in ["required", "propertyOrdering"] {
+    match obj.get_mut(key).and_then(|v| v.as_array_mut()) {
+      Some(list) => list.push(serde_json::json!(SOURCES_FIELD)),
+      None if key == "required" => {
+        obj.insert(key.into(), serde_json::json!([SOURCES_FIELD]));
+      }
+      None => {}
+    }
+  }
+  Some(out)
+}
+
+// groundingMetadata の出典（URL とタイトル）を現れた順に重複なく返す
+pub fn grounding_urls(grounding_metadata: Option<&serde_json::Value>) -> Vec<(String, Option<String>)> {
+  let mut out: Vec<(String, Option<String>)> = Vec::new();
+  let chunks = grounding_metadata.and_then(|g| g.get("groundingChunks")).and_then(|c| c.as_array());
+  for web in chunks.into_iter().flatten().filter_map(|c| c.get("web")) {
+    let Some(uri) = web.get("uri").and_then(|u| u.as_str()) else {
+      continue;
+    };
+    if !out.iter().any(|(u, _)| u == uri) {
+      out.push((uri.to_string(), web.get("title").and_then(|t| t.as_str()).map(|t| t.to_string())));
+    }
+  }
+  out
+}
+
+// 応答の JSON から _sources を取り除き、出力にあるフィールドと stage1 の出典にある URL だけを残す
+// _sources が無い（オブジェクトでない）場合は空の対応を返す
+pub fn take_sources(data: &mut serde_json::Value, grounding_metadata: Option<&serde_json::Value>) -> FieldSources {
+  let mut out = FieldSources::default();
+  let Some(obj) = data.as_object_mut() else {
+    return out;
+  };
+  let Some(serde_json::Value::Object(raw)) = obj.remove(SOURCES_FIELD) else {
+    return out;
+  };
+  let allowed = grounding_urls(grounding_metadata);
+  for (field, urls) in raw {
+    if !obj.contains_key(&field) {
+      continue;
+    }
+    let mut kept: Vec<String> = Vec::new();
+    for url in urls.as_array().into_iter().flatten().filter_map(|u| u.as_str()).map(|u| u.trim()) {
+      if !allowed.iter().any(|(a, _)| a == url) {
+        out.rejected.push(url.to_string());
+      } else if !kept.iter().any(|k| k == url) {
+        kept.push(url.to_string());
+      }
+    }
+    if !kept.is_empty() {
+      out.sources.insert(field, kept);
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn augment_schema_adds_sources_for_each_top_level_property() {
+    let schema = json!({
+      "type": "OBJECT",
+      "properties": { "name": { "type": "STRING" }, "price": { "type": "NUMBER" } },
+      "required": ["name"],
+      "propertyOrdering": ["name", "price"],
+    });
+    let out = augment_schema(&schema).unwrap();
+    let sources = &out["properties"][SOURCES_FIELD];
+    assert_eq!(sources["type"], "OBJECT");
+    assert_eq!(sources["properties"]["name"], json!({ "type": "ARRAY", "items": { "type": "STRING" } }));
+    assert_eq!(sources["properties"]["price"], json!({ "type": "ARRAY", "items": { "type": "STRING" } }));
+    assert_eq!(out["required"], json!(["name", SOURCES_FIELD]));
+    assert_eq!(out["propertyOrdering"], json!(["name", "price", SOURCES_FIELD]));
+    // 元のスキーマは変えない
+    assert!(schema["properties"].get(SOURCES_FIELD).is_none());
+
+    // required が無ければ _sources だけを必須にし、propertyOrdering は足さない
+    let out = augment_schema(&json!({ "type": "OBJECT", "properties": { "name": { "type": "STRING" } } })).unwrap();
+    assert_eq!(out["required"], json!([SOURCES_FIELD]));
+    assert!(out.get("propertyOrdering").is_none());
+  }
+
+  #[test]
+  fn augment_schema_skips_schemas_without_properties_or_with_sources() {
+    assert!(augment_schema(&json!({ "type": "ARRAY", "items": { "type": "STRING" } })).is_none());
+    assert!(augment_schema(&json!({ "type": "OBJECT", "properties": {} })).is_none());
+    assert!(augment_schema(&json!({ "type": "OBJECT", "properties": { SOURCES_FIELD: { "type": "OBJECT" } } })).is_none());
+  }
+
+  #[test]
+  fn take_sources_keeps_only_grounded_urls_for_output_fields() {
+    let grounding = json!({
+      "groundingChunks": [
+        { "web": { "uri": "https://a.example/", "title": "A" } },
+        { "web": { "uri": "https://b.example/" } },
+        { "web": { "uri": "https://a.example/", "title": "A again" } },
+        { "retrievedContext": {} },
+      ]
+    });
+    assert_eq!(
+      grounding_urls(Some(&grounding)),
+      vec![("https://a.example/".to_string(), Some("A".to_string())), ("https://b.example/".to_string(), None)]
+    );
+
+    let mut data = json!({
+      "name": "Widget",
+      "price": 10,
+      "maker": null,
+      SOURCES_FIELD: {
+        "name": [" https://a.example/ ", "https://a.example/", "https://made-up.example/"],
+        "price": ["https://unknown.example/"],
+        "maker": [],
+        "missing": ["https://b.example/"],
+      },
+    });
+    let out = take_sources(&mut data, Some(&grounding));
+    assert_eq!(data, json!({ "name": "Widget", "price": 10, "maker": null }));
+    assert_eq!(out.sources.len(), 1);
+    assert_eq!(out.sources["name"], vec!["https://a.example/".to_string()]);
+    let mut rejected = out.rejected.clone();
+    rejected.sort();
+    assert_eq!(rejected, vec!["https://made-up.example/".to_string(), "https://unknown.example/".to_string()]);
+  }
+
+  #[test]
+  fn take_sources_without_sources_or_grounding() {
+    let mut data = json!({ "name": "Widget" });
+    let out = take_sources(&mut data, None);
+    assert!(out.sources.is_empty() && out.rejected.is_empty());
+    assert_eq!(data, json!({ "name": "Widget" }));
+
+    // 出典が無ければ URL はすべて捨てる（_sources は取り除く）
+    let mut data = json!({ "name": "Widget", SOURCES_FIELD: { "name": ["https://a.example/"] } });
+    let out = take_sources(&mut data, None);
+    assert!(out.sources.is_empty());
+    assert_eq!(out.rejected, vec!["https://a.example/".to_string()]);
+    assert_eq!(data, json!({ "name": "Widget" }));
+
+    let mut data = json!(["not", "an", "object"]);
+    assert!(take_sources(&mut data, None).sources.is_empty());
+  }
+}
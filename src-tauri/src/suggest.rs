@@ -0,0 +1,418 @@
+use crate::attachments::ATTACHMENT_MARKER_PREFIX;
+use crate::prompts::SystemPrompts;
+use crate::provider::{GenerateRequest, GenerationParams, LlmProvider, StageParams, StageTimeouts};
+use crate::usage::StageUsage;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// CSV の列名・サンプル行・目的から、プロンプトテンプレートと出力カラム定義（フロントエンドの OutputColumn と同じ形）を提案する
+// 提案は返す前に検証し、ヘッダーに無い {{列名}} や不正なカラム定義があれば指摘を付けて1回だけ作り直させる
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnType {
+  String,
+  Number,
+  Boolean,
+  Object,
+  Array,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputColumn {
+  pub name: String,
+  #[serde(default)]
+  pub description: Option<String>,
+  #[serde(rename = "type")]
+  pub column_type: ColumnType,
+  // object のプロパティ / array の要素（オブジェクト）のカラム
+  // strict スキーマでは省略可能なプロパティも null で返るため、null は空として読む
+  #[serde(default, deserialize_with = "null_as_empty")]
+  pub nested_columns: Vec<OutputColumn>,
+}
+
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Vec<OutputColumn>, D::Error> {
+  Ok(Option::<Vec<OutputColumn>>::deserialize(d)?.unwrap_or_default())
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptSuggestion {
+  pub prompt_template: String,
+  pub output_columns: Vec<OutputColumn>,
+  // output_columns から作った応答スキーマ（ProcessConfig.response_schema にそのまま渡せる）
+  pub response_schema: serde_json::Value,
+  pub usage: Option<StageUsage>,
+  pub model: Option<String>,
+}
+
+pub struct SuggestRequest {
+  pub headers: Vec<String>,
+  pub sample_rows: Vec<serde_json::Map<String, serde_json::Value>>,
+  pub goal: String,
+  // 提案するプロンプト・説明の言語（ja / en / de、未指定は ja）
+  pub language: Option<String>,
+  pub params: GenerationParams,
+  pub timeout_secs: u64,
+}
+
+// プロンプトに載せるサンプル行数とセルの文字数の上限
+const MAX_SAMPLE_ROWS: usize = 5;
+const MAX_SAMPLE_CELL_CHARS: usize = 200;
+// カラムの入れ子の深さの上限（トップレベルを 1 とする）
+const MAX_COLUMN_DEPTH: usize = 3;
+
+pub async fn suggest(llm: &dyn LlmProvider, req: SuggestRequest) -> Result<PromptSuggestion> {
+  // 置換は列名の完全一致のため、ヘッダーは前後の空白も含めてそのまま照合する
+  let headers: Vec<String> = req.headers.iter().filter(|h| !h.trim().is_empty()).cloned().collect();
+  if headers.is_empty() {
+    return Err(anyhow!("at least one CSV header is required"));
+  }
+  if req.goal.trim().is_empty() {
+    return Err(anyhow!("goal must not be empty"));
+  }
+  let prompt = render_request(&headers, &req);
+  let mut feedback: Option<String> = None;
+  let mut usage = StageUsage::default();
+  loop {
+    let full_prompt = match feedback.as_ref() {
+      Some(problems) => format!("{}\n\nYour previous suggestion was rejected: {}\nFix these problems and answer again.", prompt, problems),
+      None => prompt.clone(),
+    };
+    let resp = llm
+      .generate_structured(GenerateRequest {
+        response_schema: Some(suggestion_schema()),
+        params: StageParams { search: GenerationParams::default(), structure: req.params.clone() },
+        ..GenerateRequest::new(
+          full_prompt,
+          StageTimeouts::uniform(req.timeout_secs),
+          Arc::new(SystemPrompts::defaults(req.language.as_deref().unwrap_or("ja"))),
+        )
+      })
+      .await?;
+    if let Some(u) = resp.usage.as_ref() {
+      usage.add(u);
+    }
+    let checked = serde_json::from_str::<Suggested>(resp.text.trim())
+      .map_err(|e| anyhow!("invalid suggestion response: {}", e))
+      .and_then(|s| validate(&s, &headers).map(|_| s));
+    match checked {
+      Ok(s) => {
+        let response_schema = response_schema(&s.output_columns);
+        return Ok(PromptSuggestion {
+          prompt_template: s.prompt_template,
+          output_columns: s.output_columns,
+          response_schema,
+          usage: Some(usage),
+          model: resp.model,
+        });
+      }
+      Err(e) if feedback.is_none() => feedback = Some(e.to_string()),
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Suggested {
+  prompt_template: String,
+  output_columns: Vec<OutputColumn>,
+}
+
+fn render_request(headers: &[String], req: &SuggestRequest) -> String {
+  let language = match req.language.as_deref().unwrap_or("ja") {
+    "en" => "English",
+    "de" => "German",
+    _ => "Japanese",
+  };
+  let samples: Vec<serde_json::Value> = req
+    .sample_rows
+    .iter()
+    .take(MAX_SAMPLE_ROWS)
+    .map(|row| {
+      let cells = row
+        .iter()
+        .map(|(k, v)| {
+          let text = match v {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+          };
+          (k.clone(), serde_json::Value::String(text.chars().take(MAX_SAMPLE_CELL_CHARS).collect()))
+        })
+        .collect();
+      serde_json::Value::Object(cells)
+    })
+    .collect();
+  format!(
+    "Design a prompt template and output columns for processing each row of a CSV file with an LLM.\n\n\
+     Goal: {goal}\n\n\
+     CSV columns: {headers}\n\n\
+     Sample rows:\n{samples}\n\n\
+     Rules:\n\
+     - The prompt template is sent once per row. Refer to row values only with {{{{column}}}} placeholders, using the CSV column names exactly as listed.\n\
+     - Output columns describe the JSON returned per row. Types are string, number, boolean, object or array. \
+     object needs nestedColumns; array may have nestedColumns for object elements (otherwise the elements are strings). \
+     Nest at most {depth} levels.\n\
+     - Column names must be unique within their level and must not start with an underscore.\n\
+     - Write the prompt template and the column descriptions in {language}.",
+    goal = req.goal.trim(),
+    headers = serde_json::to_string(headers).unwrap_or_default(),
+    samples = serde_json::to_string_pretty(&samples).unwrap_or_default(),
+    depth = MAX_COLUMN_DEPTH,
+    language = language,
+  )
+}
+
+// 提案の応答スキーマ（Gemini の responseSchema は再帰できないため、入れ子の深さ分だけ展開する）
+fn suggestion_schema() -> serde_json::Value {
+  serde_json::json!({
+    "type": "OBJECT",
+    "properties": {
+      "promptTemplate": { "type": "STRING" },
+      "outputColumns": { "type": "ARRAY", "items": column_schema(MAX_COLUMN_DEPTH) },
+    },
+    "required": ["promptTemplate", "outputColumns"],
+  })
+}
+
+fn column_schema(depth: usize) -> serde_json::Value {
+  let mut schema = serde_json::json!({
+    "type": "OBJECT",
+    "properties": {
+      "name": { "type": "STRING" },
+      "description": { "type": "STRING" },
+      "type": { "type": "STRING", "enum": ["string", "number", "boolean", "object", "array"] },
+    },
+    "required": ["name", "type"],
+  });
+  if depth > 1 {
+    schema["properties"]["nestedColumns"] = serde_json::json!({ "type": "ARRAY", "items": column_schema(depth - 1) });
+  }
+  schema
+}
+
+// テンプレートの {{列名}} / {{file:列名}} から列名を出現順に取り出す（重複は除く）
+pub fn placeholders(template: &str) -> Vec<String> {
+  let mut out: Vec<String> = Vec::new();
+  let mut rest = template;
+  while let Some(start) = rest.find("{{") {
+    let after = &rest[start + 2..];
+    let Some(end) = after.find("}}") else {
+      break;
+    };
+    let inner = &after[..end];
+    // {{file:列名}} は添付列（列名の前後の空白は無視される）、それ以外は {{列名}} の完全一致で置換される
+    let name = match inner.strip_prefix(&ATTACHMENT_MARKER_PREFIX[2..]) {
+      Some(col) => col.trim().to_string(),
+      None => inner.to_string(),
+    };
+    if !out.contains(&name) {
+      out.push(name);
+    }
+    rest = &after[end + 2..];
+  }
+  out
+}
+
+// ヘッダーに無い {{列名}}・カラム定義の問題をまとめて1つのエラーにする
+fn validate(s: &Suggested, headers: &[String]) -> Result<()> {
+  let mut problems = Vec::new();
+  if s.prompt_template.trim().is_empty() {
+    problems.push("the prompt template is empty".to_string());
+  }
+  let unknown: Vec<String> = placeholders(&s.prompt_template).into_iter().filter(|p| !headers.contains(p)).collect();
+  if !unknown.is_empty() {
+    problems.push(format!("placeholders not found in the CSV headers: {}", unknown.iter().map(|p| format!("{{{{{}}}}}", p)).collect::<Vec<_>>().join(", ")));
+  }
+  if s.output_columns.is_empty() {
+    problems.push("no output columns".to_string());
+  }
+  validate_columns(&s.output_columns, "", 1, &mut problems);
+  if problems.is_empty() {
+    Ok(())
+  } else {
+    Err(anyhow!("{}", problems.join("; ")))
+  }
+}
+
+fn validate_columns(columns: &[OutputColumn], parent: &str, depth: usize, problems: &mut Vec<String>) {
+  for (i, col) in columns.iter().enumerate() {
+    let path = format!("{}{}", parent, col.name);
+    if col.name.trim().is_empty() {
+      problems.push(format!("column #{} under '{}' has no name", i + 1, parent.trim_end_matches('.')));
+    } else if col.name.starts_with('_') {
+      problems.push(format!("column '{}' must not start with an underscore", path));
+    }
+    if columns[..i].iter().any(|c| c.name == col.name) {
+      problems.push(format!("duplicate column '{}'", path));
+    }
+    match col.column_type {
+      ColumnType::Object if col.nested_columns.is_empty() => problems.push(format!("object column '{}' has no nested columns", path)),
+      ColumnType::Object | ColumnType::Array if !col.nested_columns.is_empty() => {
+        if depth >= MAX_COLUMN_DEPTH {
+          problems.push(format!("column '{}' is nested deeper than {} levels", path, MAX_COLUMN_DEPTH));
+        }
+        validate_columns(&col.nested_columns, &format!("{}.", path), depth + 1, problems);
+      }
+      ColumnType::String | ColumnType::Number | ColumnType::Boolean if !col.nested_columns.is_empty() => {
+        problems.push(format!("{} column '{}' cannot have nested columns", type_name(col.column_type), path));
+      }
+      _ => {}
+    }
+  }
+}
+
+fn type_name(t: ColumnType) -> &'static str {
+  match t {
+    ColumnType::String => "string",
+    ColumnType::Number => "number",
+    ColumnType::Boolean => "boolean",
+    ColumnType::Object => "object",
+    ColumnType::Array => "array",
+  }
+}
+
+// フロントエンドの buildSchema と同じ形（全カラム必須、ネストの無い配列の要素は文字列）
+pub fn response_schema(columns: &[OutputColumn]) -> serde_json::Value {
+  let (properties, required) = object_properties(columns);
+  serde_json::json!({ "type": "object", "properties": properties, "required": required })
+}
+
+fn object_properties(columns: &[OutputColumn]) -> (serde_json::Map<String, serde_json::Value>, Vec<String>) {
+  let mut properties = serde_json::Map::new();
+  let mut required = Vec::new();
+  for col in columns {
+    let schema = match col.column_type {
+      ColumnType::Object => response_schema(&col.nested_columns),
+      ColumnType::Array if !col.nested_columns.is_empty() => {
+        serde_json::json!({ "type": "array", "items": response_schema(&col.nested_columns) })
+      }
+      ColumnType::Array => serde_json::json!({ "type": "array", "items": { "type": "string" } }),
+      leaf => serde_json::json!({ "type": type_name(leaf) }),
+    };
+    properties.insert(col.name.clone(), schema);
+    required.push(col.name.clone());
+  }
+  (properties, required)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn columns(value: serde_json::Value) -> Vec<OutputColumn> {
+    serde_json::from_value(value).unwrap()
+  }
+
+  fn suggested(template: &str, output_columns: serde_json::Value) -> Suggested {
+    Suggested { prompt_template: template.into(), output_columns: columns(output_columns) }
+  }
+
+  fn problems(cols: serde_json::Value) -> Vec<String> {
+    let mut out = Vec::new();
+    validate_columns(&columns(cols), "", 1, &mut out);
+    out
+  }
+
+  #[test]
+  fn placeholders_in_order_without_duplicates() {
+    assert_eq!(
+      placeholders("{{name}} / {{file: photo }} / {{name}} / {{ spaced }} / {{file:photo}} / {{open"),
+      vec!["name".to_string(), "photo".to_string(), " spaced ".to_string()]
+    );
+    assert!(placeholders("no placeholders").is_empty());
+  }
+
+  #[test]
+  fn validate_rejects_placeholders_outside_the_headers() {
+    let headers = vec!["name".to_string(), "photo".to_string()];
+    let cols = json!([{ "name": "summary", "type": "string" }]);
+    assert!(validate(&suggested("Describe {{name}} from {{file:photo}}", cols.clone()), &headers).is_ok());
+
+    let err = validate(&suggested("{{name}} {{price}} {{file:scan}}", cols.clone()), &headers).unwrap_err().to_string();
+    assert!(err.contains("{{price}}") && err.contains("{{scan}}") && !err.contains("{{name}}"), "{}", err);
+    // 置換は完全一致のため、空白の付いた列名は別の列として扱う
+    let err = validate(&suggested("{{ name }}", cols), &headers).unwrap_err().to_string();
+    assert!(err.contains("{{ name }}"), "{}", err);
+
+    let err = validate(&suggested(" ", json!([])), &headers).unwrap_err().to_string();
+    assert!(err.contains("the prompt template is empty") && err.contains("no output columns"), "{}", err);
+  }
+
+  #[test]
+  fn validate_columns_reports_each_problem() {
+    assert!(problems(json!([
+      { "name": "title", "type": "string" },
+      { "name": "specs", "type": "object", "nestedColumns": [{ "name": "weight", "type": "number" }] },
+      { "name": "tags", "type": "array" },
+    ]))
+    .is_empty());
+
+    // (カラム定義, 含まれるべき指摘)
+    let cases = vec![
+      (json!([{ "name": "a", "type": "string" }, { "name": "a", "type": "number" }]), "duplicate column 'a'"),
+      (json!([{ "name": "o", "type": "object", "nestedColumns": [{ "name": "x", "type": "string" }, { "name": "x", "type": "string" }] }]), "duplicate column 'o.x'"),
+      (json!([{ "name": "spec", "type": "object" }]), "object column 'spec' has no nested columns"),
+      (json!([{ "name": "spec", "type": "object", "nestedColumns": null }]), "object column 'spec' has no nested columns"),
+      (json!([{ "name": "_id", "type": "string" }]), "column '_id' must not start with an underscore"),
+      (json!([{ "name": " ", "type": "string" }]), "column #1 under '' has no name"),
+      (json!([{ "name": "n", "type": "number", "nestedColumns": [{ "name": "x", "type": "string" }] }]), "number column 'n' cannot have nested columns"),
+      (
+        json!([{ "name": "a", "type": "object", "nestedColumns": [{ "name": "b", "type": "array", "nestedColumns": [
+          { "name": "c", "type": "object", "nestedColumns": [{ "name": "d", "type": "string" }] }
+        ] }] }]),
+        "column 'a.b.c' is nested deeper than 3 levels",
+      ),
+    ];
+    for (cols, want) in cases {
+      let got = problems(cols.clone());
+      assert!(got.iter().any(|p| p == want), "{}: {:?}", cols, got);
+    }
+    // 3 段までは入れ子にできる
+    assert!(problems(json!([{ "name": "a", "type": "object", "nestedColumns": [{ "name": "b", "type": "object", "nestedColumns": [
+      { "name": "c", "type": "string" }
+    ] }] }]))
+    .is_empty());
+  }
+
+  #[test]
+  fn null_nested_columns_read_as_empty() {
+    // strict スキーマのバックエンドは葉のカラムにも nestedColumns: null を返す
+    let cols = columns(json!([{ "name": "title", "description": null, "type": "string", "nestedColumns": null }]));
+    assert!(cols[0].nested_columns.is_empty() && cols[0].description.is_none());
+  }
+
+  #[test]
+  fn response_schema_for_object_and_array_columns() {
+    let schema = response_schema(&columns(json!([
+      { "name": "title", "type": "string" },
+      { "name": "specs", "type": "object", "nestedColumns": [{ "name": "weight", "type": "number" }, { "name": "boxed", "type": "boolean" }] },
+      { "name": "tags", "type": "array" },
+      { "name": "offers", "type": "array", "nestedColumns": [{ "name": "price", "type": "number" }] },
+    ])));
+    assert_eq!(
+      schema,
+      json!({
+        "type": "object",
+        "properties": {
+          "title": { "type": "string" },
+          "specs": {
+            "type": "object",
+            "properties": { "weight": { "type": "number" }, "boxed": { "type": "boolean" } },
+            "required": ["weight", "boxed"],
+          },
+          "tags": { "type": "array", "items": { "type": "string" } },
+          "offers": {
+            "type": "array",
+            "items": { "type": "object", "properties": { "price": { "type": "number" } }, "required": ["price"] },
+          },
+        },
+        "required": ["title", "specs", "tags", "offers"],
+      })
+    );
+  }
+}
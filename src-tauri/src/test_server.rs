@@ -0,0 +1,129 @@
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// テスト用の最小限の HTTP/1.1 サーバー（1接続1リクエスト、応答後に接続を閉じる）
+// 受け取ったリクエストを記録し、handler の返した応答を返す
+
+#[derive(Debug, Clone)]
+pub struct Recorded {
+  pub method: String,
+  // クエリ文字列を含むパス
+  pub path: String,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+impl Recorded {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+  }
+
+  pub fn json(&self) -> serde_json::Value {
+    serde_json::from_slice(&self.body).unwrap_or_default()
+  }
+}
+
+pub struct Reply {
+  pub status: u16,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+  // false なら Content-Length を付けず、接続を閉じて本文の終わりを示す
+  pub content_length: bool,
+}
+
+impl Reply {
+  pub fn json(value: serde_json::Value) -> Self {
+    Self::with_type(200, "application/json", value.to_string())
+  }
+
+  pub fn with_type(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+    Self { status, headers: vec![("Content-Type".into(), content_type.into())], body: body.into(), content_length: true }
+  }
+
+  pub fn header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.into(), value.into()));
+    self
+  }
+}
+
+pub struct TestServer {
+  // http://127.0.0.1:port
+  pub base_url: String,
+  pub requests: Arc<Mutex<Vec<Recorded>>>,
+}
+
+impl TestServer {
+  pub fn requests(&self) -> Vec<Recorded> {
+    self.requests.lock().unwrap().clone()
+  }
+}
+
+pub async fn serve(handler: impl Fn(&Recorded, &str) -> Reply + Send + Sync + 'static) -> TestServer {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let base_url = format!("http://{}", listener.local_addr().unwrap());
+  let requests: Arc<Mutex<Vec<Recorded>>> = Arc::default();
+  let handler = Arc::new(handler);
+  let (log, base) = (requests.clone(), base_url.clone());
+  tokio::spawn(async move {
+    while let Ok((mut socket, _)) = listener.accept().await {
+      let (log, base, handler) = (log.clone(), base.clone(), handler.clone());
+      tokio::spawn(async move {
+        let Some(req) = read_request(&mut socket).await else {
+          return;
+        };
+        log.lock().unwrap().push(req.clone());
+        let reply = handler(&req, &base);
+        let mut head = format!("HTTP/1.1 {} Test\r\nConnection: close\r\n", reply.status);
+        if reply.content_length {
+          head.push_str(&format!("Content-Length: {}\r\n", reply.body.len()));
+        }
+        for (k, v) in &reply.headers {
+          head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+        let _ = socket.write_all(head.as_bytes()).await;
+        let _ = socket.write_all(&reply.body).await;
+        let _ = socket.shutdown().await;
+      });
+    }
+  });
+  TestServer { base_url, requests }
+}
+
+async fn read_request(socket: &mut tokio::net::TcpStream) -> Option<Recorded> {
+  let mut buf = Vec::new();
+  let mut chunk = [0u8; 4096];
+  let head_end = loop {
+    if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+      break pos;
+    }
+    let n = socket.read(&mut chunk).await.ok()?;
+    if n == 0 {
+      return None;
+    }
+    buf.extend_from_slice(&chunk[..n]);
+  };
+  let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+  let mut lines = head.split("\r\n");
+  let mut request_line = lines.next()?.split(' ');
+  let (method, path) = (request_line.next()?.to_string(), request_line.next()?.to_string());
+  let headers: Vec<(String, String)> = lines
+    .filter_map(|l| l.split_once(':'))
+    .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+    .collect();
+  let length = headers
+    .iter()
+    .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+    .and_then(|(_, v)| v.parse::<usize>().ok())
+    .unwrap_or(0);
+  let mut body = buf[head_end + 4..].to_vec();
+  while body.len() < length {
+    let n = socket.read(&mut chunk).await.ok()?;
+    if n == 0 {
+      break;
+    }
+    body.extend_from_slice(&chunk[..n]);
+  }
+  Some(Recorded { method, path, headers, body })
+}
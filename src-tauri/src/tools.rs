@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// 行処理中にモデルから呼び出せるローカル参照ツール（function calling）
+// ProcessConfig で名前・パラメータスキーマ・参照データ（CSV / JSON ファイル）を宣言し、
+// 呼び出しは Rust 側で「引数と同名の列が一致する行」を返す検索として実行する
+// 参照データは CSV / JSON のみ（SQLite などのデータベースは対象外。使う場合は CSV / JSON へ書き出して渡す）
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ToolConfig {
+  pub name: String,
+  #[serde(default)]
+  pub description: String,
+  // 引数の JSON Schema（Gemini の OpenAPI サブセット。type: object の properties が検索条件の列名）
+  pub parameters: serde_json::Value,
+  pub source: ToolSource,
+  #[serde(default)]
+  pub match_mode: MatchMode,
+  // 1回の呼び出しで返す最大行数（未指定は 20）
+  #[serde(default)]
+  pub max_results: Option<usize>,
+}
+
+// 参照データ（先頭行がヘッダーの CSV、またはオブジェクト配列の JSON。ほかの形式は受け付けない）
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolSource {
+  Csv { path: String },
+  Json { path: String },
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+  // 大文字小文字を区別しない完全一致
+  #[default]
+  Exact,
+  // 大文字小文字を区別しない部分一致
+  Contains,
+}
+
+const DEFAULT_MAX_RESULTS: usize = 20;
+
+// 1回の呼び出し結果（JSONL の tool_call レコードになる）
+#[derive(Debug, Clone)]
+pub struct ToolCallRecord {
+  pub round: u32,
+  pub name: String,
+  pub args: serde_json::Value,
+  pub result: Option<serde_json::Value>,
+  pub error: Option<String>,
+  pub duration_ms: u64,
+}
+
+// 行ごとの呼び出し履歴（生成が途中で失敗しても、それまでの呼び出しをログに残せるよう共有する）
+pub type ToolCallLog = Arc<Mutex<Vec<ToolCallRecord>>>;
+
+// 1行分の生成で使うツール群と呼び出し履歴
+#[derive(Clone)]
+pub struct LocalToolRun {
+  pub tools: Arc<LocalTools>,
+  pub log: ToolCallLog,
+  // 関数呼び出しの往復回数の上限（超えたら呼び出しを禁止してメモを書かせる）
+  pub max_rounds: u32,
+}
+
+impl fmt::Debug for LocalToolRun {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("LocalToolRun").field("max_rounds", &self.max_rounds).finish_non_exhaustive()
+  }
+}
+
+pub const DEFAULT_MAX_TOOL_ROUNDS: u32 = 5;
+
+struct LoadedTool {
+  config: ToolConfig,
+  rows: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+// 実行開始時に参照データを読み込んだツール群（全行で共有）
+pub struct LocalTools {
+  tools: Vec<LoadedTool>,
+}
+
+impl LocalTools {
+  pub async fn load(configs: &[ToolConfig]) -> Result<Self> {
+    let mut tools = Vec::new();
+    for config in configs {
+      if config.name.trim().is_empty() {
+        return Err(anyhow!("tool name must not be empty"));
+      }
+      let rows = match &config.source {
+        ToolSource::Csv { path } => {
+          let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow!("tool '{}': cannot read {}: {}", config.name, path, e))?;
+          csv_to_rows(&text)
+        }
+        ToolSource::Json { path } => {
+          let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow!("tool '{}': cannot read {}: {}", config.name, path, e))?;
+          let value: serde_json::Value = serde_json::from_str(&text)
+            .map_err(|e| anyhow!("tool '{}': invalid JSON in {}: {}", config.name, path, e))?;
+          value
+            .as_array()
+            .ok_or_else(|| anyhow!("tool '{}': {} must contain an array of objects", config.name, path))?
+            .iter()
+            .filter_map(|v| v.as_object().cloned())
+            .collect()
+        }
+      };
+      tools.push(LoadedTool { config: config.clone(), rows });
+    }
+    Ok(Self { tools })
+  }
+
+  pub fn names(&self) -> Vec<String> {
+    self.tools.iter().map(|t| t.config.name.clone()).collect()
+  }
+
+  // Gemini の tools[].functionDeclarations
+  pub fn declarations(&self) -> serde_json::Value {
+    let decls: Vec<serde_json::Value> = self
+      .tools
+      .iter()
+      .map(|t| {
+        serde_json::json!({
+          "name": t.config.name,
+          "description": t.config.description,
+          "parameters": t.config.parameters,
+        })
+      })
+      .collect();
+    serde_json::Value::Array(decls)
+  }
+
+  // 引数のキーを列名として一致する行を返す（未知の列名はモデルへエラーとして返す）
+  pub fn call(&self, name: &str, args: &serde_json::Value) -> Result<serde_json::Value> {
+    let tool = self
+      .tools
+      .iter()
+      .find(|t| t.config.name == name)
+      .ok_or_else(|| anyhow!("unknown function: {}", name))?;
+    let empty = serde_json::Map::new();
+    let conditions = args.as_object().unwrap_or(&empty);
+    for key in conditions.keys() {
+      if !tool.rows.iter().any(|r| r.contains_key(key)) {
+        return Err(anyhow!("unknown field '{}' for function {}", key, name));
+      }
+    }
+    let max = tool.config.max_results.unwrap_or(DEFAULT_MAX_RESULTS).max(1);
+    let matched: Vec<&serde_json::Map<String, serde_json::Value>> = tool
+      .rows
+      .iter()
+      .filter(|row| {
+        conditions.iter().all(|(k, want)| {
+          let Some(have) = row.get(k) else {
+            return false;
+          };
+          let (have, want) = (as_text(have).trim().to_lowercase(), as_text(want).trim().to_lowercase());
+          match tool.config.match_mode {
+            MatchMode::Exact => have == want,
+            MatchMode::Contains => have.contains(&want),
+          }
+        })
+      })
+      .collect();
+    let count = matched.len();
+    let rows: Vec<serde_json::Value> = matched.into_iter().take(max).map(|r| serde_json::Value::Object(r.clone())).collect();
+    Ok(serde_json::json!({ "rows": rows, "count": count, "truncated": count > max }))
+  }
+}
+
+fn as_text(v: &serde_json::Value) -> String {
+  match v {
+    serde_json::Value::String(s) => s.clone(),
+    _ => v.to_string(),
+  }
+}
+
+// 先頭行をヘッダーとして各行を列名 → 文字列のオブジェクトにする
+fn csv_to_rows(text: &str) -> Vec<serde_json::Map<String, serde_json::Value>> {
+  let mut records = parse_csv(text.trim_start_matches('\u{feff}')).into_iter();
+  let Some(header) = records.next() else {
+    return Vec::new();
+  };
+  records
+    .filter(|r| r.iter().any(|c| !c.is_empty()))
+    .map(|r| {
+      header
+        .iter()
+        .enumerate()
+        .map(|(i, h)| (h.trim().to_string(), serde_json::Value::String(r.get(i).cloned().unwrap_or_default())))
+        .collect()
+    })
+    .collect()
+}
+
+// RFC 4180 相当の最小限の CSV パーサー（ダブルクォート・"" エスケープ・クォート内改行に対応）
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+  let mut records = Vec::new();
+  let mut record = Vec::new();
+  let mut field = String::new();
+  let mut in_quotes = false;
+  let mut chars = text.chars().peekable();
+  while let Some(c) = chars.next() {
+    if in_quotes {
+      match c {
+        '"' if chars.peek() == Some(&'"') => {
+          field.push('"');
+          chars.next();
+        }
+        '"' => in_quotes = false,
+        _ => field.push(c),
+      }
+      continue;
+    }
+    match c {
+      '"' => in_quotes = true,
+      ',' => record.push(std::mem::take(&mut field)),
+      '\r' => {}
+      '\n' => {
+        record.push(std::mem::take(&mut field));
+        records.push(std::mem::take(&mut record));
+      }
+      _ => field.push(c),
+    }
+  }
+  if !field.is_empty() || !record.is_empty() {
+    record.push(field);
+    records.push(record);
+  }
+  records
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn csv_parser_handles_quotes_commas_and_newlines() {
+    let text = "\u{feff}sku,name,note\r\nA-1,\"Bolt, M6\",\"says \"\"zinc\"\"\"\r\nA-2,Nut,\"line one\nline two\"\n,,\nA-3,Washer\n";
+    let rows = csv_to_rows(text);
+    let get = |i: usize, k: &str| rows[i].get(k).and_then(|v| v.as_str()).unwrap_or("<missing>").to_string();
+    // 空行（全列が空）は除き、足りない列は空文字にする
+    assert_eq!(rows.len(), 3);
+    assert_eq!((get(0, "sku"), get(0, "name"), get(0, "note")), ("A-1".into(), "Bolt, M6".into(), "says \"zinc\"".into()));
+    assert_eq!(get(1, "note"), "line one\nline two");
+    assert_eq!((get(2, "sku"), get(2, "name"), get(2, "note")), ("A-3".into(), "Washer".into(), "".into()));
+  }
+
+  #[test]
+  fn csv_parser_keeps_the_last_record_without_trailing_newline() {
+    assert_eq!(parse_csv("a,b\n1,\"2\""), vec![vec!["a", "b"], vec!["1", "2"]]);
+    assert_eq!(parse_csv("a,\"\"\n"), vec![vec!["a", ""]]);
+    assert!(parse_csv("").is_empty());
+  }
+
+  #[test]
+  fn call_matches_rows_by_argument_columns() {
+    let tools = LocalTools {
+      tools: vec![LoadedTool {
+        config: ToolConfig {
+          name: "find_sku".into(),
+          description: String::new(),
+          parameters: serde_json::json!({ "type": "OBJECT" }),
+          source: ToolSource::Csv { path: "skus.csv".into() },
+          match_mode: MatchMode::Contains,
+          max_results: Some(1),
+        },
+        rows: csv_to_rows("sku,name\nA-1,Bolt M6\nA-2,Bolt M8\nB-1,Nut\n"),
+      }],
+    };
+    let found = tools.call("find_sku", &serde_json::json!({ "name": "bolt" })).unwrap();
+    assert_eq!(found["count"], 2);
+    assert_eq!(found["truncated"], true);
+    assert_eq!(found["rows"][0]["sku"], "A-1");
+    assert!(tools.call("find_sku", &serde_json::json!({ "colour": "red" })).is_err());
+    assert!(tools.call("other", &serde_json::json!({})).is_err());
+  }
+}
@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// トークン使用量と概算コストの集計
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenUsage {
+  pub prompt_tokens: u64,
+  pub candidates_tokens: u64,
+  // 思考トークン（Gemini 2.5 系）。出力として課金される
+  pub thoughts_tokens: u64,
+  pub total_tokens: u64,
+  // prompt_tokens のうちコンテキストキャッシュから読まれた分（Gemini のみ）
+  #[serde(default)]
+  pub cached_tokens: u64,
+}
+
+impl TokenUsage {
+  // Gemini の usageMetadata（camelCase JSON）から読み取る
+  pub fn from_gemini(usage_metadata: &serde_json::Value) -> Option<Self> {
+    let obj = usage_metadata.as_object()?;
+    let get = |k: &str| obj.get(k).and_then(|v| v.as_u64()).unwrap_or(0);
+    Some(Self {
+      prompt_tokens: get("promptTokenCount"),
+      candidates_tokens: get("candidatesTokenCount"),
+      thoughts_tokens: get("thoughtsTokenCount"),
+      total_tokens: get("totalTokenCount"),
+      cached_tokens: get("cachedContentTokenCount"),
+    })
+  }
+
+  // OpenAI 互換 API の usage から読み取る
+  pub fn from_openai(usage: &serde_json::Value) -> Option<Self> {
+    let obj = usage.as_object()?;
+    let get = |k: &str| obj.get(k).and_then(|v| v.as_u64()).unwrap_or(0);
+    Some(Self {
+      prompt_tokens: get("prompt_tokens"),
+      candidates_tokens: get("completion_tokens"),
+      thoughts_tokens: 0,
+      total_tokens: get("total_tokens"),
+      cached_tokens: 0,
+    })
+  }
+
+  // Ollama の prompt_eval_count / eval_count から読み取る
+  pub fn from_ollama(resp: &serde_json::Value) -> Option<Self> {
+    let prompt = resp.get("prompt_eval_count").and_then(|v| v.as_u64());
+    let eval = resp.get("eval_count").and_then(|v| v.as_u64());
+    if prompt.is_none() && eval.is_none() {
+      return None;
+    }
+    let prompt = prompt.unwrap_or(0);
+    let eval = eval.unwrap_or(0);
+    Some(Self {
+      prompt_tokens: prompt,
+      candidates_tokens: eval,
+      thoughts_tokens: 0,
+      total_tokens: prompt + eval,
+      cached_tokens: 0,
+    })
+  }
+
+  pub fn add(&mut self, other: &TokenUsage) {
+    self.prompt_tokens += other.prompt_tokens;
+    self.candidates_tokens += other.candidates_tokens;
+    self.thoughts_tokens += other.thoughts_tokens;
+    self.total_tokens += other.total_tokens;
+    self.cached_tokens += other.cached_tokens;
+  }
+
+  // 出力として課金されるトークン数
+  pub fn output_tokens(&self) -> u64 {
+    self.candidates_tokens + self.thoughts_tokens
+  }
+}
+
+// 段階別の使用量（search = stage1, structure = stage2 / 単発, verify = stage3）
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct StageUsage {
+  pub search: Option<TokenUsage>,
+  pub structure: Option<TokenUsage>,
+  // ローカルツール（function calling）段の合計
+  #[serde(default)]
+  pub tools: Option<TokenUsage>,
+  // 検証段（stage3）
+  #[serde(default)]
+  pub verify: Option<TokenUsage>,
+}
+
+impl StageUsage {
+  pub fn add(&mut self, other: &StageUsage) {
+    for (mine, theirs) in [
+      (&mut self.search, &other.search),
+      (&mut self.structure, &other.structure),
+      (&mut self.tools, &other.tools),
+      (&mut self.verify, &other.verify),
+    ] {
+      if let Some(u) = theirs {
+        mine.get_or_insert_with(TokenUsage::default).add(u);
+      }
+    }
+  }
+
+  pub fn total(&self) -> TokenUsage {
+    let mut total = TokenUsage::default();
+    for u in [self.search.as_ref(), self.structure.as_ref(), self.tools.as_ref(), self.verify.as_ref()].into_iter().flatten() {
+      total.add(u);
+    }
+    total
+  }
+}
+
+// 100万トークンあたりの単価（USD）
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelPrice {
+  pub input_per_million: f64,
+  pub output_per_million: f64,
+  // キャッシュから読まれた入力の単価（未指定は input_per_million と同じ＝割引なし）
+  #[serde(default)]
+  pub cached_input_per_million: Option<f64>,
+}
+
+impl ModelPrice {
+  fn cached_input(&self) -> f64 {
+    self.cached_input_per_million.unwrap_or(self.input_per_million)
+  }
+}
+
+// 既定の単価表（Gemini API の標準価格。変更時は ProcessConfig.pricing で上書きする）
+pub fn default_price_table() -> HashMap<String, ModelPrice> {
+  let mut table = HashMap::new();
+  let mut put = |model: &str, input: f64, output: f64, cached: f64| {
+    table.insert(
+      model.to_string(),
+      ModelPrice { input_per_million: input, output_per_million: output, cached_input_per_million: Some(cached) },
+    );
+  };
+  put("gemini-2.5-pro", 1.25, 10.0, 0.125);
+  put("gemini-2.5-flash", 0.30, 2.50, 0.03);
+  put("gemini-2.5-flash-lite", 0.10, 0.40, 0.01);
+  put("gemini-2.0-flash", 0.10, 0.40, 0.025);
+  put("gemini-2.0-flash-lite", 0.075, 0.30, 0.075);
+  table
+}
+
+// Batch API の割引（標準価格に対する係数）
+pub const BATCH_PRICE_FACTOR: f64 = 0.5;
+
+// 既定の単価表にユーザー指定分を上書きした表を作る
+pub fn price_table_with(overrides: Option<&HashMap<String, ModelPrice>>) -> HashMap<String, ModelPrice> {
+  let mut table = default_price_table();
+  if let Some(o) = overrides {
+    table.extend(o.iter().map(|(k, v)| (k.clone(), *v)));
+  }
+  table
+}
+
+// モデル名（"models/" 接頭辞やバージョン接尾辞付きでも可）に最長一致する単価を探す
+pub fn find_price<'a>(table: &'a HashMap<String, ModelPrice>, model: &str) -> Option<&'a ModelPrice> {
+  let name = model.trim_start_matches("models/");
+  table
+    .iter()
+    .filter(|(k, _)| name.starts_with(k.as_str()))
+    .max_by_key(|(k, _)| k.len())
+    .map(|(_, v)| v)
+}
+
+pub fn estimate_cost(table: &HashMap<String, ModelPrice>, model: &str, usage: &TokenUsage) -> Option<f64> {
+  let price = find_price(table, model)?;
+  let uncached = usage.prompt_tokens.saturating_sub(usage.cached_tokens);
+  Some(
+    uncached as f64 / 1_000_000.0 * price.input_per_million
+      + usage.cached_tokens as f64 / 1_000_000.0 * price.cached_input()
+      + usage.output_tokens() as f64 / 1_000_000.0 * price.output_per_million,
+  )
+}
+
+// キャッシュ読み出し分を通常の入力単価で払った場合との差額（キャッシュの保存料金は含まない）
+pub fn estimate_cache_savings(table: &HashMap<String, ModelPrice>, model: &str, usage: &TokenUsage) -> Option<f64> {
+  let price = find_price(table, model)?;
+  Some(usage.cached_tokens as f64 / 1_000_000.0 * (price.input_per_million - price.cached_input()))
+}
+
+// 実行全体の集計（行タスク間で共有）
+#[derive(Default)]
+pub struct UsageTotals(Mutex<UsageSummary>);
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct UsageSummary {
+  pub usage: TokenUsage,
+  pub estimated_cost_usd: f64,
+  // 単価が見つからず費用に含められなかった行数
+  pub unpriced_rows: u32,
+  // コンテキストキャッシュが効いた（cached_tokens > 0 の）行数と、それによる概算節約額
+  pub cache_hit_rows: u32,
+  pub estimated_cache_savings_usd: f64,
+}
+
+impl UsageTotals {
+  pub fn record(&self, usage: &TokenUsage, cost: Option<f64>, cache_savings: Option<f64>) {
+    let mut s = self.0.lock().unwrap();
+    s.usage.add(usage);
+    match cost {
+      Some(c) => s.estimated_cost_usd += c,
+      None => s.unpriced_rows += 1,
+    }
+    if usage.cached_tokens > 0 {
+      s.cache_hit_rows += 1;
+      s.estimated_cache_savings_usd += cache_savings.unwrap_or(0.0);
+    }
+  }
+
+  pub fn snapshot(&self) -> UsageSummary {
+    self.0.lock().unwrap().clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn usage(prompt: u64, cached: u64, candidates: u64, thoughts: u64) -> TokenUsage {
+    TokenUsage {
+      prompt_tokens: prompt,
+      candidates_tokens: candidates,
+      thoughts_tokens: thoughts,
+      total_tokens: prompt + candidates + thoughts,
+      cached_tokens: cached,
+    }
+  }
+
+  fn assert_close(got: Option<f64>, want: Option<f64>, name: &str) {
+    match (got, want) {
+      (Some(g), Some(w)) => assert!((g - w).abs() < 1e-9, "{}: got {}, want {}", name, g, w),
+      _ => assert_eq!(got, want, "{}", name),
+    }
+  }
+
+  #[test]
+  fn find_price_uses_the_longest_prefix() {
+    let mut overrides = HashMap::new();
+    overrides.insert("llama3".to_string(), ModelPrice { input_per_million: 0.0, output_per_million: 0.0, cached_input_per_million: None });
+    overrides.insert("gemini-2.5-flash".to_string(), ModelPrice { input_per_million: 0.5, output_per_million: 3.0, cached_input_per_million: None });
+    let table = price_table_with(Some(&overrides));
+    // (モデル名, 入力単価)
+    let cases = [
+      ("gemini-2.5-flash", Some(0.5)),
+      ("gemini-2.5-flash-lite", Some(0.10)),
+      ("models/gemini-2.5-flash-lite", Some(0.10)),
+      ("models/gemini-2.5-flash", Some(0.5)),
+      ("gemini-2.5-flash-preview-05-20", Some(0.5)),
+      ("gemini-2.5-flash-lite-preview-06-17", Some(0.10)),
+      ("gemini-2.0-flash-lite", Some(0.075)),
+      ("gemini-2.0-flash-001", Some(0.10)),
+      ("llama3:8b", Some(0.0)),
+      ("gpt-4o", None),
+      ("models/unknown", None),
+      ("flash", None),
+    ];
+    for (model, want) in cases {
+      assert_eq!(find_price(&table, model).map(|p| p.input_per_million), want, "{}", model);
+    }
+  }
+
+  #[test]
+  fn estimate_cost_and_cache_savings() {
+    let mut overrides = HashMap::new();
+    overrides.insert("no-cache-price".to_string(), ModelPrice { input_per_million: 1.0, output_per_million: 2.0, cached_input_per_million: None });
+    let table = price_table_with(Some(&overrides));
+    // (説明, モデル, 使用量, 概算コスト, キャッシュによる節約額)
+    let cases = [
+      ("uncached input", "gemini-2.5-flash", usage(1_000_000, 0, 0, 0), Some(0.30), Some(0.0)),
+      ("cached input at the cached price", "gemini-2.5-flash", usage(1_000_000, 400_000, 0, 0), Some(0.18 + 0.012), Some(0.4 * 0.27)),
+      ("thought tokens billed as output", "gemini-2.5-flash", usage(0, 0, 100_000, 300_000), Some(1.0), Some(0.0)),
+      ("everything together", "models/gemini-2.5-pro", usage(2_000_000, 1_000_000, 500_000, 500_000), Some(1.25 + 0.125 + 10.0), Some(1.125)),
+      ("no cached price means no discount", "no-cache-price", usage(1_000_000, 500_000, 0, 0), Some(1.0), Some(0.0)),
+      ("unknown model", "gpt-4o", usage(1_000_000, 0, 1_000_000, 0), None, None),
+    ];
+    for (name, model, u, cost, savings) in cases {
+      assert_close(estimate_cost(&table, model, &u), cost, name);
+      assert_close(estimate_cache_savings(&table, model, &u), savings, name);
+    }
+  }
+
+  #[test]
+  fn totals_count_unpriced_and_cached_rows() {
+    let totals = UsageTotals::default();
+    totals.record(&usage(100, 0, 10, 0), Some(0.5), Some(0.0));
+    totals.record(&usage(200, 0, 20, 5), None, None);
+    totals.record(&usage(300, 100, 30, 0), Some(0.1), Some(0.02));
+    totals.record(&usage(400, 200, 40, 0), None, None);
+    let s = totals.snapshot();
+    assert_eq!(s.usage, TokenUsage { prompt_tokens: 1000, candidates_tokens: 100, thoughts_tokens: 5, total_tokens: 1105, cached_tokens: 300 });
+    assert_eq!(s.unpriced_rows, 2);
+    assert_eq!(s.cache_hit_rows, 2);
+    assert!((s.estimated_cost_usd - 0.6).abs() < 1e-9);
+    assert!((s.estimated_cache_savings_usd - 0.02).abs() < 1e-9);
+  }
+}
@@ -0,0 +1,146 @@
+use crate::consensus;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+// stage3（検証）: stage2 の JSON の各フィールドが stage1 のメモで裏付けられるかを判定した結果
+// 判定はトップレベルのフィールド単位（入れ子のオブジェクト・配列はまとめて1フィールド）
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+  // メモに根拠がある
+  Supported,
+  // メモに根拠が無い、またはメモと矛盾する
+  Unsupported,
+  // メモだけでは判断できない
+  Uncertain,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FieldVerdict {
+  pub field: String,
+  pub verdict: Verdict,
+  #[serde(default)]
+  pub reason: Option<String>,
+}
+
+// 検証段の応答スキーマ（Gemini の responseSchema 形式）
+pub fn response_schema() -> serde_json::Value {
+  serde_json::json!({
+    "type": "OBJECT",
+    "properties": {
+      "verdicts": {
+        "type": "ARRAY",
+        "items": {
+          "type": "OBJECT",
+          "properties": {
+            "field": { "type": "STRING" },
+            "verdict": { "type": "STRING", "enum": ["supported", "unsupported", "uncertain"] },
+            "reason": { "type": "STRING" },
+          },
+          "required": ["field", "verdict"],
+        },
+      },
+    },
+    "required": ["verdicts"],
+  })
+}
+
+// 検証段の応答を読み取る（検証対象に無いフィールドの判定は捨て、同じフィールドは最初の判定を使う）
+pub fn parse_verdicts(text: &str, output: &serde_json::Value) -> Result<Vec<FieldVerdict>> {
+  #[derive(Deserialize)]
+  struct Verdicts {
+    verdicts: Vec<FieldVerdict>,
+  }
+  let parsed: Verdicts = serde_json::from_str(text.trim()).map_err(|e| anyhow!("invalid verification response: {}", e))?;
+  let mut out: Vec<FieldVerdict> = Vec::new();
+  for v in parsed.verdicts {
+    if output.get(&v.field).is_some() && !out.iter().any(|o| o.field == v.field) {
+      out.push(v);
+    }
+  }
+  Ok(out)
+}
+
+// 複数サンプルをまとめた値に対応する判定を選ぶ（各フィールドは、まとめた値と同じ値を返した最初のサンプルの判定）
+pub fn for_merged(merged: &serde_json::Value, samples: &[(serde_json::Value, Vec<FieldVerdict>)]) -> Vec<FieldVerdict> {
+  let Some(obj) = merged.as_object() else {
+    return Vec::new();
+  };
+  obj
+    .iter()
+    .filter_map(|(field, value)| {
+      samples
+        .iter()
+        .filter(|(output, _)| output.get(field).is_some_and(|v| consensus::same_value(v, value)))
+        .find_map(|(_, verdicts)| verdicts.iter().find(|v| &v.field == field).cloned())
+    })
+    .collect()
+}
+
+// unsupported と判定されたフィールドを null にし、null にしたフィールド名を返す
+pub fn null_unsupported(data: &mut serde_json::Value, verdicts: &[FieldVerdict]) -> Vec<String> {
+  let Some(obj) = data.as_object_mut() else {
+    return Vec::new();
+  };
+  let mut nulled = Vec::new();
+  for v in verdicts.iter().filter(|v| v.verdict == Verdict::Unsupported) {
+    if let Some(value) = obj.get_mut(&v.field).filter(|value| !value.is_null()) {
+      *value = serde_json::Value::Null;
+      nulled.push(v.field.clone());
+    }
+  }
+  nulled
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn verdict(field: &str, verdict: Verdict) -> FieldVerdict {
+    FieldVerdict { field: field.into(), verdict, reason: None }
+  }
+
+  #[test]
+  fn parse_verdicts_drops_unknown_and_duplicate_fields() {
+    let output = json!({ "name": "Widget", "price": 10, "maker": null });
+    let text = r#" {"verdicts": [
+      {"field": "name", "verdict": "supported", "reason": "in the notes"},
+      {"field": "color", "verdict": "unsupported"},
+      {"field": "price", "verdict": "unsupported"},
+      {"field": "name", "verdict": "unsupported"},
+      {"field": "maker", "verdict": "uncertain"}
+    ]} "#;
+    let verdicts = parse_verdicts(text, &output).unwrap();
+    let got: Vec<(&str, Verdict)> = verdicts.iter().map(|v| (v.field.as_str(), v.verdict)).collect();
+    assert_eq!(got, vec![("name", Verdict::Supported), ("price", Verdict::Unsupported), ("maker", Verdict::Uncertain)]);
+    assert_eq!(verdicts[0].reason.as_deref(), Some("in the notes"));
+
+    assert!(parse_verdicts("not json", &output).is_err());
+    assert!(parse_verdicts(r#"{"verdicts": [{"field": "name", "verdict": "maybe"}]}"#, &output).is_err());
+  }
+
+  #[test]
+  fn null_unsupported_returns_the_fields_it_nulled() {
+    let mut data = json!({ "name": "Widget", "price": 10, "maker": null, "tags": ["a"], "color": "red" });
+    let verdicts = vec![
+      verdict("price", Verdict::Unsupported),
+      // 既に null の値は数えない
+      verdict("maker", Verdict::Unsupported),
+      verdict("tags", Verdict::Unsupported),
+      verdict("name", Verdict::Supported),
+      verdict("color", Verdict::Uncertain),
+      verdict("missing", Verdict::Unsupported),
+    ];
+    let nulled = null_unsupported(&mut data, &verdicts);
+    assert_eq!(nulled, vec!["price".to_string(), "tags".to_string()]);
+    assert_eq!(data, json!({ "name": "Widget", "price": null, "maker": null, "tags": null, "color": "red" }));
+    for field in &nulled {
+      assert!(data[field].is_null());
+    }
+
+    let mut not_object = json!(["a"]);
+    assert!(null_unsupported(&mut not_object, &verdicts).is_empty());
+  }
+}